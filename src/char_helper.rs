@@ -1,5 +1,5 @@
 pub fn is_id_start(c: char) -> bool {
-  c.is_ascii_alphabetic() || c == '_' || c == '@'
+  c.is_ascii_alphabetic() || c == '_'
 }
 
 pub fn is_hex_signifier(c: char) -> bool {
@@ -22,8 +22,9 @@ pub fn is_ctrl_command_signifier(c: char) -> bool {
   c == '.'
 }
 
+// The characters .localchar can pick to start a cheap local label
 pub fn is_local_label_signifier(c: char) -> bool {
-  c == '@'
+  c == '@' || c == '?'
 }
 
 pub fn is_operator(c: char) -> bool {
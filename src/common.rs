@@ -1,16 +1,29 @@
+use crate::error::{AsmError, AsmResult};
 use crate::token::*;
 
-pub fn get_next_token_checked(tokens: &mut Vec<Token>, expected: Vec<TokenType>) -> Token {
+pub fn get_next_token_checked(
+  tokens: &mut Vec<Token>,
+  expected: Vec<TokenType>,
+) -> AsmResult<Token> {
   let token = get_next_token(tokens);
   let valid = expected.iter().any(|t| token.get_type() == t);
   if !valid {
-    error(&token);
+    let names: Vec<String> = expected.iter().map(|t| format!("{:?}", t)).collect();
+    let message = format!(
+      "Expected {}, found {}",
+      names.join(" or "),
+      describe_token(&token)
+    );
+    return Err(AsmError::at_token(&token, message));
   }
-  token
+  Ok(token)
 }
 
 pub fn get_next_token(tokens: &mut Vec<Token>) -> Token {
-  tokens.remove(0)
+  match tokens.is_empty() {
+    true => peek(tokens, 0),
+    false => tokens.remove(0),
+  }
 }
 
 pub fn peek_next_token(tokens: &Vec<Token>) -> Token {
@@ -29,20 +42,28 @@ fn peek(tokens: &Vec<Token>, count: usize) -> Token {
   }
 }
 
-pub fn error(token: &Token) -> ! {
-  panic!(
-    "Invalid token.\nToken Type: {:?}\nToken Value: {}\nLine Number: {}",
-    token.get_type(),
-    token.get_value(),
-    token.get_line()
-  );
+pub fn error(token: &Token) -> AsmError {
+  let message = format!("Unexpected {}", describe_token(token));
+  AsmError::at_token(token, message)
 }
 
-pub fn convert_number(value: &Token) -> Result<u16, std::num::ParseIntError> {
-  match value.get_type() {
-    TokenType::HexNumber => u16::from_str_radix(&value.get_value()[1..], 16),
-    TokenType::BinNumber => u16::from_str_radix(&value.get_value()[1..], 2),
-    TokenType::DecNumber => u16::from_str_radix(&value.get_value(), 10),
-    _ => error(&value),
+pub fn describe_token(token: &Token) -> String {
+  match token.get_type() {
+    TokenType::EndOfFile => String::from("end of file"),
+    TokenType::Newline => String::from("end of line"),
+    t => format!("{:?} \"{}\"", t, token.get_value()),
   }
 }
+
+pub fn convert_number(value: &Token) -> AsmResult<u32> {
+  let result = match value.get_type() {
+    TokenType::HexNumber => u32::from_str_radix(&value.get_value()[1..], 16),
+    TokenType::BinNumber => u32::from_str_radix(&value.get_value()[1..], 2),
+    TokenType::DecNumber => value.get_value().parse::<u32>(),
+    _ => return Err(error(value)),
+  };
+  result.map_err(|e| {
+    let message = format!("Invalid number \"{}\": {}", value.get_value(), e);
+    AsmError::at_token(value, message)
+  })
+}
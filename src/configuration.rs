@@ -1,68 +1,76 @@
 use crate::common::*;
-use crate::lexer::lex;
+use crate::error::{AsmError, AsmResult};
 use crate::token::{Token, TokenType};
-use std::fs::write;
 
-pub fn generate_config_data(config_file: &String) -> Configuration {
-  let mut tokens = lex(config_file, false);
-  let to_file = tokens.clone();
-  let out: Vec<String> = to_file.iter().map(|t| format!("{}", t)).collect();
-  write("src/out/config_lexed.out", out.join("\n")).unwrap();
+// `file = %O` in a memory area stands for the output file
+pub const OUTPUT_FILE: &str = "%O";
+
+pub fn generate_config_data(mut tokens: Vec<Token>) -> AsmResult<Configuration> {
   parse_config_file(&mut tokens)
 }
 
-fn parse_config_file(tokens: &mut Vec<Token>) -> Configuration {
+fn parse_config_file(tokens: &mut Vec<Token>) -> AsmResult<Configuration> {
   let mut config = Configuration::new();
   let mut next = peek_next_token(tokens);
   while next.get_type() != &TokenType::EndOfFile {
     config = match next.get_value().to_ascii_uppercase().as_ref() {
-      "MEMORY" => parse_memory_section(tokens, config),
-      "SEGMENTS" => parse_segment_section(tokens, config),
-      "SYMBOLS" => parse_symbol_section(tokens, config),
-      "FEATURES" => parse_feature_section(tokens, config),
-      _ => panic!("Unrecognized configuration parameter: {}", next.get_value()),
+      "MEMORY" => parse_memory_section(tokens, config)?,
+      "SEGMENTS" => parse_segment_section(tokens, config)?,
+      "SYMBOLS" => parse_symbol_section(tokens, config)?,
+      "FEATURES" => parse_feature_section(tokens, config)?,
+      _ => {
+        let message = format!("Unrecognized configuration parameter: {}", next.get_value());
+        return Err(AsmError::at_token(&next, message));
+      }
     };
     next = peek_next_token(tokens);
   }
   config.build()
 }
 
-fn parse_memory_section(tokens: &mut Vec<Token>, config: ConfigBuilder) -> ConfigBuilder {
-  get_next_token_checked(tokens, vec![TokenType::Identifier]);
+fn parse_memory_section(
+  tokens: &mut Vec<Token>,
+  config: ConfigBuilder,
+) -> AsmResult<ConfigBuilder> {
+  get_next_token_checked(tokens, vec![TokenType::Identifier])?;
   let mut memory = Memory::new();
-  get_next_token_checked(tokens, vec![TokenType::OCurly]);
+  get_next_token_checked(tokens, vec![TokenType::OCurly])?;
   let mut next = peek_next_token(tokens);
   while next.get_type() != &TokenType::CCurly {
-    parse_memory_entry(tokens, &mut memory);
+    parse_memory_entry(tokens, &mut memory)?;
     next = peek_next_token(tokens);
   }
-  get_next_token_checked(tokens, vec![TokenType::CCurly]);
-  config.memory(memory)
+  get_next_token_checked(tokens, vec![TokenType::CCurly])?;
+  Ok(config.memory(memory))
 }
 
-fn parse_memory_entry(tokens: &mut Vec<Token>, memory: &mut Memory) {
-  let id = get_next_token_checked(tokens, vec![TokenType::Identifier]);
+fn parse_memory_entry(tokens: &mut Vec<Token>, memory: &mut Memory) -> AsmResult<()> {
+  let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
   let mut memory_entry = MemoryEntry::new(id.get_value());
-  get_next_token_checked(tokens, vec![TokenType::Colon]);
+  get_next_token_checked(tokens, vec![TokenType::Colon])?;
   let mut next = peek_next_token(tokens);
   while next.get_type() != &TokenType::Comment {
-    memory_entry = parse_mem_attributes(tokens, memory_entry);
+    memory_entry = parse_mem_attributes(tokens, memory_entry)?;
     next = peek_next_token(tokens);
     if next.get_type() == &TokenType::Comma {
       get_next_token(tokens);
       next = peek_next_token(tokens);
     }
   }
-  get_next_token_checked(tokens, vec![TokenType::Comment]);
-  memory.add_entry(memory_entry.build());
+  get_next_token_checked(tokens, vec![TokenType::Comment])?;
+  let entry = memory_entry
+    .build()
+    .map_err(|message| AsmError::at_token(&id, message))?;
+  memory.add_entry(entry);
+  Ok(())
 }
 
 fn parse_mem_attributes(
   tokens: &mut Vec<Token>,
   mem_entry: MemoryEntryBuilder,
-) -> MemoryEntryBuilder {
-  let attr_name = get_next_token_checked(tokens, vec![TokenType::Identifier]);
-  get_next_token_checked(tokens, vec![TokenType::Equal]);
+) -> AsmResult<MemoryEntryBuilder> {
+  let attr_name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+  get_next_token_checked(tokens, vec![TokenType::Equal])?;
   let value = get_next_token_checked(
     tokens,
     vec![
@@ -73,62 +81,73 @@ fn parse_mem_attributes(
       TokenType::Identifier,
       TokenType::StringConst,
     ],
-  );
+  )?;
   match attr_name.get_value().as_ref() {
-    "start" => add_number(value, MemoryEntryBuilder::start, mem_entry),
-    "size" => add_number(value, MemoryEntryBuilder::size, mem_entry),
+    "start" => add_long(value, MemoryEntryBuilder::start, mem_entry),
+    "size" => add_long(value, MemoryEntryBuilder::size, mem_entry),
     "fillval" => add_u8(value, MemoryEntryBuilder::fill_val, mem_entry),
-    "type" => mem_entry.mem_type(MemType::from_string(value.get_value())),
+    "type" => Ok(mem_entry.mem_type(MemType::from_token(&value)?)),
     "file" => match value.get_type() {
-      TokenType::StringConst => mem_entry.file(value.get_value()),
+      TokenType::StringConst => Ok(mem_entry.file(value.get_value())),
+      // %O lexes as a binary number prefix followed by the O
       TokenType::BinNumber => {
-        get_next_token_checked(tokens, vec![TokenType::Identifier]);
-        mem_entry.file(&String::from("a.out"))
+        let name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+        match name.get_value().as_str() {
+          "O" => Ok(mem_entry.file(&String::from(OUTPUT_FILE))),
+          _ => Err(invalid_value(&attr_name, &name)),
+        }
       }
-      _ => panic!("Invalid memory file type {:?}", value.get_type()),
+      _ => Err(invalid_value(&attr_name, &value)),
     },
     "define" => add_bool(value, MemoryEntryBuilder::define, mem_entry),
     "fill" => add_bool(value, MemoryEntryBuilder::fill, mem_entry),
-    _ => panic!("the disco"),
+    _ => Err(invalid_attribute(&attr_name)),
   }
 }
 
-fn parse_segment_section(tokens: &mut Vec<Token>, config: ConfigBuilder) -> ConfigBuilder {
-  get_next_token_checked(tokens, vec![TokenType::Identifier]);
+fn parse_segment_section(
+  tokens: &mut Vec<Token>,
+  config: ConfigBuilder,
+) -> AsmResult<ConfigBuilder> {
+  get_next_token_checked(tokens, vec![TokenType::Identifier])?;
   let mut segment = Segment::new();
-  get_next_token_checked(tokens, vec![TokenType::OCurly]);
+  get_next_token_checked(tokens, vec![TokenType::OCurly])?;
   let mut next = peek_next_token(tokens);
   while next.get_type() != &TokenType::CCurly {
-    parse_segment_entry(tokens, &mut segment);
+    parse_segment_entry(tokens, &mut segment)?;
     next = peek_next_token(tokens);
   }
-  get_next_token_checked(tokens, vec![TokenType::CCurly]);
-  config.segments(segment)
+  get_next_token_checked(tokens, vec![TokenType::CCurly])?;
+  Ok(config.segments(segment))
 }
 
-fn parse_segment_entry(tokens: &mut Vec<Token>, segment: &mut Segment) {
-  let id = get_next_token_checked(tokens, vec![TokenType::Identifier]);
+fn parse_segment_entry(tokens: &mut Vec<Token>, segment: &mut Segment) -> AsmResult<()> {
+  let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
   let mut segment_entry = SegmentEntry::new(id.get_value());
-  get_next_token_checked(tokens, vec![TokenType::Colon]);
+  get_next_token_checked(tokens, vec![TokenType::Colon])?;
   let mut next = peek_next_token(tokens);
   while next.get_type() != &TokenType::Comment {
-    segment_entry = parse_seg_attributes(tokens, segment_entry);
+    segment_entry = parse_seg_attributes(tokens, segment_entry)?;
     next = peek_next_token(tokens);
     if next.get_type() == &TokenType::Comma {
       get_next_token(tokens);
       next = peek_next_token(tokens);
     }
   }
-  get_next_token_checked(tokens, vec![TokenType::Comment]);
-  segment.add_entry(segment_entry.build());
+  get_next_token_checked(tokens, vec![TokenType::Comment])?;
+  let entry = segment_entry
+    .build()
+    .map_err(|message| AsmError::at_token(&id, message))?;
+  segment.add_entry(entry);
+  Ok(())
 }
 
 fn parse_seg_attributes(
   tokens: &mut Vec<Token>,
   seg_entry: SegmentEntryBuilder,
-) -> SegmentEntryBuilder {
-  let attr_name = get_next_token_checked(tokens, vec![TokenType::Identifier]);
-  get_next_token_checked(tokens, vec![TokenType::Equal]);
+) -> AsmResult<SegmentEntryBuilder> {
+  let attr_name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+  get_next_token_checked(tokens, vec![TokenType::Equal])?;
   let value = get_next_token_checked(
     tokens,
     vec![
@@ -139,26 +158,50 @@ fn parse_seg_attributes(
       TokenType::Identifier,
       TokenType::StringConst,
     ],
-  );
+  )?;
   match attr_name.get_value().as_ref() {
-    "load" => seg_entry.load(value.get_value()),
-    "type" => seg_entry.seg_type(SegType::from_str(value.get_value())),
+    "load" => Ok(seg_entry.load(value.get_value())),
+    "type" => Ok(seg_entry.seg_type(SegType::from_token(&value)?)),
     "define" => add_bool(value, SegmentEntryBuilder::define, seg_entry),
     "align" => add_number(value, SegmentEntryBuilder::align, seg_entry),
-    "start" => add_number(value, SegmentEntryBuilder::start, seg_entry),
-    "run" => seg_entry.run(value.get_value()),
-    "offset" => add_number(value, SegmentEntryBuilder::start, seg_entry),
+    "start" => add_long(value, SegmentEntryBuilder::start, seg_entry),
+    "run" => Ok(seg_entry.run(value.get_value())),
+    "offset" => add_long(value, SegmentEntryBuilder::offset, seg_entry),
     "fillval" => add_u8(value, SegmentEntryBuilder::fill_val, seg_entry),
-    _ => panic!("Invalid attribute name {}", attr_name.get_value()),
+    _ => Err(invalid_attribute(&attr_name)),
   }
 }
 
-fn parse_symbol_section(_tokens: &mut Vec<Token>, _config: ConfigBuilder) -> ConfigBuilder {
-  todo!();
+fn parse_symbol_section(
+  tokens: &mut Vec<Token>,
+  _config: ConfigBuilder,
+) -> AsmResult<ConfigBuilder> {
+  let section = peek_next_token(tokens);
+  let message = String::from("SYMBOLS configuration section is not yet supported");
+  Err(AsmError::at_token(&section, message))
+}
+
+fn parse_feature_section(
+  tokens: &mut Vec<Token>,
+  _config: ConfigBuilder,
+) -> AsmResult<ConfigBuilder> {
+  let section = peek_next_token(tokens);
+  let message = String::from("FEATURES configuration section is not yet supported");
+  Err(AsmError::at_token(&section, message))
 }
 
-fn parse_feature_section(_tokens: &mut Vec<Token>, _config: ConfigBuilder) -> ConfigBuilder {
-  todo!();
+fn invalid_attribute(attr_name: &Token) -> AsmError {
+  let message = format!("Invalid attribute name {}", attr_name.get_value());
+  AsmError::at_token(attr_name, message)
+}
+
+fn invalid_value(attr_name: &Token, value: &Token) -> AsmError {
+  let message = format!(
+    "Invalid value \"{}\" for attribute {}",
+    value.get_value(),
+    attr_name.get_value()
+  );
+  AsmError::at_token(value, message)
 }
 
 pub struct Configuration {
@@ -186,15 +229,8 @@ impl Configuration {
     self.memory.find_memory_by_name(name)
   }
 
-  pub fn get_segment_start(&self, name: &String) -> u16 {
-    let segment = self.find_segment_by_name(name).unwrap();
-    match segment.get_start() {
-      Some(start) => start,
-      None => {
-        let memory_entry = self.find_memory_by_name(segment.get_load()).unwrap();
-        memory_entry.get_start()
-      }
-    }
+  pub fn get_memory(&self) -> &Memory {
+    &self.memory
   }
 
   pub fn get_segments(&self) -> &Segment {
@@ -220,25 +256,41 @@ impl ConfigBuilder {
     self
   }
 
-  fn build(self) -> Configuration {
+  fn build(self) -> AsmResult<Configuration> {
     let memory = match self.memory {
       Some(mem) => mem,
-      None => panic!("No memory configuration specified"),
+      None => {
+        let message = String::from("No memory configuration specified");
+        return Err(AsmError::new(message, None));
+      }
     };
     let segments = match self.segments {
       Some(seg) => seg,
-      None => panic!("No segment configuration specified"),
+      None => {
+        let message = String::from("No segment configuration specified");
+        return Err(AsmError::new(message, None));
+      }
     };
-    Configuration {
+    for entry in segments.get_entries() {
+      if memory.find_memory_by_name(entry.get_load()).is_none() {
+        let message = format!(
+          "Segment {} is loaded into undefined memory area {}",
+          entry.get_name(),
+          entry.get_load()
+        );
+        return Err(AsmError::new(message, None));
+      }
+    }
+    Ok(Configuration {
       memory,
       segments,
       _symbols: None,
       _features: None,
-    }
+    })
   }
 }
 
-struct Memory {
+pub struct Memory {
   entries: Vec<MemoryEntry>,
 }
 
@@ -259,12 +311,16 @@ impl Memory {
       .iter()
       .find(|e| e.name.to_ascii_uppercase() == name.to_ascii_uppercase())
   }
+
+  pub fn get_entries(&self) -> &Vec<MemoryEntry> {
+    &self.entries
+  }
 }
 
 pub struct MemoryEntry {
   name: String,
-  start: u16,
-  size: u16,
+  start: u32,
+  size: u32,
   mem_type: Option<MemType>,
   file: Option<String>,
   define: Option<bool>,
@@ -288,19 +344,36 @@ impl MemoryEntry {
     }
   }
 
-  pub fn get_start(&self) -> u16 {
+  pub fn get_start(&self) -> u32 {
     self.start
   }
 
-  pub fn get_size(&self) -> u16 {
+  pub fn get_size(&self) -> u32 {
     self.size
   }
+
+  pub fn get_name(&self) -> &String {
+    &self.name
+  }
+
+  // An empty name means the area isn't written anywhere
+  pub fn get_file(&self) -> Option<&str> {
+    self.file.as_deref().filter(|file| !file.is_empty())
+  }
+
+  pub fn get_fill(&self) -> bool {
+    self.fill.unwrap_or(false)
+  }
+
+  pub fn get_fill_val(&self) -> u8 {
+    self.fill_val.unwrap_or(0)
+  }
 }
 
 struct MemoryEntryBuilder {
   name: String,
-  start: Option<u16>,
-  size: Option<u16>,
+  start: Option<u32>,
+  size: Option<u32>,
   mem_type: Option<MemType>,
   file: Option<String>,
   define: Option<bool>,
@@ -311,12 +384,12 @@ struct MemoryEntryBuilder {
 impl ConfigEntryBuilder for MemoryEntryBuilder {}
 
 impl MemoryEntryBuilder {
-  fn start(mut self, start: u16) -> Self {
+  fn start(mut self, start: u32) -> Self {
     self.start = Some(start);
     self
   }
 
-  fn size(mut self, size: u16) -> Self {
+  fn size(mut self, size: u32) -> Self {
     self.size = Some(size);
     self
   }
@@ -346,16 +419,26 @@ impl MemoryEntryBuilder {
     self
   }
 
-  fn build(self) -> MemoryEntry {
+  fn build(self) -> Result<MemoryEntry, String> {
     let start = match self.start {
       Some(st) => st,
-      None => panic!("Memory entry {} does not have start attribute", self.name),
+      None => {
+        return Err(format!(
+          "Memory entry {} does not have start attribute",
+          self.name
+        ))
+      }
     };
     let size = match self.size {
       Some(st) => st,
-      None => panic!("Memory entry {} does not have size attribute", self.name),
+      None => {
+        return Err(format!(
+          "Memory entry {} does not have size attribute",
+          self.name
+        ))
+      }
     };
-    MemoryEntry {
+    Ok(MemoryEntry {
       name: self.name,
       start: start,
       size: size,
@@ -364,7 +447,7 @@ impl MemoryEntryBuilder {
       define: self.define,
       fill: self.fill,
       fill_val: self.fill_val,
-    }
+    })
   }
 }
 
@@ -374,11 +457,14 @@ enum MemType {
 }
 
 impl MemType {
-  fn from_string(string: &String) -> MemType {
-    match string.to_ascii_lowercase().as_ref() {
-      "ro" => MemType::Ro,
-      "rw" => MemType::Rw,
-      _ => panic!("Invalid memory type: {}", string),
+  fn from_token(token: &Token) -> AsmResult<MemType> {
+    match token.get_value().to_ascii_lowercase().as_ref() {
+      "ro" => Ok(MemType::Ro),
+      "rw" => Ok(MemType::Rw),
+      _ => {
+        let message = format!("Invalid memory type: {}", token.get_value());
+        Err(AsmError::at_token(token, message))
+      }
     }
   }
 }
@@ -416,9 +502,9 @@ pub struct SegmentEntry {
   seg_type: SegType,
   define: Option<bool>,
   align: Option<u16>,
-  start: Option<u16>,
+  start: Option<u32>,
   run: Option<String>,
-  offset: Option<u16>,
+  offset: Option<u32>,
   fill_val: Option<u8>,
   align_load: Option<u16>,
 }
@@ -453,9 +539,14 @@ impl SegmentEntry {
     &self.load
   }
 
-  fn get_start(&self) -> Option<u16> {
+  pub fn get_start(&self) -> Option<u32> {
     self.start
   }
+
+  // How far into its memory area the segment goes
+  pub fn get_offset(&self) -> Option<u32> {
+    self.offset
+  }
 }
 
 struct SegmentEntryBuilder {
@@ -464,9 +555,9 @@ struct SegmentEntryBuilder {
   seg_type: Option<SegType>,
   define: Option<bool>,
   align: Option<u16>,
-  start: Option<u16>,
+  start: Option<u32>,
   run: Option<String>,
-  offset: Option<u16>,
+  offset: Option<u32>,
   fill_val: Option<u8>,
   align_load: Option<u16>,
 }
@@ -494,7 +585,7 @@ impl SegmentEntryBuilder {
     self
   }
 
-  fn start(mut self, start: u16) -> SegmentEntryBuilder {
+  fn start(mut self, start: u32) -> SegmentEntryBuilder {
     self.start = Some(start);
     self
   }
@@ -504,7 +595,7 @@ impl SegmentEntryBuilder {
     self
   }
 
-  fn offset(mut self, offset: u16) -> SegmentEntryBuilder {
+  fn offset(mut self, offset: u32) -> SegmentEntryBuilder {
     self.offset = Some(offset);
     self
   }
@@ -519,16 +610,26 @@ impl SegmentEntryBuilder {
     self
   }
 
-  fn build(self) -> SegmentEntry {
+  fn build(self) -> Result<SegmentEntry, String> {
     let load = match self.load {
       Some(l) => l,
-      None => panic!("Segment entry missing load parameter"),
+      None => {
+        return Err(format!(
+          "Segment entry {} missing load parameter",
+          self.name
+        ))
+      }
     };
     let seg_type = match self.seg_type {
       Some(s) => s,
-      None => panic!("Segment entry missing type parameter"),
+      None => {
+        return Err(format!(
+          "Segment entry {} missing type parameter",
+          self.name
+        ))
+      }
     };
-    SegmentEntry {
+    Ok(SegmentEntry {
       name: self.name,
       load: load,
       seg_type: seg_type,
@@ -539,7 +640,7 @@ impl SegmentEntryBuilder {
       offset: self.offset,
       fill_val: self.fill_val,
       align_load: self.align_load,
-    }
+    })
   }
 }
 
@@ -552,46 +653,61 @@ pub enum SegType {
 }
 
 impl SegType {
-  fn from_str(value: &String) -> SegType {
-    match value.to_ascii_lowercase().as_ref() {
-      "ro" => SegType::Ro,
-      "rw" => SegType::Rw,
-      "bss" => SegType::Bss,
-      "zp" => SegType::Zp,
-      "overwrite" => SegType::Overwrite,
-      _ => panic!("Invalid segment type: {}", value),
+  fn from_token(token: &Token) -> AsmResult<SegType> {
+    match token.get_value().to_ascii_lowercase().as_ref() {
+      "ro" => Ok(SegType::Ro),
+      "rw" => Ok(SegType::Rw),
+      "bss" => Ok(SegType::Bss),
+      "zp" => Ok(SegType::Zp),
+      "overwrite" => Ok(SegType::Overwrite),
+      _ => {
+        let message = format!("Invalid segment type: {}", token.get_value());
+        Err(AsmError::at_token(token, message))
+      }
     }
   }
 }
 
-fn add_number<T: ConfigEntryBuilder>(value: Token, f: fn(T, u16) -> T, entry: T) -> T {
-  let num = convert_number(&value);
-  match num {
-    Ok(v) => f(entry, v),
-    Err(_) => error(&value),
+fn add_number<T: ConfigEntryBuilder>(value: Token, f: fn(T, u16) -> T, entry: T) -> AsmResult<T> {
+  let num = convert_number(&value)?;
+  if num > 0xFFFF {
+    let message = format!("Value \"{}\" does not fit in a word", value.get_value());
+    return Err(AsmError::at_token(&value, message));
   }
+  Ok(f(entry, num as u16))
 }
 
-fn add_u8<T: ConfigEntryBuilder>(value: Token, f: fn(T, u8) -> T, entry: T) -> T {
-  let num = convert_number(&value);
-  match num {
-    Ok(v) => f(entry, v as u8),
-    Err(_) => error(&value),
-  }
+// Addresses in the configuration can go past 16 bits, for output bigger than
+// the 6502 can see at once
+fn add_long<T: ConfigEntryBuilder>(value: Token, f: fn(T, u32) -> T, entry: T) -> AsmResult<T> {
+  Ok(f(entry, convert_number(&value)?))
 }
 
-fn add_bool<T: ConfigEntryBuilder>(value: Token, f: fn(T, bool) -> T, entry: T) -> T {
-  match value.get_type() == &TokenType::Identifier {
-    true => f(
-      entry,
-      match value.get_value().as_str() {
-        "yes" => true,
-        "no" => false,
-        _ => panic!("Invalid boolean instruction {}", value.get_value()),
-      },
-    ),
-    false => panic!("Invalid boolean type {:?}", value.get_type()),
+fn add_u8<T: ConfigEntryBuilder>(value: Token, f: fn(T, u8) -> T, entry: T) -> AsmResult<T> {
+  let num = convert_number(&value)?;
+  if num > 0xFF {
+    let message = format!(
+      "Value \"{}\" does not fit in a single byte",
+      value.get_value()
+    );
+    return Err(AsmError::at_token(&value, message));
   }
+  Ok(f(entry, num as u8))
+}
+
+fn add_bool<T: ConfigEntryBuilder>(value: Token, f: fn(T, bool) -> T, entry: T) -> AsmResult<T> {
+  let flag = match value.get_value().as_str() {
+    "yes" if value.get_type() == &TokenType::Identifier => true,
+    "no" if value.get_type() == &TokenType::Identifier => false,
+    _ => {
+      let message = format!(
+        "Invalid boolean value \"{}\", expected yes or no",
+        value.get_value()
+      );
+      return Err(AsmError::at_token(&value, message));
+    }
+  };
+  Ok(f(entry, flag))
 }
 
 trait ConfigSection {}
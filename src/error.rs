@@ -0,0 +1,172 @@
+use crate::node::Node;
+use crate::token::{Location, Token};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+  location: Option<Location>,
+  message: String,
+}
+
+impl AsmError {
+  pub fn new(message: String, location: Option<Location>) -> AsmError {
+    AsmError { location, message }
+  }
+
+  pub fn at_token(token: &Token, message: String) -> AsmError {
+    AsmError::new(message, Some(*token.get_location()))
+  }
+
+  pub fn at_node(node: &Node<String>, message: String) -> AsmError {
+    AsmError::new(message, node.get_location().copied())
+  }
+
+  // Errors raised deep inside a statement may not know where they came from,
+  // so the statement fills in its own location
+  pub fn or_at_node(mut self, node: &Node<String>) -> AsmError {
+    if self.location.is_none() {
+      self.location = node.get_location().copied();
+    }
+    self
+  }
+
+  pub fn get_location(&self) -> Option<&Location> {
+    self.location.as_ref()
+  }
+
+  // Renders the error along with the offending source line, e.g.
+  //
+  // error: Expected Colon, found Identifier "foo"
+  //  --> build.s:12:5
+  //    |
+  // 12 |     lda foo bar
+  //    |         ^^^
+  pub fn render(&self, sources: &SourceMap) -> String {
+    let mut out = format!("error: {}", self.message);
+    let location = match &self.location {
+      Some(location) => location,
+      None => return out,
+    };
+    // an object file read back in still knows the line
+    let source = match sources.get(location.get_file()) {
+      Some(source) => source,
+      None => return format!("error: {}", self),
+    };
+    let (line_text, column) = source.get_line(location);
+    let line_len = line_text.chars().count();
+    let width = location
+      .get_len()
+      .min(line_len.saturating_sub(column))
+      .max(1);
+    let gutter = location.get_line().to_string();
+    let padding = " ".repeat(gutter.len());
+    out.push_str(&format!(
+      "\n{}--> {}:{}:{}",
+      padding,
+      source.get_path().display(),
+      location.get_line(),
+      column + 1
+    ));
+    out.push_str(&format!("\n{} |", padding));
+    out.push_str(&format!("\n{} | {}", gutter, line_text));
+    out.push_str(&format!(
+      "\n{} | {}{}",
+      padding,
+      " ".repeat(column),
+      "^".repeat(width)
+    ));
+    out
+  }
+}
+
+impl fmt::Display for AsmError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.location {
+      Some(location) => write!(f, "{} (line {})", self.message, location.get_line()),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
+
+pub struct SourceMap {
+  files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+  pub fn new() -> SourceMap {
+    SourceMap { files: vec![] }
+  }
+
+  // Registers a file and returns the id its tokens should carry
+  pub fn add(&mut self, path: &Path, text: &str) -> usize {
+    self.files.push(SourceFile {
+      path: path.to_owned(),
+      text: text.chars().collect(),
+    });
+    self.files.len() - 1
+  }
+
+  pub fn get_path(&self, id: usize) -> Option<&PathBuf> {
+    self.files.get(id).map(SourceFile::get_path)
+  }
+
+  fn get(&self, id: usize) -> Option<&SourceFile> {
+    self.files.get(id)
+  }
+}
+
+struct SourceFile {
+  path: PathBuf,
+  text: Vec<char>,
+}
+
+impl SourceFile {
+  fn get_path(&self) -> &PathBuf {
+    &self.path
+  }
+
+  // Returns the full text of the line containing the location, and the
+  // location's column within that line
+  fn get_line(&self, location: &Location) -> (String, usize) {
+    let start = location.get_start().min(self.text.len());
+    let line_start = self.text[..start]
+      .iter()
+      .rposition(|c| *c == '\n')
+      .map_or(0, |i| i + 1);
+    let line_end = self.text[line_start..]
+      .iter()
+      .position(|c| *c == '\n' || *c == '\r')
+      .map_or(self.text.len(), |i| i + line_start);
+    let line: String = self.text[line_start..line_end]
+      .iter()
+      .map(|c| if *c == '\t' { ' ' } else { *c })
+      .collect();
+    (line, start - line_start)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_render() {
+    let mut sources = SourceMap::new();
+    let file = sources.add(Path::new("a.s"), "nop\n  lda foo bar\n");
+    let location = Location::new(file, 2, 14, 17);
+    let error = AsmError::new(String::from("Unexpected bar"), Some(location));
+    let expected =
+      "error: Unexpected bar\n --> a.s:2:11\n  |\n2 |   lda foo bar\n  |           ^^^";
+    assert_eq!(error.render(&sources), expected);
+    let unplaced = AsmError::new(String::from("Unexpected bar"), None);
+    assert_eq!(unplaced.render(&sources), "error: Unexpected bar");
+    let elsewhere = AsmError::new(
+      String::from("Unexpected bar"),
+      Some(Location::new(9, 2, 0, 3)),
+    );
+    assert_eq!(elsewhere.render(&sources), "error: Unexpected bar (line 2)");
+  }
+}
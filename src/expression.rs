@@ -0,0 +1,300 @@
+use crate::error::{AsmError, AsmResult};
+use crate::node::{Node, NodeType};
+use std::collections::HashMap;
+use std::fmt::Display;
+
+// Anything that can put a value to a name while evaluating an expression
+pub trait SymbolLookup {
+  fn lookup(&self, name: &str) -> Option<i32>;
+
+  // Size of a .struct, .union or one of their members
+  fn lookup_size(&self, _name: &str) -> Option<i32> {
+    None
+  }
+
+  fn describe_unknown(&self, name: &str) -> String {
+    format!("Undefined symbol '{}'", name)
+  }
+}
+
+impl SymbolLookup for HashMap<String, i32> {
+  fn lookup(&self, name: &str) -> Option<i32> {
+    self.get(name).copied()
+  }
+}
+
+// Evaluates a constant expression using 32 bit signed arithmetic, the caller
+// is responsible for checking the result fits wherever it ends up
+pub fn evaluate(node: &Node<String>, symbols: &dyn SymbolLookup) -> AsmResult<i32> {
+  match try_evaluate(node, symbols)? {
+    Some(value) => Ok(value),
+    None => {
+      let unknown = find_unknown(node, symbols).unwrap_or(node);
+      let name = unknown.get_first_data_result();
+      let message = match unknown.get_type() {
+        NodeType::DirectiveSizeof => format!("Size of '{}' is unknown", name),
+        _ => symbols.describe_unknown(name),
+      };
+      Err(AsmError::at_node(unknown, message))
+    }
+  }
+}
+
+// Like evaluate, but a symbol without a value yet gives None instead of an error
+pub fn try_evaluate(node: &Node<String>, symbols: &dyn SymbolLookup) -> AsmResult<Option<i32>> {
+  match node.get_type() {
+    NodeType::Number => evaluate_number(node).map(Some),
+    NodeType::Variable => Ok(symbols.lookup(node.get_first_data_result())),
+    NodeType::DirectiveSizeof => Ok(symbols.lookup_size(node.get_first_data_result())),
+    NodeType::UnaryOp => match try_evaluate(node.get_first_child(), symbols)? {
+      Some(value) => evaluate_unary_op(node, value).map(Some),
+      None => Ok(None),
+    },
+    NodeType::BinaryOp => {
+      let left = try_evaluate(node.get_first_child(), symbols)?;
+      let right = try_evaluate(&node.get_children()[1], symbols)?;
+      match (left, right) {
+        (Some(left), Some(right)) => evaluate_binary_op(node, left, right).map(Some),
+        _ => Ok(None),
+      }
+    }
+    t => {
+      let message = format!("{:?} can not be used in an expression", t);
+      Err(AsmError::at_node(node, message))
+    }
+  }
+}
+
+// The first symbol in the expression that doesn't have a value
+pub fn find_unknown<'n>(
+  node: &'n Node<String>,
+  symbols: &dyn SymbolLookup,
+) -> Option<&'n Node<String>> {
+  match node.get_type() {
+    NodeType::Variable => match symbols.lookup(node.get_first_data_result()) {
+      Some(_) => None,
+      None => Some(node),
+    },
+    NodeType::DirectiveSizeof => match symbols.lookup_size(node.get_first_data_result()) {
+      Some(_) => None,
+      None => Some(node),
+    },
+    _ => node
+      .get_children()
+      .iter()
+      .find_map(|child| find_unknown(child, symbols)),
+  }
+}
+
+// What an expression's value hangs off when `base_of` picks out symbols whose
+// value is only known once linked. The linker can only add a constant to it,
+// so anything more than adding or subtracting is an error
+pub fn find_base<B: PartialEq + Display>(
+  node: &Node<String>,
+  base_of: &dyn Fn(&String) -> Option<B>,
+) -> AsmResult<Option<B>> {
+  let children = node.get_children();
+  let base = match node.get_type() {
+    NodeType::Variable => base_of(node.get_first_data_result()),
+    NodeType::UnaryOp => match find_base(&children[0], base_of)? {
+      Some(base) if get_operator(node) != "+" => return Err(not_relocatable(node, &base)),
+      base => base,
+    },
+    NodeType::BinaryOp => {
+      let left = find_base(&children[0], base_of)?;
+      let right = find_base(&children[1], base_of)?;
+      match (get_operator(node).as_str(), left, right) {
+        (_, None, None) => None,
+        ("+", Some(base), None) | ("+", None, Some(base)) | ("-", Some(base), None) => Some(base),
+        // the difference between two places relative to the same thing is known
+        ("-", Some(left), Some(right)) if left == right => None,
+        (_, Some(base), _) | (_, None, Some(base)) => return Err(not_relocatable(node, &base)),
+      }
+    }
+    _ => None,
+  };
+  Ok(base)
+}
+
+fn not_relocatable(node: &Node<String>, base: &dyn Display) -> AsmError {
+  let message = format!(
+    "{} is only known once linked, the most that can be done to it is adding or subtracting a constant",
+    base
+  );
+  AsmError::at_node(node, message)
+}
+
+// Number nodes hold the literal as an unsigned decimal string, anything up to
+// $FFFFFFFF wraps around into the signed range like ca65 does
+fn evaluate_number(node: &Node<String>) -> AsmResult<i32> {
+  let data = node.get_first_data_result();
+  match data.parse::<u32>() {
+    Ok(num) => Ok(num as i32),
+    Err(_) => {
+      let message = format!("Invalid number {}", data);
+      Err(AsmError::at_node(node, message))
+    }
+  }
+}
+
+pub fn get_operator(node: &Node<String>) -> String {
+  let operator = node.get_first_data_result();
+  operator.trim_start_matches('.').to_ascii_lowercase()
+}
+
+fn evaluate_unary_op(node: &Node<String>, value: i32) -> AsmResult<i32> {
+  let operator = get_operator(node);
+  let result = match operator.as_str() {
+    "+" => value,
+    "-" => value.checked_neg().ok_or_else(|| overflow(node))?,
+    "~" | "bitnot" => !value,
+    "!" | "not" => from_bool(value == 0),
+    "<" | "lobyte" => value & 0xFF,
+    ">" | "hibyte" => (value >> 8) & 0xFF,
+    "^" | "bankbyte" => (value >> 16) & 0xFF,
+    "loword" => value & 0xFFFF,
+    "hiword" => (value >> 16) & 0xFFFF,
+    _ => return Err(unsupported(node, &operator)),
+  };
+  Ok(result)
+}
+
+fn evaluate_binary_op(node: &Node<String>, left: i32, right: i32) -> AsmResult<i32> {
+  let operator = get_operator(node);
+  let result = match operator.as_str() {
+    "+" => left.checked_add(right).ok_or_else(|| overflow(node))?,
+    "-" => left.checked_sub(right).ok_or_else(|| overflow(node))?,
+    "*" => left.checked_mul(right).ok_or_else(|| overflow(node))?,
+    "/" | "mod" => {
+      if right == 0 {
+        return Err(AsmError::at_node(node, String::from("Division by zero")));
+      }
+      let result = match operator.as_str() {
+        "/" => left.checked_div(right),
+        _ => left.checked_rem(right),
+      };
+      result.ok_or_else(|| overflow(node))?
+    }
+    "&" | "bitand" => left & right,
+    "|" | "bitor" => left | right,
+    "^" | "bitxor" => left ^ right,
+    "<<" | "shl" => shift(node, right).map(|count| left << count)?,
+    ">>" | "shr" => shift(node, right).map(|count| left >> count)?,
+    "=" => from_bool(left == right),
+    "<>" => from_bool(left != right),
+    "<" => from_bool(left < right),
+    ">" => from_bool(left > right),
+    "<=" => from_bool(left <= right),
+    ">=" => from_bool(left >= right),
+    "&&" | "and" => from_bool(left != 0 && right != 0),
+    "||" | "or" => from_bool(left != 0 || right != 0),
+    "xor" => from_bool((left != 0) ^ (right != 0)),
+    _ => return Err(unsupported(node, &operator)),
+  };
+  Ok(result)
+}
+
+fn shift(node: &Node<String>, count: i32) -> AsmResult<u32> {
+  match count {
+    0..=31 => Ok(count as u32),
+    _ => {
+      let message = format!("Shift count {} is out of range", count);
+      Err(AsmError::at_node(node, message))
+    }
+  }
+}
+
+fn from_bool(value: bool) -> i32 {
+  match value {
+    true => 1,
+    false => 0,
+  }
+}
+
+fn overflow(node: &Node<String>) -> AsmError {
+  let message = String::from("Arithmetic overflow, result does not fit in 32 bits");
+  AsmError::at_node(node, message)
+}
+
+fn unsupported(node: &Node<String>, operator: &str) -> AsmError {
+  let message = format!("Operator '{}' is not supported in expressions", operator);
+  AsmError::at_node(node, message)
+}
+
+// Range checks for when a value is finally emitted
+pub fn to_byte(node: &Node<String>, value: i32) -> AsmResult<u8> {
+  match value {
+    -128..=255 => Ok(value as u8),
+    _ => Err(range_error(node, value, "-128..255")),
+  }
+}
+
+pub fn to_word(node: &Node<String>, value: i32) -> AsmResult<u16> {
+  match value {
+    -32768..=65535 => Ok(value as u16),
+    _ => Err(range_error(node, value, "-32768..65535")),
+  }
+}
+
+pub fn to_far_address(node: &Node<String>, value: i32) -> AsmResult<u32> {
+  match value {
+    0..=0xFFFFFF => Ok(value as u32),
+    _ => Err(range_error(node, value, "$000000..$FFFFFF")),
+  }
+}
+
+pub fn to_address(node: &Node<String>, value: i32) -> AsmResult<u16> {
+  match value {
+    0..=0xFFFF => Ok(value as u16),
+    _ => Err(range_error(node, value, "$0000..$FFFF")),
+  }
+}
+
+fn range_error(node: &Node<String>, value: i32, range: &str) -> AsmError {
+  let message = format!("Range error, {} is not in {}", value, range);
+  AsmError::at_node(node, message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::SourceMap;
+  use crate::lexer::lex;
+  use crate::options::Options;
+  use crate::parser::parse;
+  use crate::preprocessor::preprocess;
+
+  fn eval(expression: &str) -> AsmResult<i32> {
+    let source = format!("value = {}\n", expression);
+    let tree = parse(
+      preprocess(
+        lex(&source, 0, true)?,
+        &mut SourceMap::new(),
+        &Options::default(),
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    evaluate(tree.get_first_child().get_first_child(), &HashMap::new())
+  }
+
+  #[test]
+  fn check_evaluate() {
+    assert_eq!(eval("2 + 3 * 4").unwrap(), 14);
+    assert_eq!(eval("<$1234 + >$1234").unwrap(), 0x34 + 0x12);
+    assert_eq!(eval("^$123456").unwrap(), 0x12);
+    assert_eq!(eval("-1 < 0").unwrap(), 1);
+    assert_eq!(eval("7 .mod 4 .bitor 8").unwrap(), 11);
+    assert!(eval("1 / 0").is_err());
+    assert!(eval("$7FFFFFFF + 1").is_err());
+  }
+
+  #[test]
+  fn check_ranges() {
+    let node = Node::new(NodeType::Number);
+    assert_eq!(to_word(&node, -1).unwrap(), 0xFFFF);
+    assert!(to_word(&node, 0x10000).is_err());
+    assert_eq!(to_far_address(&node, 0xFFFFFF).unwrap(), 0xFFFFFF);
+    assert!(to_far_address(&node, -1).is_err());
+  }
+}
@@ -1,73 +1,212 @@
-use crate::configuration::{generate_config_data, Configuration, SegType};
+use crate::char_helper::is_local_label_signifier;
+use crate::configuration::{Configuration, SegType};
+use crate::error::{AsmError, AsmResult};
+use crate::expression::{
+  evaluate, find_base, find_unknown, get_operator, to_address, to_byte, to_far_address, to_word,
+  try_evaluate, SymbolLookup,
+};
 use crate::node::{Node, NodeType};
-use crate::opcode::*;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use crate::object::{Export, ObjectFile, ObjectSegment, RelocKind, RelocTarget, Relocation};
+use crate::opcode::{encode, find, invert_branch, AddressingMode, Instruction};
+use crate::options::Options;
+use crate::token::Location;
+use std::collections::{HashMap, HashSet};
 use std::fs::{metadata, read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// Assembles one file. Addresses in its segments are kept relative to the
+// segment, for the linker to fill in once it has placed them
+pub fn assemble(
+  tree: Node<String>,
+  name: &str,
+  config: &Configuration,
+  options: &Options,
+) -> Result<ObjectFile, Vec<AsmError>> {
+  let mut context = Context::new(&tree, config, options);
+  for (name, value) in options.get_defines() {
+    context.add_var_to_map(name, *value);
+  }
+  create_symbols(&tree, &mut context)?;
+  add_imports(&tree, &mut context)?;
+  create_size_map(&tree, &mut context)?;
+  populate_data(&tree, &mut context)?;
+  add_exports(&tree, &mut context)?;
+  Ok(context.into_object(name))
+}
 
-pub fn generate(tree: Node<String>, config_file: &String) -> [u8; 0xFFFF] {
-  let config = generate_config_data(config_file);
-  let mut context = Context::new(&tree, config);
-  create_symbols(&tree, &mut context);
-  create_size_map(&tree, &mut context);
-  populate_data(&tree, &mut context);
-  let mut out = [0; 0xFFFF];
-  for entry in context.get_config().get_segments().get_entries() {
-    let memory = context
-      .get_config()
-      .find_memory_by_name(entry.get_load())
-      .unwrap();
-    let segment = context
-      .get_segment_list()
-      .iter()
-      .find(|s| s.get_name().to_ascii_uppercase() == entry.get_name().to_ascii_uppercase())
-      .unwrap();
-    let start = memory.get_start();
-    let size = memory.get_size() as usize;
-    for (i, value) in segment.get_values().iter().enumerate() {
-      if i > size {
-        panic!("Too many values in segment: {:?}", segment.get_name());
-      }
-      let address = i + start as usize;
-      if *value != 0 {
-        out[address] = *value;
-      }
+// Runs a pass over every statement, collecting errors rather than stopping at the first
+fn run_pass<F: Fn(&Node<String>, &mut Context) -> AsmResult<()>>(
+  tree: &Node<String>,
+  context: &mut Context,
+  pass: F,
+) -> Result<(), Vec<AsmError>> {
+  context.reset_tracking();
+  let mut errors: Vec<AsmError> = tree
+    .get_children()
+    .iter()
+    .filter_map(|node| {
+      let result = pass(node, context);
+      context.track_cheap_local_owner(node);
+      let tracked = context.track_scope(node);
+      result.and(tracked).err().map(|e| e.or_at_node(node))
+    })
+    .collect();
+  errors.append(&mut context.take_unclosed_scopes());
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(errors),
+  }
+}
+
+fn create_symbols(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  run_pass(tree, context, |node, context| match node.get_type() {
+    NodeType::AssignmentStatement => add_assignment_variables(node, context),
+    NodeType::DirectiveStatement => add_directive_symbols(node.get_first_child(), context),
+    NodeType::LabelStatement => add_labels(node.get_first_child(), context),
+    _ => Ok(()),
+  })
+}
+
+// values are worked out while sizing, once labels have addresses
+fn add_assignment_variables(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let key = node.get_first_data_result();
+  context.declare_var(key);
+  Ok(())
+}
+
+fn add_directive_symbols(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  match node.get_type() {
+    NodeType::DirectiveSegment => context.switch_segment(node),
+    NodeType::DirectivePushseg => context.push_segment(),
+    NodeType::DirectivePopseg => context.pop_segment(node),
+    // a .proc is also a label in the scope around it
+    NodeType::DirectiveProc => context.add_label_to_map(node.get_first_data_result()),
+    NodeType::DirectiveStruct | NodeType::DirectiveUnion => {
+      let prefix = format!("{}::", node.get_first_data_result());
+      declare_struct_members(node, &prefix, context);
+      Ok(())
     }
+    _ => Ok(()),
   }
-  out
 }
 
-fn create_symbols(tree: &Node<String>, context: &mut Context) {
-  for node in tree.get_children() {
-    match node.get_type() {
-      NodeType::AssignmentStatement => add_assignment_variables(&node, context),
-      NodeType::DirectiveStatement => switch_current_segment(&node.get_first_child(), context),
-      NodeType::LabelStatement => add_labels(&node.get_first_child(), context),
-      _ => (),
+fn declare_struct_members(node: &Node<String>, prefix: &str, context: &mut Context) {
+  for member in node.get_children() {
+    let name = member
+      .get_data()
+      .first()
+      .map(|name| format!("{}{}", prefix, name));
+    if let Some(name) = &name {
+      context.declare_var(name);
+    }
+    if let NodeType::DirectiveStruct | NodeType::DirectiveUnion = member.get_type() {
+      let prefix = match &name {
+        Some(name) => format!("{}::", name),
+        None => prefix.to_owned(),
+      };
+      declare_struct_members(member, &prefix, context);
     }
   }
 }
 
-fn add_assignment_variables(node: &Node<String>, context: &mut Context) {
-  let key = node.get_first_data_result();
-  let child = node.get_first_child();
-  let value = child.get_first_data_result();
-  let value = u16::from_str_radix(value, 10).unwrap();
-  context.add_var_to_map(key, value);
+// Runs once every label and assignment is known, as .global only imports
+// what this file doesn't define
+fn add_imports(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  run_pass(tree, context, |node, context| {
+    if node.get_type() != &NodeType::DirectiveStatement {
+      return Ok(());
+    }
+    let directive = node.get_first_child();
+    let (mode, is_global) = match directive.get_type() {
+      NodeType::DirectiveImport => (AddressMode::Absolute, false),
+      NodeType::DirectiveImportzp => (AddressMode::ZeroPage, false),
+      NodeType::DirectiveGlobal => (AddressMode::Absolute, true),
+      NodeType::DirectiveGlobalzp => (AddressMode::ZeroPage, true),
+      _ => return Ok(()),
+    };
+    // other files only know the name, so it's imported at the global scope
+    // whichever scope the directive is in
+    for name in directive.get_data() {
+      match (context.resolve_symbol(name).is_some(), is_global) {
+        (true, true) => (),
+        (true, false) => {
+          let message = format!("'{}' is imported, but this file also defines it", name);
+          return Err(AsmError::at_node(directive, message));
+        }
+        (false, _) => context.add_import(get_global_name(name).to_owned(), mode),
+      }
+    }
+    Ok(())
+  })
 }
 
-fn switch_current_segment(node: &Node<String>, context: &mut Context) {
-  if node.get_type() == &NodeType::DirectiveSegment {
-    context.switch_segment(node.get_first_data_result());
-  }
+fn get_global_name(name: &str) -> &str {
+  name.strip_prefix("::").unwrap_or(name)
 }
 
-fn add_labels(node: &Node<String>, context: &mut Context) {
+// Exports go out with their final values, so this runs after everything else
+fn add_exports(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  run_pass(tree, context, |node, context| {
+    if node.get_type() != &NodeType::DirectiveStatement {
+      return Ok(());
+    }
+    let directive = node.get_first_child();
+    let (is_zero_page, is_global) = match directive.get_type() {
+      NodeType::DirectiveExport => (false, false),
+      NodeType::DirectiveExportzp => (true, false),
+      NodeType::DirectiveGlobal => (false, true),
+      NodeType::DirectiveGlobalzp => (true, true),
+      _ => return Ok(()),
+    };
+    for name in directive.get_data() {
+      let segment = match context.get_base(name) {
+        // a .global this file doesn't define is an import
+        Some(RelocTarget::Import(_)) if is_global => continue,
+        Some(RelocTarget::Import(_)) => {
+          let message = format!("'{}' is imported, it can't be exported as well", name);
+          return Err(AsmError::at_node(directive, message));
+        }
+        Some(RelocTarget::Segment(segment)) => Some(segment),
+        None => None,
+      };
+      let value = match context.lookup(name) {
+        Some(value) => value,
+        None => {
+          let message = format!("Exported symbol '{}' is not defined", name);
+          return Err(AsmError::at_node(directive, message));
+        }
+      };
+      let problem = match &segment {
+        Some(segment) if !context.is_zero_page(&RelocTarget::Segment(segment.to_owned())) => {
+          Some(format!("it is in segment {}", segment))
+        }
+        None if !(0..=0xFF).contains(&value) => Some(format!("it is ${:04X}", value)),
+        _ => None,
+      };
+      if let (true, Some(problem)) = (is_zero_page, problem) {
+        let message = format!("'{}' is exported as zero page, but {}", name, problem);
+        return Err(AsmError::at_node(directive, message));
+      }
+      context.add_export(Export::new(get_global_name(name), value, segment));
+    }
+    Ok(())
+  })
+}
+
+fn add_labels(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   match node.get_type() {
     NodeType::UnnamedLabel => context.add_unnamed_label_to_map(),
+    NodeType::LocalLabel if !context.has_cheap_local_owner() => {
+      let message = format!(
+        "Cheap local label '{}' needs a normal label before it",
+        node.get_first_data_result()
+      );
+      Err(AsmError::at_node(node, message))
+    }
     _ => {
       let name = node.get_first_data_result();
-      context.add_label_to_map(name);
+      context.add_label_to_map(name)
     }
   }
 }
@@ -79,576 +218,1188 @@ fn get_count(node_type: &NodeType, children: &Vec<Node<String>>) -> usize {
     .count()
 }
 
-fn create_size_map(tree: &Node<String>, context: &mut Context) {
-  context.reset_label_count();
-  for child in tree.get_children() {
-    match child.get_type() {
+const MAX_SIZE_PASSES: usize = 64;
+
+// Sizes everything repeatedly, using the label addresses from the pass before,
+// until nothing moves. Operand sizes only ever grow, so they always settle.
+// .res and .incbin counts are worked out afresh each pass and settle once the
+// labels they depend on do
+fn create_size_map(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  for _ in 0..MAX_SIZE_PASSES {
+    context.start_pass();
+    context.reset_segment_sizes();
+    run_pass(tree, context, |child, context| match child.get_type() {
       NodeType::DirectiveStatement => add_directive_sizes(child, context),
       NodeType::LabelStatement => add_label_sizes(child, context),
       NodeType::OpcodeStatement => add_opcode_sizes(child, context),
-      NodeType::AssignmentStatement => (),
-      _ => panic!("Invalid statement type {:?}", child.get_type()),
+      NodeType::AssignmentStatement => update_assignment(child, context),
+      _ => Err(invalid_node("statement type", child)),
+    })?;
+    if !context.has_changed() {
+      return check_assignments(tree, context);
+    }
+  }
+  let message = format!("Sizes did not settle after {} passes", MAX_SIZE_PASSES);
+  Err(vec![AsmError::new(message, None)])
+}
+
+// A symbol set from a label keeps hanging off its segment
+fn update_assignment(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  if let Some(operand) = try_get_operand(node.get_first_child(), context)? {
+    let name = node.get_first_data_result();
+    context.update_var(name, operand.value);
+    context.update_var_base(name, operand.base);
+  }
+  Ok(())
+}
+
+// Anything still without a value is either missing a symbol or defined in
+// terms of itself
+fn check_assignments(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  run_pass(tree, context, |child, context| match child.get_type() {
+    NodeType::AssignmentStatement => check_assignment(child, context),
+    NodeType::DirectiveStatement => check_struct(child.get_first_child(), context),
+    _ => Ok(()),
+  })
+}
+
+fn check_struct(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  match node.get_type() {
+    NodeType::DirectiveStruct | NodeType::DirectiveUnion => (),
+    _ => return Ok(()),
+  }
+  for member in node.get_children() {
+    match member.get_type() {
+      NodeType::DirectiveStruct | NodeType::DirectiveUnion => check_struct(member, context)?,
+      _ => {
+        if let Some(count) = member.get_children().first() {
+          evaluate_constant(count, context)?;
+        }
+      }
     }
   }
+  Ok(())
 }
 
-fn add_directive_sizes(node: &Node<String>, context: &mut Context) {
+fn check_assignment(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let name = node.get_first_data_result();
+  if context.get_var(&context.qualify(name)).is_some() {
+    return Ok(());
+  }
+  let expression = node.get_first_child();
+  let is_declared = |unknown: &Node<String>| {
+    if unknown.get_type() != &NodeType::Variable {
+      return false;
+    }
+    let key = context.resolve_symbol(unknown.get_first_data_result());
+    match key {
+      Some(key) => context.is_declared_var(&key),
+      None => false,
+    }
+  };
+  match find_unknown(expression, context) {
+    Some(unknown) if is_declared(unknown) => {
+      let message = format!("Circular reference, '{}' depends on its own value", name);
+      Err(AsmError::at_node(node, message))
+    }
+    _ => evaluate(expression, context).map(|_| ()),
+  }
+}
+
+fn add_directive_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   for child in node.get_children() {
     match child.get_type() {
-      NodeType::DirectiveSegment => context.switch_segment(child.get_first_data_result()),
-      NodeType::DirectiveByte | NodeType::DirectiveByt => add_byte_sizes(child, context),
-      NodeType::DirectiveIncbin => add_incbin_sizes(child, context),
-      NodeType::DirectiveWord => add_word_sizes(child, context),
-      NodeType::DirectiveRes => add_res_sizes(child, context),
-      _ => panic!("Directive not found {:?}", child.get_type()),
+      NodeType::DirectiveSegment => context.switch_segment(child)?,
+      NodeType::DirectivePushseg => context.push_segment()?,
+      NodeType::DirectivePopseg => context.pop_segment(child)?,
+      NodeType::DirectiveProc => context.add_size_to_label(child.get_first_data_result())?,
+      NodeType::DirectiveScope | NodeType::DirectiveEndscope | NodeType::DirectiveEndproc => (),
+      // the lexer has already applied it
+      NodeType::DirectiveLocalchar => (),
+      // dealt with before sizing and after the bytes are in
+      NodeType::DirectiveImport
+      | NodeType::DirectiveImportzp
+      | NodeType::DirectiveExport
+      | NodeType::DirectiveExportzp
+      | NodeType::DirectiveGlobal
+      | NodeType::DirectiveGlobalzp => (),
+      NodeType::DirectiveByte | NodeType::DirectiveByt => add_byte_sizes(child, context)?,
+      NodeType::DirectiveIncbin => add_incbin_sizes(child, context)?,
+      NodeType::DirectiveWord
+      | NodeType::DirectiveAddr
+      | NodeType::DirectiveDbyt
+      | NodeType::DirectiveFaraddr
+      | NodeType::DirectiveDword
+      | NodeType::DirectiveLobytes
+      | NodeType::DirectiveHibytes
+      | NodeType::DirectiveBankbytes => add_value_sizes(child, context)?,
+      NodeType::DirectiveAsciiz => add_asciiz_sizes(child, context)?,
+      // only changes the bytes strings turn into, not how many
+      NodeType::DirectiveCharmap => (),
+      NodeType::DirectiveRes | NodeType::DirectiveTag => add_res_sizes(child, context)?,
+      NodeType::DirectiveOrg => {
+        let offset = context.get_current_segment_size()?;
+        set_origin(child, offset, context)?
+      }
+      NodeType::DirectiveReloc => context.get_current_segment()?.set_origin(None),
+      NodeType::DirectiveAlign => add_align_sizes(child, context)?,
+      NodeType::DirectiveStruct | NodeType::DirectiveUnion => add_struct_sizes(child, context)?,
+      _ => return Err(unsupported_directive(child)),
     }
   }
+  Ok(())
 }
 
-fn add_label_sizes(node: &Node<String>, context: &mut Context) {
+fn add_label_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   for child in node.get_children() {
     match child.get_type() {
       NodeType::UnnamedLabel => {
-        context.add_size_to_unnamed_label();
+        context.add_size_to_unnamed_label()?;
       }
       NodeType::Label | NodeType::LocalLabel => {
         let data = child.get_first_data_result();
-        context.add_size_to_label(data);
+        context.add_size_to_label(data)?;
       }
-      _ => panic!("Invalid label type {:?}", child.get_type()),
+      _ => return Err(invalid_node("label type", child)),
     }
   }
+  Ok(())
 }
 
-fn add_opcode_sizes(node: &Node<String>, context: &mut Context) {
+fn add_opcode_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   for child in node.get_children() {
     match child.get_type() {
-      NodeType::AccumulatorMode => add_accumulator_sizes(context),
-      NodeType::ImmediateMode => add_immediate_mode_sizes(context),
+      NodeType::ImpliedMode => add_mode_size(AddressingMode::Implied, context)?,
+      NodeType::AccumulatorMode => add_mode_size(AddressingMode::Accumulator, context)?,
+      NodeType::ImmediateMode => add_immediate_mode_sizes(context)?,
       NodeType::DirectMode | NodeType::DirectRegXMode | NodeType::DirectRegYMode => {
-        add_direct_mode_sizes(child, context)
+        add_direct_mode_sizes(child, context)?
       }
-      NodeType::RelativeMode => add_relative_mode_sizes(context),
-      _ => (),
+      NodeType::RelativeMode => add_relative_mode_sizes(child, context)?,
+      NodeType::IndirectMode => add_mode_size(AddressingMode::Indirect, context)?,
+      NodeType::IndirectXMode => add_mode_size(AddressingMode::IndirectX, context)?,
+      NodeType::IndirectYMode => add_mode_size(AddressingMode::IndirectY, context)?,
+      _ => return Err(invalid_node("addressing mode", child)),
     }
   }
+  Ok(())
 }
 
-fn add_byte_sizes(node: &Node<String>, context: &mut Context) {
-  for child in node.get_children() {
-    let dir_args = child.get_children();
-    for arg in dir_args {
-      match arg.get_type() {
-        NodeType::String => {
-          let data = arg.get_first_data_result();
-          let count = data.chars().count();
-          context.add_size_to_current_segment(count);
-        }
-        NodeType::Number => {
-          context.add_size_to_current_segment(1);
-        }
-        NodeType::BinaryOp => {
-          // assuming that a sequence of operations will result in a single byte of data
-          context.add_size_to_current_segment(1);
-        }
-        _ => panic!("nyi"),
+fn add_byte_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let count = node
+    .get_first_child()
+    .get_children()
+    .iter()
+    .map(get_byte_count)
+    .sum();
+  context.add_size_to_current_segment(count)
+}
+
+// Strings give a byte per character, anything else is one expression
+fn get_byte_count(arg: &Node<String>) -> usize {
+  match arg.get_type() {
+    NodeType::String => arg.get_first_data_result().chars().count(),
+    _ => 1,
+  }
+}
+
+fn add_incbin_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let args = node.get_first_child().get_children();
+  let path = get_incbin_path(node, args)?;
+  let meta = metadata(&path).map_err(|e| file_error(&args[0], &path, e))?;
+  let range = get_incbin_range(args, &path, meta.len() as usize, |arg| {
+    try_evaluate_constant(arg, context)
+  })?;
+  let size = context.resolve_count(range.map(|r| r.len()));
+  context.add_size_to_current_segment(size)
+}
+
+// <incbin> ::= ".incbin" <file-name> [ "," <offset> [ "," <length> ] ]
+// the preprocessor has already swapped the name for where the file was found
+fn get_incbin_path(node: &Node<String>, args: &[Node<String>]) -> AsmResult<PathBuf> {
+  match (args.first().map(Node::get_type), args.len()) {
+    (Some(NodeType::String), 1..=3) => Ok(PathBuf::from(args[0].get_first_data_result())),
+    (Some(NodeType::String), _) => Err(AsmError::at_node(
+      &args[3],
+      String::from("Too many arguments to .incbin"),
+    )),
+    _ => Err(invalid_node("argument to .incbin", node)),
+  }
+}
+
+// The part of the file to include, None while the offset or length aren't known
+fn get_incbin_range<F: Fn(&Node<String>) -> AsmResult<Option<i32>>>(
+  args: &[Node<String>],
+  path: &Path,
+  file_len: usize,
+  evaluate: F,
+) -> AsmResult<Option<Range<usize>>> {
+  let mut values = vec![];
+  for arg in args.iter().skip(1) {
+    match evaluate(arg)? {
+      Some(value) if value < 0 => {
+        return Err(AsmError::at_node(
+          arg,
+          format!("Range error, {} is negative", value),
+        ))
       }
+      Some(value) => values.push(value as usize),
+      None => return Ok(None),
     }
   }
+  let offset = values.first().copied().unwrap_or(0);
+  let length = values
+    .get(1)
+    .copied()
+    .unwrap_or_else(|| file_len.saturating_sub(offset));
+  if offset + length > file_len {
+    let message = format!(
+      "{} bytes at offset {} is past the end of {} ({} bytes)",
+      length,
+      offset,
+      path.display(),
+      file_len
+    );
+    return Err(AsmError::at_node(&args[1], message));
+  }
+  Ok(Some(offset..offset + length))
 }
 
-fn add_incbin_sizes(node: &Node<String>, context: &mut Context) {
-  let dir_args = node.get_first_child();
-  for arg in dir_args.get_children() {
-    let file_name = arg.get_first_data_result();
-    let path = format!("src/data/{}", file_name);
-    let meta = metadata(path).expect("file not found");
-    context.add_size_to_current_segment(meta.len() as usize);
+// Bytes taken up by each value of a data directive, or each unit of a struct
+// member
+fn get_data_width(node: &Node<String>) -> usize {
+  match node.get_type() {
+    NodeType::DirectiveWord | NodeType::DirectiveAddr | NodeType::DirectiveDbyt => 2,
+    NodeType::DirectiveFaraddr => 3,
+    NodeType::DirectiveDword => 4,
+    _ => 1,
   }
 }
 
-fn add_word_sizes(node: &Node<String>, context: &mut Context) {
-  let dir_args = node.get_first_child();
-  for _ in dir_args.get_children() {
-    context.add_size_to_current_segment(2);
+fn add_value_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let count = node.get_first_child().get_children().len();
+  context.add_size_to_current_segment(count * get_data_width(node))
+}
+
+fn add_asciiz_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let mut count = 1;
+  for arg in node.get_first_child().get_children() {
+    count += get_asciiz_string(arg)?.chars().count();
+  }
+  context.add_size_to_current_segment(count)
+}
+
+fn get_asciiz_string(arg: &Node<String>) -> AsmResult<&String> {
+  match arg.get_type() {
+    NodeType::String => Ok(arg.get_first_data_result()),
+    _ => Err(invalid_node("argument to .asciiz", arg)),
   }
 }
 
-fn add_res_sizes(node: &Node<String>, context: &mut Context) {
+fn add_res_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   let dir_args = node.get_first_child();
   let size = dir_args.get_first_child();
-  let number = usize::from_str_radix(size.get_first_data_result(), 10).unwrap();
-  context.add_size_to_current_segment(number);
+  let number = match try_evaluate_constant(size, context)? {
+    Some(value) => Some(to_address(size, value)? as usize),
+    None => None,
+  };
+  let number = context.resolve_count(number);
+  context.add_size_to_current_segment(number)
 }
 
-fn add_accumulator_sizes(context: &mut Context) {
-  context.add_size_to_current_segment(1);
+// <org> ::= ".org" <expression>
+// Whatever follows runs from the given address, though it is still stored
+// where it would have been
+fn set_origin(node: &Node<String>, offset: u32, context: &mut Context) -> AsmResult<()> {
+  let arg = node.get_first_child().get_first_child();
+  let address = to_address(arg, evaluate_constant(arg, context)?)? as u32;
+  context
+    .get_current_segment()?
+    .set_origin(Some(Origin { address, offset }));
+  Ok(())
 }
 
-fn add_immediate_mode_sizes(context: &mut Context) {
-  context.add_size_to_current_segment(2);
+fn add_align_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let offset = context.get_current_segment_size()?;
+  let padding = get_align_padding(node, offset, context)?;
+  context.add_size_to_current_segment(padding)
 }
 
-fn add_direct_mode_sizes(node: &Node<String>, context: &mut Context) {
-  let operand_node = node.get_first_child();
-  match operand_node.get_type() {
-    NodeType::Number => {
-      let bytes = get_bytes_from_number_node(operand_node);
-      let count = match bytes[1] > 0 {
-        true => 3,
-        false => 2,
-      };
-      context.add_size_to_current_segment(count);
-    }
-    NodeType::Variable => {
-      let variable = operand_node.get_first_data_result();
-      context.add_size_from_variable(variable);
-    }
-    NodeType::LabelJump => {
-      let data = operand_node.get_first_data_result();
-      let is_pos = data.chars().any(|c| c == '+');
-      let f = |op| data.chars().filter(|c| c == &op).count();
-      let count = match is_pos {
-        true => f('+'),
-        false => f('-'),
-      };
-      context.add_size_to_label_jump(is_pos, count);
-    }
-    NodeType::BinaryOp => {
-      // in the case of a label we need to parse through the expression tree
-      // to figure out how big the number is
-      let result = evaluate_binary_op_for_size(operand_node, context);
-      let size = match result > 0xFF {
-        true => 3,
-        false => 2,
-      };
-      context.add_size_to_current_segment(size);
-    }
-    _ => panic!(
-      "Invalid child for direct mode {:?}",
-      operand_node.get_type()
-    ),
+// <align> ::= ".align" <expression> [ "," <expression> ]
+// Bytes needed to get from the offset to the next multiple of the alignment
+fn get_align_padding(node: &Node<String>, offset: u32, context: &mut Context) -> AsmResult<usize> {
+  let args = node.get_first_child().get_children();
+  let alignment = to_address(&args[0], evaluate_constant(&args[0], context)?)?;
+  if alignment == 0 {
+    let message = String::from("Alignment must be at least 1");
+    return Err(AsmError::at_node(&args[0], message));
+  }
+  let segment = context.get_current_segment()?;
+  // the linker has to start this file's part of the segment aligned as well
+  if segment.get_origin().is_none() {
+    segment.align_to(alignment);
+  }
+  let address = context.get_address_at(offset)? as usize;
+  let alignment = alignment as usize;
+  Ok((alignment - address % alignment) % alignment)
+}
+
+fn add_struct_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let name = node.get_first_data_result();
+  if let Some(size) = define_struct(node, &format!("{}::", name), 0, context)? {
+    context.update_size(name, size);
   }
+  Ok(())
 }
 
-fn add_relative_mode_sizes(context: &mut Context) {
-  context.add_size_to_current_segment(2);
+// Gives each named member its offset and size and returns the size of the
+// whole thing, if every member's is known yet. Members of an unnamed nested
+// struct or union belong to the one around it
+fn define_struct(
+  node: &Node<String>,
+  prefix: &str,
+  base: i32,
+  context: &mut Context,
+) -> AsmResult<Option<i32>> {
+  let is_union = node.get_type() == &NodeType::DirectiveUnion;
+  let mut size = 0;
+  for member in node.get_children() {
+    let name = member
+      .get_data()
+      .first()
+      .map(|name| format!("{}{}", prefix, name));
+    let offset = match is_union {
+      true => base,
+      false => base + size,
+    };
+    let member_size = match (member.get_type(), &name) {
+      (NodeType::DirectiveStruct | NodeType::DirectiveUnion, Some(name)) => {
+        define_struct(member, &format!("{}::", name), 0, context)?
+      }
+      (NodeType::DirectiveStruct | NodeType::DirectiveUnion, None) => {
+        define_struct(member, prefix, offset, context)?
+      }
+      _ => get_member_size(member, context)?,
+    };
+    let member_size = match member_size {
+      Some(member_size) => member_size,
+      None => return Ok(None),
+    };
+    if let Some(name) = &name {
+      context.update_var(name, offset);
+      context.update_size(name, member_size);
+    }
+    size = match is_union {
+      true => size.max(member_size),
+      false => size + member_size,
+    };
+  }
+  Ok(Some(size))
 }
 
-fn evaluate_binary_op_for_size(node: &Node<String>, context: &mut Context) -> u16 {
-  let left = node.get_first_child();
-  let right = node.get_children().get(1).unwrap();
-  let operator = node.get_first_data_result();
-  let left_num = evaluate_term_for_size(left, context);
-  let right_num = evaluate_term_for_size(right, context);
-  match operator.as_str() {
-    "+" => left_num + right_num,
-    "-" => left_num - right_num,
-    "/" => left_num / right_num,
-    "*" => left_num * right_num,
-    "|" => left_num | right_num,
-    "&" => left_num & right_num,
-    "<<" => left_num << right_num,
-    ">>" => left_num >> right_num,
-    _ => panic!("unsupported operator {}", operator),
+fn get_member_size(member: &Node<String>, context: &mut Context) -> AsmResult<Option<i32>> {
+  let unit = get_data_width(member) as i32;
+  let count = match member.get_children().first() {
+    Some(count) => count,
+    None => return Ok(Some(unit)),
+  };
+  match try_evaluate_constant(count, context)? {
+    Some(value) => Ok(Some(unit * to_address(count, value)? as i32)),
+    None => Ok(None),
   }
 }
 
-fn evaluate_term_for_size(node: &Node<String>, context: &mut Context) -> u16 {
-  let name = node.get_first_data_result();
+fn add_immediate_mode_sizes(context: &mut Context) -> AsmResult<()> {
+  add_mode_size(AddressingMode::Immediate, context)
+}
+
+// symbols defined further down have no value on the first pass, guess zero
+// page and let later passes grow it. Anything the linker fills in goes by the
+// address size of its segment or import
+fn add_direct_mode_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let size = match try_get_operand(node.get_first_child(), context)? {
+    Some(Operand {
+      base: Some(base), ..
+    }) => Some(get_direct_mode_size(node, context.is_zero_page(&base))),
+    Some(operand) => Some(get_direct_mode_size(
+      node,
+      (0..=0xFF).contains(&operand.value),
+    )),
+    None => None,
+  };
+  let size = context.resolve_size(size, 2);
+  context.add_size_to_current_segment(size)
+}
+
+fn get_direct_modes(node: &Node<String>) -> (AddressingMode, AddressingMode) {
   match node.get_type() {
-    NodeType::Number => u16::from_str_radix(name, 10).unwrap(),
-    NodeType::BinaryOp => evaluate_binary_op_for_size(node, context),
-    NodeType::Variable => {
-      let var_opt = context.get_var(name);
-      match var_opt {
-        Some(num) => *num,
-        // if we're not in zero page add 0x100 to determine byte size
-        None => match context.is_label_in_zero_page(name) {
-          true => 0,
-          false => 0x100,
-        },
+    NodeType::DirectRegXMode => (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX),
+    NodeType::DirectRegYMode => (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY),
+    _ => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+  }
+}
+
+// zero page when the value fits and the instruction has the mode, otherwise absolute
+fn get_direct_mode_size(node: &Node<String>, fits: bool) -> usize {
+  let opcode = node.get_first_data_result();
+  let (zero_page, absolute) = get_direct_modes(node);
+  let instruction = match fits {
+    true => find(opcode, zero_page).or_else(|| find(opcode, absolute)),
+    false => find(opcode, absolute),
+  };
+  // one that doesn't exist is reported when its bytes go in
+  instruction.map_or(zero_page.get_length(), Instruction::get_length) as usize
+}
+
+const LONG_BRANCH_SIZE: usize = 5;
+
+// Branches stay two bytes unless they can't reach and long branches are on,
+// then they become an inverted branch over a JMP to the target
+fn add_relative_mode_sizes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let size = match context.options.use_long_branches() {
+    true => {
+      let offset = context.get_current_segment_size()?;
+      let address = context.get_address_at(offset)?;
+      let base = context.get_current_base()?;
+      // only a target placed along with the branch can be measured now
+      match try_get_operand(node.get_first_child(), context)? {
+        Some(target)
+          if target.base == base && get_branch_offset(node, target.value, address).is_err() =>
+        {
+          Some(LONG_BRANCH_SIZE)
+        }
+        _ => None,
       }
     }
-    _ => panic!("unknown term: {:?}", node.get_type()),
-  }
+    false => None,
+  };
+  let size = context.resolve_size(size, AddressingMode::Relative.get_length() as usize);
+  context.add_size_to_current_segment(size)
 }
 
-fn get_bytes_from_number_node(node: &Node<String>) -> [u8; 2] {
+// `:+` counts the plus signs, `:--` the minus signs
+fn parse_label_jump(node: &Node<String>) -> (bool, usize) {
   let data = node.get_first_data_result();
-  let full = u16::from_str_radix(data, 10).unwrap();
-  full.to_le_bytes()
+  let is_pos = data.chars().any(|c| c == '+');
+  let f = |op| data.chars().filter(|c| c == &op).count();
+  let count = match is_pos {
+    true => f('+'),
+    false => f('-'),
+  };
+  (is_pos, count)
 }
 
-fn populate_data(tree: &Node<String>, context: &mut Context) {
-  context.reset_label_count();
-  for child in tree.get_children() {
-    match child.get_type() {
-      NodeType::AssignmentStatement => (),
-      NodeType::DirectiveStatement => populate_directive_data(child, context),
-      NodeType::LabelStatement => {
-        let label_type_node = child.get_first_child();
-        if label_type_node.get_type() == &NodeType::UnnamedLabel {
-          context.advance_unnamed_label_counter();
-        }
+// Offsets count from the instruction after the branch
+fn get_branch_offset(node: &Node<String>, target: i32, address: u32) -> AsmResult<u8> {
+  let offset = target - (address as i32 + AddressingMode::Relative.get_length() as i32);
+  let distance = match offset {
+    -128..=127 => return Ok(offset as u8),
+    o if o > 127 => o - 127,
+    o => -128 - o,
+  };
+  let message = format!("Branch out of range by {} bytes", distance);
+  Err(AsmError::at_node(node, message))
+}
+
+fn add_mode_size(mode: AddressingMode, context: &mut Context) -> AsmResult<()> {
+  context.add_size_to_current_segment(mode.get_length() as usize)
+}
+
+fn populate_data(tree: &Node<String>, context: &mut Context) -> Result<(), Vec<AsmError>> {
+  context.start_pass();
+  run_pass(tree, context, |child, context| match child.get_type() {
+    NodeType::AssignmentStatement => Ok(()),
+    NodeType::DirectiveStatement => populate_directive_data(child, context),
+    NodeType::LabelStatement => {
+      let label_type_node = child.get_first_child();
+      if label_type_node.get_type() == &NodeType::UnnamedLabel {
+        context.advance_unnamed_label_counter();
       }
-      NodeType::OpcodeStatement => populate_opcode_data(child, context),
-      _ => panic!("Invalid statement type {:?}", child.get_type()),
+      Ok(())
     }
-  }
+    NodeType::OpcodeStatement => populate_opcode_data(child, context),
+    _ => Err(invalid_node("statement type", child)),
+  })
 }
 
-fn populate_directive_data(node: &Node<String>, context: &mut Context) {
+fn populate_directive_data(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   for child in node.get_children() {
     match child.get_type() {
-      NodeType::DirectiveSegment => context.switch_segment(child.get_first_data_result()),
-      NodeType::DirectiveByte | NodeType::DirectiveByt => populate_bytes(child, context),
-      NodeType::DirectiveIncbin => populate_incbin(child, context),
-      NodeType::DirectiveWord => populate_words(child, context),
-      NodeType::DirectiveRes => (),
-      _ => panic!("Unimplemented type {:?}", child.get_type()),
+      NodeType::DirectiveSegment => context.switch_segment(child)?,
+      NodeType::DirectivePushseg => context.push_segment()?,
+      NodeType::DirectivePopseg => context.pop_segment(child)?,
+      NodeType::DirectiveScope
+      | NodeType::DirectiveProc
+      | NodeType::DirectiveEndscope
+      | NodeType::DirectiveEndproc
+      | NodeType::DirectiveLocalchar
+      | NodeType::DirectiveImport
+      | NodeType::DirectiveImportzp
+      | NodeType::DirectiveExport
+      | NodeType::DirectiveExportzp
+      | NodeType::DirectiveGlobal
+      | NodeType::DirectiveGlobalzp => (),
+      NodeType::DirectiveByte | NodeType::DirectiveByt => populate_bytes(child, context)?,
+      NodeType::DirectiveIncbin => populate_incbin(child, context)?,
+      NodeType::DirectiveWord
+      | NodeType::DirectiveAddr
+      | NodeType::DirectiveDbyt
+      | NodeType::DirectiveFaraddr
+      | NodeType::DirectiveDword
+      | NodeType::DirectiveLobytes
+      | NodeType::DirectiveHibytes
+      | NodeType::DirectiveBankbytes => populate_values(child, context)?,
+      NodeType::DirectiveAsciiz => populate_asciiz(child, context)?,
+      NodeType::DirectiveCharmap => populate_charmap(child, context)?,
+      NodeType::DirectiveRes | NodeType::DirectiveTag => populate_res(child, context)?,
+      NodeType::DirectiveOrg => {
+        let offset = context.get_current_segment()?.get_value_len();
+        set_origin(child, offset, context)?
+      }
+      NodeType::DirectiveReloc => context.get_current_segment()?.set_origin(None),
+      NodeType::DirectiveAlign => populate_align(child, context)?,
+      NodeType::DirectiveStruct | NodeType::DirectiveUnion => (),
+      _ => return Err(unsupported_directive(child)),
     }
   }
+  Ok(())
 }
 
-fn populate_bytes(node: &Node<String>, context: &mut Context) {
-  for child in node.get_children() {
-    for arg in child.get_children() {
-      match arg.get_type() {
-        NodeType::String => {
-          let data = arg.get_first_data_result();
-          data
-            .chars()
-            .for_each(|c| context.add_value_to_current_segment(c as u8));
-        }
-        NodeType::Number => {
-          let num = arg.get_first_data_result();
-          let num = u8::from_str_radix(num, 10).unwrap();
-          context.add_value_to_current_segment(num);
-        }
-        NodeType::BinaryOp => {
-          let num = evaluate_binary_expression(arg, context);
-          match num > 0xFF {
-            true => {
-              let bytes = num.to_le_bytes();
-              context.add_value_to_current_segment(bytes[0]);
-              context.add_value_to_current_segment(bytes[1]);
-            }
-            false => {
-              context.add_value_to_current_segment(num as u8);
-            }
-          }
-        }
-        _ => panic!("nyi"),
+fn populate_bytes(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  for arg in node.get_first_child().get_children() {
+    let bytes = match arg.get_type() {
+      NodeType::String => encode_string(arg, context)?,
+      _ => {
+        let (kind, operand) = get_byte_operand(arg, context)?;
+        add_operand(arg, operand, kind, context)?;
+        continue;
       }
+    };
+    debug_assert_eq!(bytes.len(), get_byte_count(arg));
+    for byte in bytes {
+      context.add_value_to_current_segment(byte)?;
     }
   }
+  Ok(())
 }
 
-fn populate_incbin(node: &Node<String>, context: &mut Context) {
-  for child in node.get_children() {
-    for arg in child.get_children() {
-      for file_name in arg.get_data() {
-        let path = format!("src/data/{}", file_name);
-        let error = format!("File not found {}", file_name);
-        let bytes = read(path).expect(&error);
-        for byte in bytes.iter() {
-          context.add_value_to_current_segment(*byte);
-        }
+fn populate_res(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let count = context.next_size();
+  let size = node.get_first_child().get_first_child();
+  let number = to_address(size, evaluate_constant(size, context)?)? as usize;
+  debug_assert_eq!(number, count);
+  for _ in 0..count {
+    context.add_value_to_current_segment(0)?;
+  }
+  Ok(())
+}
+
+fn populate_align(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let offset = context.get_current_segment()?.get_value_len();
+  let padding = get_align_padding(node, offset, context)?;
+  let fill = match node.get_first_child().get_children().get(1) {
+    Some(fill) => to_byte(fill, evaluate_constant(fill, context)?)?,
+    None => 0,
+  };
+  for _ in 0..padding {
+    context.add_value_to_current_segment(fill)?;
+  }
+  Ok(())
+}
+
+fn populate_incbin(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  context.next_size();
+  let args = node.get_first_child().get_children();
+  let path = get_incbin_path(node, args)?;
+  let bytes = read(&path).map_err(|e| file_error(&args[0], &path, e))?;
+  let range = get_incbin_range(args, &path, bytes.len(), |arg| {
+    evaluate_constant(arg, context).map(Some)
+  })?;
+  for byte in bytes[range.unwrap()].iter() {
+    context.add_value_to_current_segment(*byte)?;
+  }
+  Ok(())
+}
+
+fn populate_values(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let width = get_data_width(node);
+  for arg in node.get_first_child().get_children() {
+    if let Some(kind) = get_value_kind(node) {
+      let operand = get_operand(arg, context)?;
+      if operand.base.is_some() {
+        add_operand(arg, operand, kind, context)?;
+        continue;
+      }
+    }
+    let value = evaluate_constant(arg, context)?;
+    let bytes = match node.get_type() {
+      NodeType::DirectiveDbyt => to_word(arg, value)?.to_be_bytes().to_vec(),
+      NodeType::DirectiveWord | NodeType::DirectiveAddr => {
+        to_word(arg, value)?.to_le_bytes().to_vec()
       }
+      NodeType::DirectiveFaraddr => to_far_address(arg, value)?.to_le_bytes().to_vec(),
+      NodeType::DirectiveDword => value.to_le_bytes().to_vec(),
+      NodeType::DirectiveHibytes => vec![(value >> 8) as u8],
+      NodeType::DirectiveBankbytes => vec![(value >> 16) as u8],
+      _ => vec![value as u8],
+    };
+    // the sizing pass went by the width, so that is exactly what goes out
+    for byte in bytes.into_iter().take(width) {
+      context.add_value_to_current_segment(byte)?;
     }
   }
+  Ok(())
 }
 
-fn populate_words(node: &Node<String>, context: &mut Context) {
-  for child in node.get_children() {
-    for arg in child.get_children() {
-      match arg.get_type() {
-        NodeType::Variable => {
-          let name = arg.get_first_data_result();
-          let var_opt = context.get_var(name);
-          let value = match var_opt {
-            Some(num) => *num,
-            None => context.get_label_address(name),
-          };
-          let bytes = value.to_le_bytes();
-          context.add_value_to_current_segment(bytes[0]);
-          context.add_value_to_current_segment(bytes[1]);
-        }
-        _ => panic!("Not yet implemented: {:?}", arg.get_type()),
+// How a value that hangs off a segment or import goes in, for the data
+// directives that can take one
+fn get_value_kind(node: &Node<String>) -> Option<RelocKind> {
+  match node.get_type() {
+    NodeType::DirectiveWord | NodeType::DirectiveAddr => Some(RelocKind::Word),
+    NodeType::DirectiveFaraddr => Some(RelocKind::Far),
+    NodeType::DirectiveDbyt => Some(RelocKind::Dbyt),
+    NodeType::DirectiveDword => Some(RelocKind::Dword),
+    NodeType::DirectiveLobytes => Some(RelocKind::Lo),
+    NodeType::DirectiveHibytes => Some(RelocKind::Hi),
+    NodeType::DirectiveBankbytes => Some(RelocKind::Bank),
+    _ => None,
+  }
+}
+
+fn populate_asciiz(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  for arg in node.get_first_child().get_children() {
+    get_asciiz_string(arg)?;
+    for byte in encode_string(arg, context)? {
+      context.add_value_to_current_segment(byte)?;
+    }
+  }
+  context.add_value_to_current_segment(0)
+}
+
+// <charmap> ::= ".charmap" <expression> "," <expression>
+fn populate_charmap(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let args = node.get_first_child().get_children();
+  if args.len() != 2 {
+    let message = format!(".charmap takes 2 arguments, found {}", args.len());
+    return Err(AsmError::at_node(node, message));
+  }
+  let from = get_charmap_char(&args[0], context)?;
+  let to = to_byte(&args[1], evaluate_constant(&args[1], context)?)?;
+  context.map_char(from, to);
+  Ok(())
+}
+
+// The character being mapped can be given as a char constant or its code
+fn get_charmap_char(arg: &Node<String>, context: &Context) -> AsmResult<char> {
+  if arg.get_type() == &NodeType::String {
+    let mut chars = arg.get_first_data_result().chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+      return Ok(c);
+    }
+  }
+  let code = evaluate_constant(arg, context)?;
+  match std::char::from_u32(code as u32) {
+    Some(c) if code >= 0 => Ok(c),
+    _ => {
+      let message = format!("{} is not a character code", code);
+      Err(AsmError::at_node(arg, message))
+    }
+  }
+}
+
+// Runs a string through the current character map
+fn encode_string(arg: &Node<String>, context: &Context) -> AsmResult<Vec<u8>> {
+  let data = arg.get_first_data_result();
+  let mut bytes = Vec::with_capacity(data.len());
+  for c in data.chars() {
+    match context.translate_char(c) {
+      Some(byte) => bytes.push(byte),
+      None => {
+        let message = format!("Character '{}' is not in the character map", c);
+        return Err(AsmError::at_node(arg, message));
       }
     }
   }
+  Ok(bytes)
 }
 
-fn populate_opcode_data(node: &Node<String>, context: &mut Context) {
+fn populate_opcode_data(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   for child in node.get_children() {
     match child.get_type() {
-      NodeType::AccumulatorMode => populate_accumulator_mode(child, context),
-      NodeType::ImmediateMode => populate_immediate_mode(child, context),
+      NodeType::ImpliedMode => populate_implied_mode(child, context, AddressingMode::Implied)?,
+      NodeType::AccumulatorMode => {
+        populate_implied_mode(child, context, AddressingMode::Accumulator)?
+      }
+      NodeType::ImmediateMode => populate_immediate_mode(child, context)?,
       NodeType::DirectMode | NodeType::DirectRegXMode | NodeType::DirectRegYMode => {
-        populate_direct_mode(child, context)
+        populate_direct_mode(child, context)?
       }
-      NodeType::RelativeMode => populate_relative_mode(child, context),
-      _ => panic!("Not yet implemented: {:?}", child.get_type()),
+      NodeType::RelativeMode => populate_relative_mode(child, context)?,
+      NodeType::IndirectMode | NodeType::IndirectXMode | NodeType::IndirectYMode => {
+        populate_indirect_mode(child, context)?
+      }
+      _ => return Err(invalid_node("addressing mode", child)),
     }
   }
+  Ok(())
 }
 
-fn populate_accumulator_mode(node: &Node<String>, context: &mut Context) {
+// implied and accumulator instructions are just the opcode byte
+fn populate_implied_mode(
+  node: &Node<String>,
+  context: &mut Context,
+  mode: AddressingMode,
+) -> AsmResult<()> {
   let opcode = node.get_first_data_result();
-  let num = get_accumulator(opcode);
-  context.add_value_to_current_segment(num);
+  let num = encode(opcode, mode).ok_or_else(|| invalid_mode(node, "an implied"))?;
+  context.add_value_to_current_segment(num)
 }
 
-fn populate_immediate_mode(node: &Node<String>, context: &mut Context) {
+fn populate_immediate_mode(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   let opcode = node.get_first_data_result();
-  let num = get_immediate(opcode);
-  context.add_value_to_current_segment(num);
+  let num =
+    encode(opcode, AddressingMode::Immediate).ok_or_else(|| invalid_mode(node, "an immediate"))?;
+  context.add_value_to_current_segment(num)?;
   let operand_node = node.get_first_child();
-  let operand_data = match operand_node.get_type() {
-    NodeType::Number => to_u8_checked(get_real_number_value(operand_node)),
-    NodeType::Variable => {
-      let data = operand_node.get_first_data_result();
-      to_u8_checked(match context.get_var(data) {
-        Some(val) => *val,
-        None => context.get_label_address(data),
-      })
-    }
-    // a unary op in this case is almost certainly hibyte or lobyte
-    NodeType::UnaryOp => {
-      let op = operand_node.get_first_data_result();
-      let child = operand_node.get_first_child();
-      let data = match child.get_type() {
-        NodeType::Variable => context.get_label_address(child.get_first_data_result()),
-        _ => panic!("Unknown child type: {:?}", child.get_type()),
-      };
-      let bytes = data.to_le_bytes();
-      match op.as_str() {
-        "<" => bytes[0],
-        ">" => bytes[1],
-        _ => panic!("Unknown operator for immediate unary: {:?}", op),
+  let (kind, operand) = get_byte_operand(operand_node, context)?;
+  add_operand(operand_node, operand, kind, context)
+}
+
+// A value the linker may still have to add the address of a segment or an
+// import to
+struct Operand {
+  base: Option<RelocTarget>,
+  value: i32,
+}
+
+fn get_operand(node: &Node<String>, context: &Context) -> AsmResult<Operand> {
+  if node.get_type() == &NodeType::LabelJump {
+    let (is_pos, count) = parse_label_jump(node);
+    return context.get_unnamed_label_operand(is_pos, count);
+  }
+  let base = find_base(node, &|name| context.get_base(name))?;
+  let value = evaluate(node, context)?;
+  Ok(Operand { base, value })
+}
+
+// Like get_operand, but None while a symbol has no value yet
+fn try_get_operand(node: &Node<String>, context: &Context) -> AsmResult<Option<Operand>> {
+  if node.get_type() == &NodeType::LabelJump {
+    return get_operand(node, context).map(Some);
+  }
+  let base = find_base(node, &|name| context.get_base(name))?;
+  let value = try_evaluate(node, context)?;
+  Ok(value.map(|value| Operand { base, value }))
+}
+
+// `<`, `>` and `^` on something that hangs off a segment or import leave
+// picking the byte out to the linker
+fn get_byte_operand(node: &Node<String>, context: &Context) -> AsmResult<(RelocKind, Operand)> {
+  if node.get_type() == &NodeType::UnaryOp {
+    let kind = match get_operator(node).as_str() {
+      "<" | "lobyte" => Some(RelocKind::Lo),
+      ">" | "hibyte" => Some(RelocKind::Hi),
+      "^" | "bankbyte" => Some(RelocKind::Bank),
+      _ => None,
+    };
+    if let Some(kind) = kind {
+      let operand = get_operand(node.get_first_child(), context)?;
+      if operand.base.is_some() {
+        return Ok((kind, operand));
       }
     }
-    NodeType::BinaryOp => {
-      let num = evaluate_binary_expression(operand_node, context);
-      if num > 0xFF {
-        panic!("Immediate mode binary op is more than one byte");
-      }
-      num as u8
+  }
+  Ok((RelocKind::Byte, get_operand(node, context)?))
+}
+
+// Anything that isn't an operand has to be known without linking
+fn evaluate_constant(node: &Node<String>, context: &Context) -> AsmResult<i32> {
+  match get_operand(node, context)? {
+    Operand {
+      base: Some(base), ..
+    } => Err(not_constant(node, &base)),
+    operand => Ok(operand.value),
+  }
+}
+
+fn try_evaluate_constant(node: &Node<String>, context: &Context) -> AsmResult<Option<i32>> {
+  match try_get_operand(node, context)? {
+    Some(Operand {
+      base: Some(base), ..
+    }) => Err(not_constant(node, &base)),
+    operand => Ok(operand.map(|operand| operand.value)),
+  }
+}
+
+fn not_constant(node: &Node<String>, base: &RelocTarget) -> AsmError {
+  let message = format!(
+    "{} is only known once linked, so it can't be used here",
+    base
+  );
+  AsmError::at_node(node, message)
+}
+
+// The operand's bytes, or room for the linker to put them
+fn add_operand(
+  node: &Node<String>,
+  operand: Operand,
+  kind: RelocKind,
+  context: &mut Context,
+) -> AsmResult<()> {
+  let value = operand.value;
+  let bytes = match (operand.base, kind) {
+    (Some(base), _) => {
+      let location = node.get_location().copied();
+      return context.add_relocation_to_current_segment(kind, base, value, location);
     }
-    _ => panic!("Invalid node type {:?}", operand_node.get_type()),
+    (None, RelocKind::Word) => to_address(node, value)?.to_le_bytes().to_vec(),
+    (None, RelocKind::Far) => to_far_address(node, value)?.to_le_bytes()[..3].to_vec(),
+    (None, RelocKind::Dbyt) => to_word(node, value)?.to_be_bytes().to_vec(),
+    (None, RelocKind::Dword) => value.to_le_bytes().to_vec(),
+    (None, RelocKind::Lo) => vec![value as u8],
+    (None, RelocKind::Hi) => vec![(value >> 8) as u8],
+    (None, RelocKind::Bank) => vec![(value >> 16) as u8],
+    (None, _) => vec![to_byte(node, value)?],
   };
-  context.add_value_to_current_segment(operand_data);
+  for byte in bytes {
+    context.add_value_to_current_segment(byte)?;
+  }
+  Ok(())
 }
 
-fn populate_direct_mode(node: &Node<String>, context: &mut Context) {
+// the size was settled while sizing, so stick to it even if a smaller mode would do now
+fn populate_direct_mode(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   let opcode = node.get_first_data_result();
+  let size = context.next_size();
   let op_node = node.get_first_child();
-  let num = match op_node.get_type() {
-    NodeType::Number => get_real_number_value(op_node),
-    NodeType::Variable => {
-      let name = op_node.get_first_data_result();
-      let var_opt = context.get_var(name);
-      match var_opt {
-        Some(num) => *num,
-        None => context.get_label_address(name),
-      }
-    }
-    NodeType::BinaryOp => evaluate_binary_expression(op_node, context),
-    _ => panic!("Invalid node child {:?}", op_node.get_type()),
-  };
-  let bytes = num.to_le_bytes();
-  match num > 0xFF {
+  let operand = get_operand(op_node, context)?;
+  let (zero_page, absolute) = get_direct_modes(node);
+  match size == absolute.get_length() as usize {
     true => {
-      let opcode_byte = match node.get_type() {
-        NodeType::DirectRegXMode => get_absolute_x(opcode),
-        NodeType::DirectRegYMode => get_absolute_y(opcode),
-        NodeType::DirectMode => get_absolute(opcode),
-        _ => panic!("Invalid node type {:?}", node.get_type()),
-      };
-      context.add_value_to_current_segment(opcode_byte);
-      context.add_value_to_current_segment(bytes[0]);
-      context.add_value_to_current_segment(bytes[1]);
+      let opcode_byte =
+        encode(opcode, absolute).ok_or_else(|| invalid_mode(node, "an absolute"))?;
+      context.add_value_to_current_segment(opcode_byte)?;
+      add_operand(op_node, operand, RelocKind::Word, context)
     }
     false => {
-      let opcode_byte = match node.get_type() {
-        NodeType::DirectRegXMode => get_zero_page_x(opcode),
-        NodeType::DirectRegYMode => get_zero_page_y(opcode),
-        NodeType::DirectMode => get_zero_page(opcode),
-        _ => panic!("Invalid node type {:?}", node.get_type()),
+      let opcode_byte = match operand.base.is_none() && to_address(op_node, operand.value)? > 0xFF {
+        true => None,
+        false => encode(opcode, zero_page),
       };
-      context.add_value_to_current_segment(opcode_byte);
-      context.add_value_to_current_segment(bytes[0]);
+      let opcode_byte = opcode_byte.ok_or_else(|| invalid_mode(node, "an absolute"))?;
+      context.add_value_to_current_segment(opcode_byte)?;
+      add_operand(op_node, operand, RelocKind::Byte, context)
     }
   }
 }
 
-fn populate_relative_mode(node: &Node<String>, context: &mut Context) {
+fn populate_indirect_mode(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
   let opcode = node.get_first_data_result();
   let op_node = node.get_first_child();
-  match op_node.get_type() {
-    NodeType::LabelJump => {
-      let data = op_node.get_first_data_result();
-      let is_pos = data.chars().any(|c| c == '+');
-      let f = |op| data.chars().filter(|c| c == &op).count();
-      let count = match is_pos {
-        true => f('+'),
-        false => f('-'),
-      };
-      let opcode_byte = get_relative(opcode);
-      context.add_value_to_current_segment(opcode_byte);
-      let address = context.get_address_for_label_jump(is_pos, count);
-      context.add_value_to_current_segment(address);
-    }
-    NodeType::Variable => {
-      let name = op_node.get_first_data_result();
-      let var_opt = context.get_var(name);
-      let target_address = match var_opt {
-        Some(num) => *num,
-        None => context.get_label(name).unwrap().get_offset(),
-      };
-      let cur_address = context.get_current_segment().get_value_len();
-      let ordering = target_address.cmp(&cur_address);
-      let address = match ordering {
-        Ordering::Less => !(cur_address - target_address) + 1,
-        Ordering::Greater => target_address - cur_address,
-        _ => cur_address,
+  let operand = get_operand(op_node, context)?;
+  let opcode_byte = match node.get_type() {
+    NodeType::IndirectXMode => {
+      encode(opcode, AddressingMode::IndirectX).ok_or_else(|| invalid_mode(node, "an (indirect,x)"))
+    }
+    NodeType::IndirectYMode => {
+      encode(opcode, AddressingMode::IndirectY).ok_or_else(|| invalid_mode(node, "an (indirect),y"))
+    }
+    _ => encode(opcode, AddressingMode::Indirect).ok_or_else(|| invalid_mode(node, "an indirect")),
+  }?;
+  context.add_value_to_current_segment(opcode_byte)?;
+  match node.get_type() {
+    NodeType::IndirectMode => add_operand(op_node, operand, RelocKind::Word, context),
+    // the pointer for (zp,x) and (zp),y has to live in zero page
+    _ => {
+      let pointer = match &operand.base {
+        Some(base) if !context.is_zero_page(base) => Some(describe_base(base)),
+        Some(_) => None,
+        None if to_address(op_node, operand.value)? > 0xFF => {
+          Some(format!("${:04X}", operand.value))
+        }
+        None => None,
       };
-      let opcode_byte = get_relative(opcode);
-      context.add_value_to_current_segment(opcode_byte);
-      context.add_value_to_current_segment(address as u8);
+      match pointer {
+        Some(pointer) => {
+          let message = format!("Indirect pointer {} is not in zero page", pointer);
+          Err(AsmError::at_node(op_node, message))
+        }
+        None => add_operand(op_node, operand, RelocKind::Byte, context),
+      }
     }
-    _ => panic!("Unimplemented node type {:?}", op_node.get_type()),
   }
 }
 
-fn evaluate_binary_expression(node: &Node<String>, context: &mut Context) -> u16 {
-  let left = node.get_first_child();
-  let right = node.get_children().get(1).unwrap();
-  let operator = node.get_first_data_result();
-  let left_num = evaluate_term(left, context);
-  let right_num = evaluate_term(right, context);
-  match operator.as_str() {
-    "+" => left_num + right_num,
-    "-" => left_num - right_num,
-    "/" => left_num / right_num,
-    "*" => left_num * right_num,
-    "|" => left_num | right_num,
-    "&" => left_num & right_num,
-    "<<" => left_num << right_num,
-    ">>" => left_num >> right_num,
-    _ => panic!("unsupported operator {}", operator),
+fn describe_base(base: &RelocTarget) -> String {
+  match base {
+    RelocTarget::Segment(name) => format!("in segment {}", name),
+    RelocTarget::Import(name) => format!("'{}'", name),
   }
 }
 
-fn evaluate_term(node: &Node<String>, context: &mut Context) -> u16 {
-  let name = node.get_first_data_result();
-  match node.get_type() {
-    NodeType::Number => u16::from_str_radix(name, 10).unwrap(),
-    NodeType::BinaryOp => evaluate_binary_op_for_size(node, context),
-    NodeType::Variable => {
-      let var_opt = context.get_var(name);
-      match var_opt {
-        Some(num) => *num,
-        None => context.get_label_address(name),
+fn populate_relative_mode(node: &Node<String>, context: &mut Context) -> AsmResult<()> {
+  let opcode = node.get_first_data_result();
+  let size = context.next_size();
+  let op_node = node.get_first_child();
+  let offset = context.get_current_segment()?.get_value_len();
+  let address = context.get_address_at(offset)?;
+  let base = context.get_current_base()?;
+  let target = get_operand(op_node, context)?;
+  match size {
+    LONG_BRANCH_SIZE => {
+      let inverted = invert_branch(opcode).ok_or_else(|| invalid_mode(node, "a relative"))?;
+      let branch_byte = encode(inverted, AddressingMode::Relative).unwrap();
+      let jump_byte = encode("jmp", AddressingMode::Absolute).unwrap();
+      context.add_value_to_current_segment(branch_byte)?;
+      context.add_value_to_current_segment(AddressingMode::Absolute.get_length())?;
+      context.add_value_to_current_segment(jump_byte)?;
+      add_operand(op_node, target, RelocKind::Word, context)
+    }
+    _ => {
+      let opcode_byte =
+        encode(opcode, AddressingMode::Relative).ok_or_else(|| invalid_mode(node, "a relative"))?;
+      context.add_value_to_current_segment(opcode_byte)?;
+      // a target placed along with the branch is a fixed distance away,
+      // anything else the linker works out
+      match (target.base, base) {
+        (target_base, base) if target_base == base => {
+          let offset_byte = get_branch_offset(node, target.value, address)?;
+          context.add_value_to_current_segment(offset_byte)
+        }
+        (Some(target_base), Some(_)) => context.add_relocation_to_current_segment(
+          RelocKind::Relative,
+          target_base,
+          target.value,
+          op_node.get_location().copied(),
+        ),
+        (Some(target_base), None) => {
+          let message = format!(
+            "{} is only known once linked, code under .org can't branch to it",
+            target_base
+          );
+          Err(AsmError::at_node(op_node, message))
+        }
+        (None, _) => {
+          let message = format!(
+            "Can't branch to ${:04X} from code the linker places, use jmp instead",
+            target.value
+          );
+          Err(AsmError::at_node(op_node, message))
+        }
       }
     }
-    _ => panic!("unknown term: {:?}", node.get_type()),
   }
 }
 
-fn get_real_number_value(node: &Node<String>) -> u16 {
-  let num = node.get_first_data_result();
-  u16::from_str_radix(num, 10).unwrap()
+fn invalid_node(expected: &str, node: &Node<String>) -> AsmError {
+  let message = format!("Invalid {} {:?}", expected, node.get_type());
+  AsmError::at_node(node, message)
 }
 
-fn to_u8_checked(num: u16) -> u8 {
-  match num > 0xFF {
-    false => num as u8,
-    true => panic!("Number '{}' is greater than a single byte", num),
-  }
+fn invalid_mode(node: &Node<String>, mode: &str) -> AsmError {
+  let message = format!(
+    "{} does not have {} address mode",
+    node.get_first_data_result(),
+    mode
+  );
+  AsmError::at_node(node, message)
+}
+
+fn unsupported_directive(node: &Node<String>) -> AsmError {
+  let message = format!("Directive not supported {:?}", node.get_type());
+  AsmError::at_node(node, message)
 }
 
-struct Context {
-  config: Configuration,
-  var_map: HashMap<String, u16>,
+fn file_error(node: &Node<String>, path: &Path, e: std::io::Error) -> AsmError {
+  let message = format!("Unable to read {}: {}", path.display(), e);
+  AsmError::at_node(node, message)
+}
+
+struct Context<'a> {
+  config: &'a Configuration,
+  options: &'a Options,
+  var_map: HashMap<String, i32>,
+  // symbols set from a label or an import, which the linker has to add to
+  var_bases: HashMap<String, RelocTarget>,
   label_map: HashMap<String, Label>,
   segment_list: Vec<Segment>,
   seg_counter: u8,
-  current_seg_id: u8,
+  current_seg_id: Option<u8>,
+  segment_stack: Vec<Option<u8>>,
   unnamed_label_counter: u16,
+  declared_vars: HashSet<String>,
+  size_map: HashMap<String, i32>,
+  charmap: HashMap<char, u8>,
+  imports: HashMap<String, AddressMode>,
+  exports: Vec<Export>,
+  scopes: Vec<OpenScope>,
+  anonymous_scope_counter: usize,
+  cheap_local_owner: Option<String>,
+  sizes: Vec<usize>,
+  size_cursor: usize,
+  changed: bool,
 }
 
-impl Context {
-  fn new(tree: &Node<String>, config: Configuration) -> Context {
+impl<'a> Context<'a> {
+  fn new(tree: &Node<String>, config: &'a Configuration, options: &'a Options) -> Context<'a> {
     let label_count = get_count(&NodeType::LabelStatement, tree.get_children());
     let assign_count = get_count(&NodeType::AssignmentStatement, tree.get_children());
     Context {
       config,
+      options,
       var_map: HashMap::with_capacity(assign_count),
+      var_bases: HashMap::new(),
       label_map: HashMap::with_capacity(label_count),
       segment_list: vec![],
       seg_counter: 0,
-      current_seg_id: 0,
+      current_seg_id: None,
+      segment_stack: vec![],
       unnamed_label_counter: 0,
+      declared_vars: HashSet::with_capacity(assign_count),
+      size_map: HashMap::new(),
+      charmap: HashMap::new(),
+      imports: HashMap::new(),
+      exports: vec![],
+      scopes: vec![],
+      anonymous_scope_counter: 0,
+      cheap_local_owner: None,
+      sizes: vec![],
+      size_cursor: 0,
+      changed: false,
+    }
+  }
+
+  fn into_object(self, name: &str) -> ObjectFile {
+    let mut object = ObjectFile::new(name);
+    let mut imports: Vec<&String> = self.imports.keys().collect();
+    imports.sort();
+    for import in imports {
+      object.add_import(import);
+    }
+    for export in self.exports {
+      object.add_export(export);
     }
+    for segment in self.segment_list {
+      let mut part = ObjectSegment::new(&segment.name);
+      part.align_to(segment.align);
+      for byte in segment.values {
+        part.add_value(byte);
+      }
+      for reloc in segment.relocations {
+        part.add_relocation(reloc);
+      }
+      object.add_segment(part);
+    }
+    object
+  }
+
+  fn add_import(&mut self, key: String, mode: AddressMode) {
+    self.imports.insert(key, mode);
+  }
+
+  fn is_import(&self, key: &str) -> bool {
+    self.imports.contains_key(key)
   }
 
-  fn get_segment_list(&self) -> &Vec<Segment> {
-    &self.segment_list
+  // What the linker has to add to a symbol's value, if anything
+  fn get_base(&self, name: &str) -> Option<RelocTarget> {
+    let key = self.resolve_symbol(name)?;
+    if self.is_import(&key) {
+      return Some(RelocTarget::Import(key));
+    }
+    if let Some(base) = self.var_bases.get(&key) {
+      return Some(base.to_owned());
+    }
+    let label = self
+      .label_map
+      .get(&key)
+      .filter(|label| label.is_relocatable())?;
+    let segment = self.get_segment_by_id(label.get_segment())?;
+    Some(RelocTarget::Segment(segment.get_name().to_owned()))
+  }
+
+  fn is_zero_page(&self, base: &RelocTarget) -> bool {
+    let mode = match base {
+      RelocTarget::Import(key) => self.imports.get(key),
+      RelocTarget::Segment(name) => self
+        .segment_list
+        .iter()
+        .find(|segment| &segment.name == name)
+        .map(Segment::get_mode),
+    };
+    mode == Some(&AddressMode::ZeroPage)
   }
 
-  fn get_config(&self) -> &Configuration {
-    &self.config
+  fn add_export(&mut self, export: Export) {
+    self.exports.push(export);
   }
 
-  fn add_var_to_map(&mut self, k: &String, v: u16) {
+  fn add_var_to_map(&mut self, k: &String, v: i32) {
     self.var_map.insert(k.to_owned(), v);
   }
 
-  fn get_var(&self, k: &String) -> Option<&u16> {
-    self.var_map.get(k)
+  fn declare_var(&mut self, k: &str) {
+    self.declared_vars.insert(self.qualify(k));
   }
 
-  fn get_label(&mut self, k: &String) -> Option<&mut Label> {
-    self.label_map.get_mut(k)
+  fn is_declared_var(&self, k: &String) -> bool {
+    self.declared_vars.contains(k)
   }
 
-  fn add_size_from_variable(&mut self, k: &String) {
-    let var_opt = self.get_var(k);
-    match var_opt {
-      Some(num) => {
-        let count = match num > &0xFF {
-          true => 3,
-          false => 2,
-        };
-        self.get_current_segment().add_size(count);
-      }
-      None => {
-        let label = self.get_label(k).unwrap();
-        let seg_id = label.get_segment();
-        let segment = self.get_segment_by_id(seg_id);
-        match segment {
-          Some(seg) => {
-            let count = match seg.get_mode() {
-              AddressMode::Absolute => 3,
-              _ => 2,
-            };
-            self.add_size_to_current_segment(count);
-          }
-          None => panic!("Label has invalid segment id {}", seg_id),
-        }
-      }
+  fn update_var(&mut self, k: &str, v: i32) {
+    if self.var_map.insert(self.qualify(k), v) != Some(v) {
+      self.changed = true;
+    }
+  }
+
+  fn update_var_base(&mut self, k: &str, base: Option<RelocTarget>) {
+    let key = self.qualify(k);
+    match base {
+      Some(base) => self.var_bases.insert(key, base),
+      None => self.var_bases.remove(&key),
+    };
+  }
+
+  fn update_size(&mut self, k: &str, v: i32) {
+    if self.size_map.insert(self.qualify(k), v) != Some(v) {
+      self.changed = true;
     }
   }
 
-  fn add_label_to_map(&mut self, k: &String) {
-    let label = Label::new(self.current_seg_id);
+  fn map_char(&mut self, from: char, to: u8) {
+    self.charmap.insert(from, to);
+  }
+
+  // Until the first .charmap characters stand for themselves, as long as they
+  // fit in a byte. After that only the characters mapped can be used
+  fn translate_char(&self, c: char) -> Option<u8> {
+    match self.charmap.get(&c) {
+      Some(byte) => Some(*byte),
+      None if self.charmap.is_empty() && (c as u32) <= 0xFF => Some(c as u8),
+      None => None,
+    }
+  }
+
+  fn get_var(&self, k: &String) -> Option<&i32> {
+    self.var_map.get(k)
+  }
+
+  fn add_label_to_map(&mut self, k: &str) -> AsmResult<()> {
+    self.insert_label(&self.qualify(k))
+  }
+
+  fn insert_label(&mut self, k: &String) -> AsmResult<()> {
+    let segment = self.get_current_segment_id()?;
+    if self.label_map.contains_key(k) {
+      let message = format!("Label '{}' is already defined", k);
+      return Err(AsmError::new(message, None));
+    }
+    let label = Label::new(segment);
     self.label_map.insert(k.to_owned(), label);
+    Ok(())
   }
 
-  fn get_formatted_name(&mut self, count: u16) -> String {
+  fn get_formatted_name(&self, count: u16) -> String {
     format!("label-{}", count)
   }
 
@@ -662,16 +1413,28 @@ impl Context {
     name
   }
 
-  fn add_unnamed_label_to_map(&mut self) {
+  fn add_unnamed_label_to_map(&mut self) -> AsmResult<()> {
+    // unnamed labels don't belong to any scope
     let name = self.get_unnamed_label_now();
-    self.add_label_to_map(&name);
+    self.insert_label(&name)
   }
 
-  fn switch_segment(&mut self, name: &String) {
+  // Segments are made the first time they're used, an address size given then
+  // overrides the one from the configuration
+  fn switch_segment(&mut self, node: &Node<String>) -> AsmResult<()> {
+    let name = node.get_first_data_result();
+    let address_mode = node
+      .get_data()
+      .get(1)
+      .map(|size| AddressMode::from_size(size));
     let found = self.segment_list.iter().find(|s| &s.name == name);
     match found {
       Some(seg) => {
-        self.current_seg_id = seg.id;
+        if address_mode.is_some() && address_mode.as_ref() != Some(seg.get_mode()) {
+          let message = format!("Segment '{}' already has a different address size", name);
+          return Err(AsmError::at_node(node, message));
+        }
+        self.current_seg_id = Some(seg.id);
       }
       None => {
         let id = self.seg_counter;
@@ -680,106 +1443,429 @@ impl Context {
         match config_entry {
           Some(seg_entry) => {
             let seg_type = seg_entry.get_type();
-            let segment = Segment::new(id, name, AddressMode::from_seg_type(seg_type));
-            self.current_seg_id = id;
+            let mode = address_mode.unwrap_or_else(|| AddressMode::from_seg_type(seg_type));
+            let segment = Segment::new(id, name, mode);
+            self.current_seg_id = Some(id);
             self.segment_list.push(segment);
           }
-          None => panic!("Segment undefined in configuration file: {}", name),
+          None => {
+            let message = format!("Segment undefined in configuration file: {}", name);
+            return Err(AsmError::new(message, None));
+          }
         }
       }
     }
+    Ok(())
   }
 
-  fn is_label_in_zero_page(&self, label_name: &String) -> bool {
-    let label = self.label_map.get(label_name).unwrap();
-    let segment = self.get_segment_by_id(label.get_segment()).unwrap();
-    segment.get_mode() == &AddressMode::ZeroPage
+  fn push_segment(&mut self) -> AsmResult<()> {
+    self.segment_stack.push(self.current_seg_id);
+    Ok(())
   }
 
-  fn get_current_segment(&mut self) -> &mut Segment {
-    let index = self
-      .segment_list
-      .iter()
-      .position(|s| s.id == self.current_seg_id)
-      .unwrap();
-    self.segment_list.get_mut(index).unwrap()
+  fn pop_segment(&mut self, node: &Node<String>) -> AsmResult<()> {
+    match self.segment_stack.pop() {
+      Some(id) => {
+        self.current_seg_id = id;
+        Ok(())
+      }
+      None => {
+        let message = String::from("Missing .pushseg before this");
+        Err(AsmError::at_node(node, message))
+      }
+    }
   }
 
-  fn get_current_segment_size(&mut self) -> u16 {
-    self.get_current_segment().get_size()
+  fn get_current_segment_id(&self) -> AsmResult<u8> {
+    match self.current_seg_id {
+      Some(id) => Ok(id),
+      None => {
+        let message = String::from("No segment selected, use .segment first");
+        Err(AsmError::new(message, None))
+      }
+    }
+  }
+
+  fn get_current_segment(&mut self) -> AsmResult<&mut Segment> {
+    let id = self.get_current_segment_id()?;
+    let segment = self.segment_list.iter_mut().find(|s| s.id == id).unwrap();
+    Ok(segment)
   }
 
-  fn add_value_to_current_segment(&mut self, byte: u8) {
-    let seg = self.get_current_segment();
+  fn get_current_segment_size(&mut self) -> AsmResult<u32> {
+    Ok(self.get_current_segment()?.get_size())
+  }
+
+  fn add_value_to_current_segment(&mut self, byte: u8) -> AsmResult<()> {
+    let seg = self.get_current_segment()?;
     seg.add_value(byte);
+    Ok(())
+  }
+
+  // Zeros stand in for the value until the linker fills it in
+  fn add_relocation_to_current_segment(
+    &mut self,
+    kind: RelocKind,
+    target: RelocTarget,
+    addend: i32,
+    location: Option<Location>,
+  ) -> AsmResult<()> {
+    let seg = self.get_current_segment()?;
+    let reloc = Relocation::new(seg.get_value_len(), kind, target, addend, location);
+    seg.add_relocation(reloc);
+    for _ in 0..kind.get_width() {
+      seg.add_value(0);
+    }
+    Ok(())
   }
 
-  fn get_address_for_label_jump(&mut self, is_pos: bool, count: usize) -> u8 {
+  fn get_unnamed_label(&self, is_pos: bool, count: usize) -> AsmResult<&Label> {
     let count = count as u16;
     let num = match is_pos {
-      true => self.unnamed_label_counter + count - 1,
-      false => self.unnamed_label_counter - count,
+      true => self
+        .unnamed_label_counter
+        .checked_add(count)
+        .and_then(|n| n.checked_sub(1)),
+      false => self.unnamed_label_counter.checked_sub(count),
     };
-    let offset = self.get_current_segment().get_value_len();
-    let target_name = self.get_formatted_name(num);
-    let target_label = self.label_map.get(&target_name).unwrap();
-    match is_pos {
-      true => (target_label.get_offset() - offset) as u8,
-      false => {
-        let diff = offset - target_label.get_offset();
-        (!diff + 1) as u8
+    let target_name = num.map(|n| self.get_formatted_name(n));
+    let label_map = &self.label_map;
+    match target_name.and_then(|name| label_map.get(&name)) {
+      Some(label) => Ok(label),
+      None => {
+        let message = String::from("Unnamed label reference has no matching label");
+        Err(AsmError::new(message, None))
       }
     }
   }
 
-  fn add_size_to_label(&mut self, label_name: &String) {
-    let offset = self.get_current_segment_size();
-    let label = self.label_map.get_mut(label_name).unwrap();
-    label.add_offset(offset);
+  fn get_unnamed_label_operand(&self, is_pos: bool, count: usize) -> AsmResult<Operand> {
+    let label = self.get_unnamed_label(is_pos, count)?.to_owned();
+    let base = match label.is_relocatable() {
+      true => self
+        .get_segment_by_id(label.get_segment())
+        .map(|segment| RelocTarget::Segment(segment.get_name().to_owned())),
+      false => None,
+    };
+    Ok(Operand {
+      base,
+      value: label.get_address() as i32,
+    })
+  }
+
+  // Address of an offset into the current segment, counted from the last .org
+  // if there is one. Without one it stays an offset for the linker to add the
+  // segment's address to
+  fn get_address_at(&mut self, offset: u32) -> AsmResult<u32> {
+    let segment = self.get_current_segment()?;
+    match segment.get_origin() {
+      Some(origin) => Ok(origin.address + (offset - origin.offset)),
+      None => Ok(offset),
+    }
+  }
+
+  fn get_current_base(&mut self) -> AsmResult<Option<RelocTarget>> {
+    let segment = self.get_current_segment()?;
+    match segment.get_origin() {
+      Some(_) => Ok(None),
+      None => Ok(Some(RelocTarget::Segment(segment.get_name().to_owned()))),
+    }
+  }
+
+  fn add_size_to_label(&mut self, label_name: &str) -> AsmResult<()> {
+    let offset = self.get_current_segment_size()?;
+    self.move_label(&self.qualify(label_name), offset)
   }
 
-  fn add_size_to_unnamed_label(&mut self) {
-    let offset = self.get_current_segment_size();
+  fn add_size_to_unnamed_label(&mut self) -> AsmResult<()> {
+    let offset = self.get_current_segment_size()?;
     let name = self.get_unnamed_label_now();
-    let label = self.label_map.get_mut(&name).unwrap();
-    label.add_offset(offset);
+    self.move_label(&name, offset)
+  }
+
+  fn move_label(&mut self, label_name: &String, offset: u32) -> AsmResult<()> {
+    let address = self.get_address_at(offset)?;
+    let relocatable = self.get_current_segment()?.get_origin().is_none();
+    let label = self.label_map.get_mut(label_name).unwrap();
+    if label.get_offset() != offset
+      || label.get_address() != address
+      || label.is_relocatable() != relocatable
+    {
+      label.add_offset(offset);
+      label.set_address(address);
+      label.set_relocatable(relocatable);
+      self.changed = true;
+    }
+    Ok(())
   }
 
-  fn add_size_to_current_segment(&mut self, byte: usize) {
-    let seg = self.get_current_segment();
-    seg.add_size(byte as u16);
+  fn add_size_to_current_segment(&mut self, byte: usize) -> AsmResult<()> {
+    let seg = self.get_current_segment()?;
+    seg.add_size(byte as u32);
+    Ok(())
   }
 
   fn get_segment_by_id(&self, id: u8) -> Option<&Segment> {
     self.segment_list.iter().find(|s| s.id == id)
   }
 
-  fn reset_label_count(&mut self) {
+  fn start_pass(&mut self) {
+    for segment in self.segment_list.iter_mut() {
+      segment.set_origin(None);
+    }
     self.unnamed_label_counter = 0;
+    self.size_cursor = 0;
+    self.changed = false;
   }
 
-  fn add_size_to_label_jump(&mut self, is_pos: bool, count: usize) {
-    let count = count as u16;
-    let num = match is_pos {
-      true => self.unnamed_label_counter + count - 1,
-      false => self.unnamed_label_counter - count,
+  fn reset_segment_sizes(&mut self) {
+    for segment in self.segment_list.iter_mut() {
+      segment.reset_size();
+    }
+  }
+
+  // Every pass starts outside of any segment, scope or cheap local label
+  fn reset_tracking(&mut self) {
+    self.current_seg_id = None;
+    self.segment_stack.clear();
+    self.scopes.clear();
+    self.anonymous_scope_counter = 0;
+    self.cheap_local_owner = None;
+  }
+
+  // Cheap local labels belong to the normal label before them, a .proc
+  // counts as one
+  fn track_cheap_local_owner(&mut self, node: &Node<String>) {
+    let child = match node.get_children().first() {
+      Some(child) => child,
+      None => return,
+    };
+    match (node.get_type(), child.get_type()) {
+      (NodeType::LabelStatement, NodeType::Label)
+      | (NodeType::DirectiveStatement, NodeType::DirectiveProc) => {
+        self.cheap_local_owner = Some(self.qualify(child.get_first_data_result()));
+      }
+      _ => (),
+    }
+  }
+
+  fn has_cheap_local_owner(&self) -> bool {
+    self.cheap_local_owner.is_some()
+  }
+
+  // Follows .scope and .proc blocks, so names are defined and looked up in the
+  // right one
+  fn track_scope(&mut self, node: &Node<String>) -> AsmResult<()> {
+    if node.get_type() != &NodeType::DirectiveStatement {
+      return Ok(());
+    }
+    let directive = node.get_first_child();
+    let is_proc = match directive.get_type() {
+      NodeType::DirectiveScope => false,
+      NodeType::DirectiveProc => true,
+      NodeType::DirectiveEndscope | NodeType::DirectiveEndproc => {
+        let is_proc = directive.get_type() == &NodeType::DirectiveEndproc;
+        return match self.scopes.pop() {
+          Some(scope) if scope.is_proc == is_proc => Ok(()),
+          Some(scope) => Err(AsmError::at_node(directive, scope.get_missing_end())),
+          None => {
+            let message = String::from("Missing .scope or .proc before this");
+            Err(AsmError::at_node(directive, message))
+          }
+        };
+      }
+      _ => return Ok(()),
+    };
+    let name = match directive.get_data().first() {
+      Some(name) => name.to_owned(),
+      None => {
+        // '#' can't appear in a source name, so nothing can refer to these
+        self.anonymous_scope_counter += 1;
+        format!("#scope{}", self.anonymous_scope_counter)
+      }
+    };
+    self.scopes.push(OpenScope {
+      name,
+      is_proc,
+      location: directive.get_location().copied(),
+    });
+    Ok(())
+  }
+
+  fn take_unclosed_scopes(&mut self) -> Vec<AsmError> {
+    self
+      .scopes
+      .drain(..)
+      .map(|scope| AsmError::new(scope.get_missing_end(), scope.location))
+      .collect()
+  }
+
+  // The key a name defined here is stored under
+  fn qualify(&self, name: &str) -> String {
+    match is_cheap_local(name) {
+      true => format!(
+        "{}{}",
+        self.cheap_local_owner.as_deref().unwrap_or(""),
+        name
+      ),
+      false => join_scopes(&self.scopes, name),
+    }
+  }
+
+  // Walks outward from the current scope to the first one that has the name,
+  // a leading :: starts at the global scope
+  fn resolve_symbol(&self, name: &str) -> Option<String> {
+    self.resolve_with(name, |key| self.is_symbol(key))
+  }
+
+  fn resolve_with<F: Fn(&str) -> bool>(&self, name: &str, exists: F) -> Option<String> {
+    if is_cheap_local(name) {
+      return Some(self.qualify(name)).filter(|key| exists(key));
+    }
+    if let Some(global) = name.strip_prefix("::") {
+      return Some(global.to_owned()).filter(|key| exists(key));
+    }
+    (0..=self.scopes.len())
+      .rev()
+      .map(|depth| join_scopes(&self.scopes[..depth], name))
+      .find(|key| exists(key))
+  }
+
+  fn is_symbol(&self, key: &str) -> bool {
+    self.var_map.contains_key(key)
+      || self.declared_vars.contains(key)
+      || self.label_map.contains_key(key)
+      || self.imports.contains_key(key)
+  }
+
+  fn has_changed(&self) -> bool {
+    self.changed
+  }
+
+  // Picks the size for the next variable sized instruction, never smaller than
+  // last pass. Unknown sizes keep last pass' size, or the default on the first
+  fn resolve_size(&mut self, size: Option<usize>, default: usize) -> usize {
+    let previous = self.sizes.get(self.size_cursor).copied();
+    let size = match (previous, size) {
+      (Some(previous), Some(size)) => previous.max(size),
+      (Some(previous), None) => previous,
+      (None, size) => size.unwrap_or(default),
+    };
+    self.store_size(previous, size)
+  }
+
+  // Like resolve_size, but for a count of bytes, which can go down as well
+  // as up. Unknown counts keep last pass' count, or nothing on the first
+  fn resolve_count(&mut self, count: Option<usize>) -> usize {
+    let previous = self.sizes.get(self.size_cursor).copied();
+    let count = count.or(previous).unwrap_or(0);
+    self.store_size(previous, count)
+  }
+
+  fn store_size(&mut self, previous: Option<usize>, size: usize) -> usize {
+    match previous {
+      Some(previous) if previous == size => (),
+      Some(_) => self.sizes[self.size_cursor] = size,
+      None => self.sizes.push(size),
+    }
+    if previous != Some(size) {
+      self.changed = true;
+    }
+    self.size_cursor += 1;
+    size
+  }
+
+  fn next_size(&mut self) -> usize {
+    let size = self.sizes.get(self.size_cursor).copied().unwrap_or(0);
+    self.size_cursor += 1;
+    size
+  }
+
+  fn get_label_address(&self, name: &String) -> Option<u32> {
+    self.label_map.get(name).map(Label::get_address)
+  }
+}
+
+// Labels the linker places give their offset into the segment and imports
+// give zero, get_base says what has to be added to that
+impl<'a> SymbolLookup for Context<'a> {
+  fn lookup(&self, name: &str) -> Option<i32> {
+    let key = self.resolve_symbol(name)?;
+    if self.is_import(&key) {
+      return Some(0);
+    }
+    match self.get_var(&key) {
+      Some(num) => Some(*num),
+      None => self.get_label_address(&key).map(|address| address as i32),
+    }
+  }
+
+  fn lookup_size(&self, name: &str) -> Option<i32> {
+    let key = self.resolve_with(name, |key| self.size_map.contains_key(key))?;
+    self.size_map.get(&key).copied()
+  }
+
+  fn describe_unknown(&self, name: &str) -> String {
+    if !is_cheap_local(name) {
+      return format!("Undefined symbol '{}'", name);
+    }
+    let owner = match &self.cheap_local_owner {
+      Some(owner) => owner,
+      None => {
+        return format!(
+          "Cheap local label '{}' needs a normal label before it",
+          name
+        )
+      }
     };
-    let name = self.get_formatted_name(num);
-    self.add_size_from_variable(&name);
+    match self.label_map.keys().any(|key| key.ends_with(name)) {
+      true => format!(
+        "Cheap local label '{}' is not defined after '{}', it can't be used across a normal label",
+        name, owner
+      ),
+      false => format!("Undefined symbol '{}'", name),
+    }
   }
+}
+
+fn is_cheap_local(name: &str) -> bool {
+  name.starts_with(is_local_label_signifier)
+}
+
+struct OpenScope {
+  name: String,
+  is_proc: bool,
+  location: Option<Location>,
+}
 
-  fn get_label_address(&self, name: &String) -> u16 {
-    let label = self.label_map.get(name).unwrap();
-    let segment = self.get_segment_by_id(label.get_segment()).unwrap();
-    let start = self.config.get_segment_start(segment.get_name());
-    start + label.get_offset()
+impl OpenScope {
+  fn get_missing_end(&self) -> String {
+    let end = match self.is_proc {
+      true => ".endproc",
+      false => ".endscope",
+    };
+    format!("Missing {} for '{}'", end, self.name)
+  }
+}
+
+fn join_scopes(scopes: &[OpenScope], name: &str) -> String {
+  let mut key = String::new();
+  for scope in scopes {
+    key.push_str(&scope.name);
+    key.push_str("::");
   }
+  key.push_str(name);
+  key
 }
 
 #[derive(Clone)]
 struct Label {
   segment_id: u8,
-  offset_from_seg_start: u16,
+  offset_from_seg_start: u32,
+  // where it is when the code runs, which .org can move away from the offset
+  address: u32,
+  // outside of a .org the address is an offset the linker adds to
+  relocatable: bool,
 }
 
 impl Label {
@@ -787,6 +1873,8 @@ impl Label {
     Label {
       segment_id,
       offset_from_seg_start: 0,
+      address: 0,
+      relocatable: false,
     }
   }
 
@@ -794,21 +1882,48 @@ impl Label {
     self.segment_id
   }
 
-  fn add_offset(&mut self, offset: u16) {
+  fn add_offset(&mut self, offset: u32) {
     self.offset_from_seg_start = offset;
   }
 
-  fn get_offset(&self) -> u16 {
+  fn get_offset(&self) -> u32 {
     self.offset_from_seg_start
   }
+
+  fn set_address(&mut self, address: u32) {
+    self.address = address;
+  }
+
+  fn get_address(&self) -> u32 {
+    self.address
+  }
+
+  fn set_relocatable(&mut self, relocatable: bool) {
+    self.relocatable = relocatable;
+  }
+
+  fn is_relocatable(&self) -> bool {
+    self.relocatable
+  }
 }
 
 struct Segment {
   id: u8,
   name: String,
   values: Vec<u8>,
-  size: u16,
+  relocations: Vec<Relocation>,
+  size: u32,
+  // the most any .align in it asked for
+  align: u16,
   address_mode: AddressMode,
+  origin: Option<Origin>,
+}
+
+// A .org, the address code runs at from the offset it was given at
+#[derive(Clone, Copy)]
+struct Origin {
+  address: u32,
+  offset: u32,
 }
 
 impl Segment {
@@ -817,8 +1932,11 @@ impl Segment {
       id,
       name: name.to_owned(),
       values: vec![],
+      relocations: vec![],
       size: 0,
+      align: 1,
       address_mode: mode,
+      origin: None,
     }
   }
 
@@ -826,18 +1944,38 @@ impl Segment {
     self.values.push(byte);
   }
 
-  fn get_value_len(&self) -> u16 {
-    self.values.len() as u16
+  fn add_relocation(&mut self, reloc: Relocation) {
+    self.relocations.push(reloc);
+  }
+
+  fn align_to(&mut self, align: u16) {
+    self.align = self.align.max(align);
+  }
+
+  fn get_value_len(&self) -> u32 {
+    self.values.len() as u32
   }
 
-  fn add_size(&mut self, size: u16) {
+  fn add_size(&mut self, size: u32) {
     self.size += size;
   }
 
-  fn get_size(&self) -> u16 {
+  fn reset_size(&mut self) {
+    self.size = 0;
+  }
+
+  fn get_size(&self) -> u32 {
     self.size
   }
 
+  fn set_origin(&mut self, origin: Option<Origin>) {
+    self.origin = origin;
+  }
+
+  fn get_origin(&self) -> Option<Origin> {
+    self.origin
+  }
+
   fn get_mode(&self) -> &AddressMode {
     &self.address_mode
   }
@@ -845,10 +1983,6 @@ impl Segment {
   fn get_name(&self) -> &String {
     &self.name
   }
-
-  fn get_values(&self) -> &Vec<u8> {
-    &self.values
-  }
 }
 
 impl Eq for Segment {}
@@ -858,7 +1992,7 @@ impl PartialEq for Segment {
   }
 }
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq)]
 enum AddressMode {
   ZeroPage,
   Absolute,
@@ -871,4 +2005,209 @@ impl AddressMode {
       _ => AddressMode::Absolute,
     }
   }
+
+  fn from_size(address_size: &str) -> AddressMode {
+    match address_size {
+      "zeropage" => AddressMode::ZeroPage,
+      _ => AddressMode::Absolute,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::SourceMap;
+  use crate::linker::link;
+  use crate::test_helper::{self, configure, CONFIG};
+
+  fn assemble_source(source: &str) -> Result<ObjectFile, Vec<AsmError>> {
+    test_helper::assemble_source("a.s", source, &configure(CONFIG))
+  }
+
+  #[test]
+  fn check_charmap() {
+    let mapped =
+      ".segment \"CODE\"\n.byte \"AB\"\n.charmap 'A', 1\n.charmap 'B', 2\n.asciiz \"BA\"\n";
+    let object = assemble_source(mapped).unwrap();
+    let bytes = object.get_segments()[0].get_values();
+    assert_eq!(bytes, &vec![0x41, 0x42, 2, 1, 0]);
+    let unmapped = ".segment \"CODE\"\n.charmap 'A', 1\n.byte \"AC\"\n";
+    let errors = assemble_source(unmapped).err().unwrap();
+    assert!(errors[0]
+      .to_string()
+      .starts_with("Character 'C' is not in the character map"));
+    let unmapped = ".segment \"CODE\"\n.charmap 'A', 1\n.asciiz \"CA\"\n";
+    assert!(assemble_source(unmapped).is_err());
+    let wide = ".segment \"CODE\"\n.byte \"A\u{20AC}\"\n";
+    assert!(assemble_source(wide).is_err());
+  }
+
+  #[test]
+  fn check_error_locations() {
+    let source = ".segment \"CODE\"\nnop\nstx $1234,y\n";
+    let errors = assemble_source(source).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    let mut sources = SourceMap::new();
+    sources.add(Path::new("a.s"), source);
+    let rendered = errors[0].render(&sources);
+    assert!(rendered.starts_with("error: stx does not have an absolute address mode"));
+    assert!(rendered.contains("| stx $1234,y"));
+    let errors = assemble_source("nop\n").err().unwrap();
+    assert!(errors[0].to_string().starts_with("No segment selected"));
+  }
+
+  #[test]
+  fn check_indirect_modes() {
+    let source = ".segment \"ZEROPAGE\"\n.res 4\nptr: .res 2\n.segment \"CODE\"\n\
+      lda (ptr,x)\nsta (ptr),y\nlda ($10,x)\njmp (vector)\nvector: .word $1234\n";
+    let config = configure(CONFIG);
+    let object = test_helper::assemble_source("a.s", source, &config).unwrap();
+    let files = link(&[object], &config, Path::new("a.out")).unwrap();
+    let expected = [
+      0xA1, 0x04, 0x91, 0x04, 0xA1, 0x10, 0x6C, 0x09, 0x80, 0x34, 0x12,
+    ];
+    assert_eq!(&files[0].get_bytes()[..11], &expected);
+    let errors = assemble_source(".segment \"CODE\"\ntable: lda (table,x)\n")
+      .err()
+      .unwrap();
+    assert!(errors[0]
+      .to_string()
+      .starts_with("Indirect pointer in segment CODE is not in zero page"));
+    let errors = assemble_source(".segment \"CODE\"\nlda ($1234),y\n")
+      .err()
+      .unwrap();
+    assert!(errors[0]
+      .to_string()
+      .starts_with("Indirect pointer $1234 is not in zero page"));
+  }
+
+  #[test]
+  fn check_shift_modes() {
+    let source = ".segment \"CODE\"\nasl a\nlsr\nasl $10\nasl $0200,x\nclc\n";
+    let object = assemble_source(source).unwrap();
+    let bytes = object.get_segments()[0].get_values();
+    assert_eq!(bytes, &vec![0x0A, 0x4A, 0x06, 0x10, 0x1E, 0x00, 0x02, 0x18]);
+    assert!(assemble_source(".segment \"CODE\"\nlda a\n").is_err());
+  }
+
+  #[test]
+  fn check_res_shrinks() {
+    // the first pass guesses zero page for fwd, so the .res starts out a byte
+    // bigger than it ends up
+    let source = ".segment \"CODE\"\nstart: lda fwd\nend:\n.res 16 - (end - start)\n\
+      next: .word next\nfwd = $1234\n";
+    let object = assemble_source(source).unwrap();
+    let segment = &object.get_segments()[0];
+    assert_eq!(segment.get_values().len(), 18);
+    assert_eq!(&segment.get_values()[..3], &[0xAD, 0x34, 0x12]);
+    let reloc = &segment.get_relocations()[0];
+    assert_eq!(reloc.get_offset(), 16);
+    assert_eq!(reloc.get_addend(), 16);
+  }
+
+  #[test]
+  fn check_incbin_range() {
+    let path = std::env::temp_dir().join("rusty_axe65_check_incbin_range.bin");
+    std::fs::write(&path, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+    let incbin = |args: &str| {
+      let source = format!(
+        ".segment \"CODE\"\n.incbin \"{}\"{}\n",
+        path.display(),
+        args
+      );
+      assemble_source(&source).map(|object| object.get_segments()[0].get_values().to_owned())
+    };
+    let whole = incbin("");
+    let from = incbin(", 5");
+    let range = incbin(", 2, 3");
+    let past_end = incbin(", 6, 4");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(whole.unwrap(), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(from.unwrap(), vec![5, 6, 7]);
+    assert_eq!(range.unwrap(), vec![2, 3, 4]);
+    assert!(past_end.is_err());
+  }
+
+  #[test]
+  fn check_scopes() {
+    let source = ".segment \"CODE\"
+value = 1
+.scope outer
+  value = 2
+  .scope inner
+    value = 3
+  .endscope
+  .byte value, ::value, inner::value
+.endscope
+.byte outer::value, outer::inner::value, value
+.proc main
+  lda #value
+.endproc
+";
+    let object = assemble_source(source).unwrap();
+    let bytes = object.get_segments()[0].get_values();
+    assert_eq!(bytes, &vec![2, 1, 3, 2, 3, 1, 0xA9, 0x01]);
+    let hidden = ".segment \"CODE\"\n.scope outer\nvalue = 2\n.endscope\n.byte value\n";
+    assert!(assemble_source(hidden).is_err());
+  }
+
+  #[test]
+  fn check_byte() {
+    let source = ".segment \"CODE\"\nstart: .byte 1, -1, $FF, \"AB\", <$1234, >start\n";
+    let object = assemble_source(source).unwrap();
+    let segment = &object.get_segments()[0];
+    assert_eq!(
+      segment.get_values(),
+      &vec![1, 0xFF, 0xFF, 0x41, 0x42, 0x34, 0]
+    );
+    let reloc = &segment.get_relocations()[0];
+    assert_eq!(reloc.get_offset(), 6);
+    assert_eq!(reloc.get_kind(), RelocKind::Hi);
+    assert!(assemble_source(".segment \"CODE\"\n.byte 256\n").is_err());
+    assert!(assemble_source(".segment \"CODE\"\n.byte -129\n").is_err());
+  }
+
+  #[test]
+  fn check_org_and_align() {
+    let source = ".segment \"CODE\"
+nop
+.org $C000
+here: jmp here
+.align 4, $EA
+.reloc
+back: jmp back
+.align 4
+.byte 1
+";
+    let object = assemble_source(source).unwrap();
+    let segment = &object.get_segments()[0];
+    // $C003 needs one byte to line up, the offset after the .reloc none
+    let expected = vec![0xEA, 0x4C, 0x00, 0xC0, 0xEA, 0x4C, 0x00, 0x00, 0x01];
+    assert_eq!(segment.get_values(), &expected);
+    assert_eq!(segment.get_align(), 4);
+    let reloc = &segment.get_relocations()[0];
+    assert_eq!(reloc.get_offset(), 6);
+    assert_eq!(reloc.get_addend(), 5);
+    assert!(assemble_source(".segment \"CODE\"\n.align 0\n").is_err());
+  }
+
+  #[test]
+  fn check_segment_stack() {
+    let source = ".code\nnop\n.pushseg\n.zeropage\nptr: .res 1\n.popseg\nlda ptr\n";
+    let object = assemble_source(source).unwrap();
+    let segments = object.get_segments();
+    assert_eq!(segments[0].get_name(), "CODE");
+    assert_eq!(segments[0].get_values(), &vec![0xEA, 0xA5, 0x00]);
+    let reloc = &segments[0].get_relocations()[0];
+    assert_eq!(reloc.get_kind(), RelocKind::Byte);
+    assert_eq!(
+      reloc.get_target(),
+      &RelocTarget::Segment(String::from("ZEROPAGE"))
+    );
+    assert_eq!(segments[1].get_name(), "ZEROPAGE");
+    assert!(assemble_source(".code\n.popseg\n").is_err());
+    let resized = ".zeropage\n.segment \"ZEROPAGE\": absolute\n";
+    assert!(assemble_source(resized).is_err());
+  }
 }
@@ -1,29 +1,26 @@
 use crate::char_helper::*;
+use crate::error::{AsmError, AsmResult};
 use crate::opcode::is_opcode;
-use crate::token::{Token, TokenType};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::token::{Location, Token, TokenType};
 
-static LINE_COUNTER: AtomicUsize = AtomicUsize::new(1);
-
-fn line_num() -> usize {
-  LINE_COUNTER.load(Ordering::Relaxed)
-}
-
-pub fn lex(file: &String, prune_comments: bool) -> Vec<Token> {
-  if &file[file.len() - 1..] != "\n" {
-    panic!("File needs to end in a newline");
+pub fn lex(file: &str, file_id: usize, prune_comments: bool) -> AsmResult<Vec<Token>> {
+  let chars: Vec<char> = file.chars().collect();
+  let file_len = chars.len();
+  if chars.last() != Some(&'\n') {
+    let last_line = chars.iter().filter(|c| is_newline(**c)).count() + 1;
+    let location = Location::new(file_id, last_line, file_len, file_len);
+    let message = String::from("File needs to end in a newline");
+    return Err(AsmError::new(message, Some(location)));
   }
-  let file_len = file.len();
   let mut out_vec = Vec::with_capacity(file_len);
-  let mut characters = Characters::new(file.chars().collect(), file_len);
-  let mut next = next_token(&mut characters);
+  let mut characters = Characters::new(chars, file_len, file_id);
+  let mut next = next_token(&mut characters)?;
   while next.get_type() != &TokenType::EndOfFile {
     out_vec.push(next);
-    next = next_token(&mut characters);
+    next = next_token(&mut characters)?;
   }
-  LINE_COUNTER.store(0, Ordering::Relaxed);
   out_vec.push(next);
-  out_vec
+  let tokens = out_vec
     .into_iter()
     .filter(|t| {
       let mut wn = t.get_type() != &TokenType::Whitespace && t.get_type() != &TokenType::Newline;
@@ -32,20 +29,22 @@ pub fn lex(file: &String, prune_comments: bool) -> Vec<Token> {
       }
       wn
     })
-    .collect()
+    .map(|t| t.in_file(file_id))
+    .collect();
+  Ok(tokens)
 }
 
-fn next_token(chars: &mut Characters) -> Token {
+fn next_token(chars: &mut Characters) -> AsmResult<Token> {
   let empty = String::from("");
   let start = chars.get_index();
   if chars.get_index() >= chars.max_size() {
-    return Token::new(
+    return Ok(Token::new(
       empty,
       TokenType::EndOfFile,
       start,
       chars.max_size(),
-      line_num(),
-    );
+      chars.get_line(),
+    ));
   }
   let next = chars.get_next();
   if is_num_signifier(next) {
@@ -54,7 +53,7 @@ fn next_token(chars: &mut Characters) -> Token {
   if is_ctrl_command_signifier(next) {
     return handle_control_command(chars);
   }
-  if is_local_label_signifier(next) {
+  if chars.is_local_char(next) {
     return handle_local_label(chars);
   }
   if is_identifier(next) {
@@ -64,32 +63,48 @@ fn next_token(chars: &mut Characters) -> Token {
     return handle_operator(chars);
   }
   if is_whitespace(next) {
-    return handle_whitespace(chars);
+    return Ok(handle_whitespace(chars));
   }
   if is_newline(next) {
-    LINE_COUNTER.fetch_add(1, Ordering::Relaxed);
-    return Token::new(
+    let token = Token::new(
       empty,
       TokenType::Newline,
       start,
       chars.get_index(),
-      line_num(),
+      chars.get_line(),
     );
+    chars.next_line();
+    chars.take_expect_local_char();
+    return Ok(token);
   }
-  Token::new(String::from(""), TokenType::EndOfFile, 0, 0, line_num())
+  let message = format!("Unexpected character '{}'", next);
+  Err(chars.error_from(start, message))
 }
 
-fn handle_number(chars: &mut Characters) -> Token {
+fn handle_number(chars: &mut Characters) -> AsmResult<Token> {
   if is_hex_signifier(chars.get_current()) {
-    return create_number_token(chars, is_hex_number, TokenType::HexNumber);
+    return Ok(create_number_token(
+      chars,
+      is_hex_number,
+      TokenType::HexNumber,
+    ));
   }
   if is_bin_signifier(chars.get_current()) {
-    return create_number_token(chars, is_bin_number, TokenType::BinNumber);
+    return Ok(create_number_token(
+      chars,
+      is_bin_number,
+      TokenType::BinNumber,
+    ));
   }
   if is_dec_signifier(chars.get_current()) {
-    return create_number_token(chars, is_dec_number, TokenType::DecNumber);
+    return Ok(create_number_token(
+      chars,
+      is_dec_number,
+      TokenType::DecNumber,
+    ));
   }
-  panic!("Invalid number provided: {}", chars.get_current());
+  let message = format!("Invalid number provided: {}", chars.get_current());
+  Err(chars.error_from(chars.get_index() - 1, message))
 }
 
 fn create_number_token<F: Fn(char) -> bool>(
@@ -106,54 +121,59 @@ fn create_number_token<F: Fn(char) -> bool>(
     token_string.push(c);
     next = chars.peek_next();
   }
-  Token::new(token_string, t, start, chars.get_index(), line_num())
+  Token::new(token_string, t, start, chars.get_index(), chars.get_line())
 }
 
-fn handle_control_command(chars: &mut Characters) -> Token {
-  let start = chars.get_index();
-  let period = chars.get_current();
-  if period != '.' {
-    panic!("Expected '.' got {}", period);
-  }
+fn handle_control_command(chars: &mut Characters) -> AsmResult<Token> {
+  let start = chars.get_index() - 1;
   chars.get_next();
-  let token_string = get_identifier_text(chars);
+  let token_string = get_identifier_text(chars)?;
   let dir_string = token_string.to_ascii_lowercase();
-  Token::new(
+  let operator = TokenType::get_operator_type(&dir_string);
+  let directive = match operator.or_else(|| TokenType::get_directive_type(&dir_string)) {
+    Some(directive) => directive,
+    None => {
+      let message = format!("Unknown directive '.{}'", token_string);
+      return Err(chars.error_from(start, message));
+    }
+  };
+  if directive == TokenType::DirectiveLocalchar {
+    chars.expect_local_char();
+  }
+  Ok(Token::new(
     token_string,
-    TokenType::get_directive_type(&dir_string),
+    directive,
     start,
     chars.get_index(),
-    line_num(),
-  )
+    chars.get_line(),
+  ))
 }
 
-fn handle_local_label(chars: &mut Characters) -> Token {
-  let start = chars.get_index();
-  let period = chars.get_current();
-  if period != '@' {
-    panic!("Expected '@' got {}", period);
-  }
-  let token_string = get_identifier_text(chars);
-  Token::new(
+fn handle_local_label(chars: &mut Characters) -> AsmResult<Token> {
+  let start = chars.get_index() - 1;
+  let mut token_string = String::from(chars.get_current());
+  chars.get_next();
+  token_string.push_str(&get_identifier_text(chars)?);
+  Ok(Token::new(
     token_string,
     TokenType::LocalLabel,
     start,
     chars.get_index(),
-    line_num(),
-  )
+    chars.get_line(),
+  ))
 }
 
-fn handle_identifier(chars: &mut Characters) -> Token {
+fn handle_identifier(chars: &mut Characters) -> AsmResult<Token> {
   let start = chars.get_index() - 1;
   let next = chars.peek_next();
   let token_string = match is_identifier(next) {
-    true => get_identifier_text(chars),
+    true => get_identifier_text(chars)?,
     false => String::from(chars.get_current()),
   };
   let t = match token_string.len() {
     1 => match token_string.as_ref() {
-      "X" => TokenType::XRegister,
-      "Y" => TokenType::YRegister,
+      "X" | "x" => TokenType::XRegister,
+      "Y" | "y" => TokenType::YRegister,
       _ => TokenType::Identifier,
     },
     _ => match is_opcode(&token_string) {
@@ -161,80 +181,87 @@ fn handle_identifier(chars: &mut Characters) -> Token {
       false => TokenType::Identifier,
     },
   };
-  Token::new(token_string, t, start, chars.get_index(), line_num())
+  Ok(Token::new(
+    token_string,
+    t,
+    start,
+    chars.get_index(),
+    chars.get_line(),
+  ))
 }
 
-fn handle_operator(chars: &mut Characters) -> Token {
+fn handle_operator(chars: &mut Characters) -> AsmResult<Token> {
   let start = chars.get_index() - 1;
   let current = chars.get_current();
   let next = chars.peek_next();
   let end = chars.get_index();
-  match is_combo_operator(current) {
+  let token = match is_combo_operator(current) {
     true => match current {
       '<' => match next {
         '<' => handle_combo_operator("<<", TokenType::Shl, start, end, chars),
         '>' => handle_combo_operator("<>", TokenType::NotEqual, start, end, chars),
         '=' => handle_combo_operator("<=", TokenType::LessThanOrEqual, start, end, chars),
-        _ => handle_single_operator(current, TokenType::LessThan, start, end),
+        _ => handle_single_operator(current, TokenType::LessThan, start, end, chars),
       },
       '>' => match next {
         '>' => handle_combo_operator(">>", TokenType::Shr, start, end, chars),
         '=' => handle_combo_operator(">=", TokenType::GreaterThanOrEqual, start, end, chars),
-        _ => handle_single_operator(current, TokenType::GreaterThan, start, end),
+        _ => handle_single_operator(current, TokenType::GreaterThan, start, end, chars),
       },
       ':' => match next {
         ':' => handle_combo_operator("::", TokenType::Namespace, start, end, chars),
         '=' => handle_combo_operator(":=", TokenType::Assignment, start, end, chars),
         '+' => handle_unnamed_label(TokenType::ULabel, start, end, chars, '+'),
         '-' => handle_unnamed_label(TokenType::ULabel, start, end, chars, '-'),
-        _ => handle_single_operator(current, TokenType::Colon, start, end),
+        _ => handle_single_operator(current, TokenType::Colon, start, end, chars),
       },
       '|' => match next {
         '|' => handle_combo_operator("||", TokenType::BoolOr, start, end, chars),
-        _ => handle_single_operator(current, TokenType::Or, start, end),
+        _ => handle_single_operator(current, TokenType::Or, start, end, chars),
       },
       '&' => match next {
         '&' => handle_combo_operator("&&", TokenType::BoolAnd, start, end, chars),
-        _ => handle_single_operator(current, TokenType::And, start, end),
+        _ => handle_single_operator(current, TokenType::And, start, end, chars),
       },
-      _ => panic!("Not yet implemented {}", current),
+      _ => unreachable!("{} is not a combo operator", current),
     },
     false => match current {
       ';' => handle_comment(chars),
-      '+' => handle_single_operator(current, TokenType::Addition, start, end),
-      '-' => handle_single_operator(current, TokenType::Subtraction, start, end),
-      '*' => handle_single_operator(current, TokenType::Multiplication, start, end),
-      '/' => handle_single_operator(current, TokenType::Division, start, end),
-      '=' => handle_single_operator(current, TokenType::Equal, start, end),
-      '^' => handle_single_operator(current, TokenType::Xor, start, end),
-      ',' => handle_single_operator(current, TokenType::Comma, start, end),
-      '~' => handle_single_operator(current, TokenType::Not, start, end),
-      '!' => handle_single_operator(current, TokenType::BoolNot, start, end),
-      '<' => handle_single_operator(current, TokenType::LessThan, start, end),
-      '>' => handle_single_operator(current, TokenType::GreaterThan, start, end),
-      '(' => handle_single_operator(current, TokenType::OParen, start, end),
-      ')' => handle_single_operator(current, TokenType::CParen, start, end),
-      '[' => handle_single_operator(current, TokenType::OBracket, start, end),
-      ']' => handle_single_operator(current, TokenType::CBracket, start, end),
-      '{' => handle_single_operator(current, TokenType::OCurly, start, end),
-      '}' => handle_single_operator(current, TokenType::CCurly, start, end),
-      '#' => handle_single_operator(current, TokenType::Hash, start, end),
-      ':' => handle_single_operator(current, TokenType::Colon, start, end),
-      '"' => handle_string_constant(chars, start),
-      '\'' => handle_char_constant(chars, start),
-      _ => panic!("Unrecognized operator: {}", current),
+      '+' => handle_single_operator(current, TokenType::Addition, start, end, chars),
+      '-' => handle_single_operator(current, TokenType::Subtraction, start, end, chars),
+      '*' => handle_single_operator(current, TokenType::Multiplication, start, end, chars),
+      '/' => handle_single_operator(current, TokenType::Division, start, end, chars),
+      '=' => handle_single_operator(current, TokenType::Equal, start, end, chars),
+      '^' => handle_single_operator(current, TokenType::Xor, start, end, chars),
+      ',' => handle_single_operator(current, TokenType::Comma, start, end, chars),
+      '~' => handle_single_operator(current, TokenType::Not, start, end, chars),
+      '!' => handle_single_operator(current, TokenType::BoolNot, start, end, chars),
+      '<' => handle_single_operator(current, TokenType::LessThan, start, end, chars),
+      '>' => handle_single_operator(current, TokenType::GreaterThan, start, end, chars),
+      '(' => handle_single_operator(current, TokenType::OParen, start, end, chars),
+      ')' => handle_single_operator(current, TokenType::CParen, start, end, chars),
+      '[' => handle_single_operator(current, TokenType::OBracket, start, end, chars),
+      ']' => handle_single_operator(current, TokenType::CBracket, start, end, chars),
+      '{' => handle_single_operator(current, TokenType::OCurly, start, end, chars),
+      '}' => handle_single_operator(current, TokenType::CCurly, start, end, chars),
+      '#' => handle_single_operator(current, TokenType::Hash, start, end, chars),
+      ':' => handle_single_operator(current, TokenType::Colon, start, end, chars),
+      '"' => handle_string_constant(chars, start)?,
+      '\'' => handle_char_constant(chars, start)?,
+      _ => {
+        let message = format!("Unrecognized operator: {}", current);
+        return Err(chars.error_from(start, message));
+      }
     },
-  }
+  };
+  Ok(token)
 }
 
-fn get_identifier_text(chars: &mut Characters) -> String {
+fn get_identifier_text(chars: &mut Characters) -> AsmResult<String> {
   let mut next = chars.get_current();
   if !is_id_start(next) {
-    panic!(
-      "Invalid identifier starting point \"{}\" | line: {}",
-      next,
-      line_num()
-    );
+    let message = format!("Invalid identifier starting point \"{}\"", next);
+    return Err(chars.error_from(chars.get_index() - 1, message));
   }
   let mut token_string = String::from(next);
   next = chars.peek_next();
@@ -243,11 +270,11 @@ fn get_identifier_text(chars: &mut Characters) -> String {
     token_string.push(c);
     next = chars.peek_next();
   }
-  token_string
+  Ok(token_string)
 }
 
-fn handle_single_operator(c: char, t: TokenType, s: usize, e: usize) -> Token {
-  Token::new(String::from(c), t, s, e, line_num())
+fn handle_single_operator(c: char, t: TokenType, s: usize, e: usize, chars: &Characters) -> Token {
+  Token::new(String::from(c), t, s, e, chars.get_line())
 }
 
 fn handle_combo_operator(
@@ -258,7 +285,7 @@ fn handle_combo_operator(
   chars: &mut Characters,
 ) -> Token {
   chars.get_next(); // discard the second operator
-  Token::new(String::from(c), t, s, e, line_num())
+  Token::new(String::from(c), t, s, e + 1, chars.get_line())
 }
 
 fn handle_unnamed_label(
@@ -275,14 +302,14 @@ fn handle_unnamed_label(
     out_string.push(c);
     next = chars.peek_next();
   }
-  Token::new(out_string, t, s, e, line_num())
+  Token::new(out_string, t, s, e.max(chars.get_index()), chars.get_line())
 }
 
 fn handle_comment(chars: &mut Characters) -> Token {
   let start = chars.get_index() - 1;
   let mut next = chars.peek_next();
   let mut end = String::new();
-  while next != '\n' {
+  while !is_newline(next) && chars.get_index() < chars.max_size() {
     let c = chars.get_next();
     end.push(c);
     next = chars.peek_next();
@@ -292,88 +319,124 @@ fn handle_comment(chars: &mut Characters) -> Token {
     TokenType::Comment,
     start,
     chars.get_index(),
-    line_num(),
+    chars.get_line(),
   )
 }
 
-fn handle_string_constant(chars: &mut Characters, s: usize) -> Token {
+fn handle_string_constant(chars: &mut Characters, s: usize) -> AsmResult<Token> {
   let mut next = chars.peek_next();
   let mut out_string = String::new();
-  while next != '"' {
+  while next != '"' && !is_newline(next) {
     let c = chars.get_next();
     out_string.push(c);
     next = chars.peek_next();
   }
-  let close_quote = chars.get_next();
-  if close_quote != '"' {
-    panic!("String constant quotes not closed");
+  if next != '"' {
+    let message = String::from("String constant quotes not closed");
+    return Err(chars.error_from(s, message));
   }
-  Token::new(
+  chars.get_next();
+  Ok(Token::new(
     out_string,
     TokenType::StringConst,
     s,
     chars.get_index(),
-    line_num(),
-  )
+    chars.get_line(),
+  ))
 }
 
-fn handle_char_constant(chars: &mut Characters, s: usize) -> Token {
+fn handle_char_constant(chars: &mut Characters, s: usize) -> AsmResult<Token> {
   let mut next = chars.peek_next();
   let mut out_string = String::new();
-  while next != '\'' {
+  while next != '\'' && !is_newline(next) {
     let c = chars.get_next();
     out_string.push(c);
     next = chars.peek_next();
   }
-  let close_quote = chars.get_next();
-  if close_quote != '\'' {
-    panic!("Char constant quotes not closed");
+  if next != '\'' {
+    let message = String::from("Char constant quotes not closed");
+    return Err(chars.error_from(s, message));
   }
-  Token::new(
+  chars.get_next();
+  // the argument of .localchar changes how the rest of the file is read
+  if chars.take_expect_local_char() {
+    let mut local_char = out_string.chars();
+    match (local_char.next(), local_char.next()) {
+      (Some(c), None) if is_local_label_signifier(c) => chars.set_local_char(c),
+      _ => {
+        let message = String::from("The local label character must be '@' or '?'");
+        return Err(chars.error_from(s, message));
+      }
+    }
+  }
+  Ok(Token::new(
     out_string,
     TokenType::StringConst,
     s,
     chars.get_index(),
-    line_num(),
-  )
+    chars.get_line(),
+  ))
 }
 
 fn handle_whitespace(chars: &mut Characters) -> Token {
   let empty = String::from("");
-  let start = chars.get_index();
+  let start = chars.get_index() - 1;
   let mut next = chars.peek_next();
   while is_whitespace(next) {
     chars.get_next();
     next = chars.peek_next();
   }
-  return Token::new(
+  Token::new(
     empty,
     TokenType::Whitespace,
     start,
     chars.get_index(),
-    line_num(),
-  );
+    chars.get_line(),
+  )
 }
 
 struct Characters {
   cur_index: usize,
   chars: Vec<char>,
   max_size: usize,
+  file_id: usize,
+  line: usize,
+  local_char: char,
+  expecting_local_char: bool,
 }
 
 impl Characters {
-  fn new(chars: Vec<char>, max: usize) -> Characters {
+  fn new(chars: Vec<char>, max: usize, file_id: usize) -> Characters {
     Characters {
       cur_index: 0,
       chars,
       max_size: max,
+      file_id,
+      line: 1,
+      local_char: '@',
+      expecting_local_char: false,
     }
   }
 
+  // Builds an error spanning from start up to the current position
+  fn error_from(&self, start: usize, message: String) -> AsmError {
+    let end = self.cur_index.max(start + 1);
+    let location = Location::new(self.file_id, self.line, start, end);
+    AsmError::new(message, Some(location))
+  }
+
   fn get_index(&self) -> usize {
     self.cur_index
   }
 
+  fn get_line(&self) -> usize {
+    self.line
+  }
+
+  fn next_line(&mut self) {
+    self.line += 1;
+  }
+
   fn get_next(&mut self) -> char {
     let c = self.chars[self.cur_index];
     self.cur_index += 1;
@@ -385,10 +448,85 @@ impl Characters {
   }
 
   fn peek_next(&mut self) -> char {
-    self.chars[self.cur_index]
+    match self.chars.get(self.cur_index) {
+      Some(c) => *c,
+      None => '\0',
+    }
   }
 
   fn max_size(&self) -> usize {
     self.max_size
   }
+
+  fn is_local_char(&self, c: char) -> bool {
+    c == self.local_char
+  }
+
+  fn set_local_char(&mut self, c: char) {
+    self.local_char = c;
+  }
+
+  fn expect_local_char(&mut self) {
+    self.expecting_local_char = true;
+  }
+
+  fn take_expect_local_char(&mut self) -> bool {
+    std::mem::replace(&mut self.expecting_local_char, false)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn local_labels(source: &str) -> Vec<String> {
+    lex(source, 0, true)
+      .unwrap()
+      .iter()
+      .filter(|t| t.get_type() == &TokenType::LocalLabel)
+      .map(|t| t.get_value().to_owned())
+      .collect()
+  }
+
+  #[test]
+  fn check_localchar() {
+    assert_eq!(local_labels("@loop: bne @loop\n"), vec!["@loop", "@loop"]);
+    assert_eq!(
+      local_labels(".localchar '?'\n?loop: bne ?loop\n"),
+      vec!["?loop", "?loop"]
+    );
+    assert!(lex(".localchar '!'\n", 0, true).is_err());
+  }
+
+  #[test]
+  fn check_errors() {
+    let error = lex("lda #1\n.bogus\n", 0, true).unwrap_err();
+    assert_eq!(error.get_location().unwrap().get_start(), 7);
+    assert_eq!(error.get_location().unwrap().get_line(), 2);
+    let error = lex("lda #1\n  ldx #`\n", 0, true).unwrap_err();
+    assert_eq!(error.get_location().unwrap().get_start(), 14);
+    let error = lex("nop\nnop", 0, true).unwrap_err();
+    assert_eq!(error.get_location().unwrap().get_line(), 2);
+  }
+
+  #[test]
+  fn check_lines() {
+    // each call counts its own lines, even with others running alongside it
+    let lex_lines = || {
+      let source = "nop\n\nlda #1\n".repeat(100);
+      lex(&source, 0, true)
+        .unwrap()
+        .iter()
+        .map(|t| t.get_location().get_line())
+        .collect::<Vec<usize>>()
+    };
+    let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(lex_lines)).collect();
+    let mut expected: Vec<usize> = (0..100)
+      .flat_map(|i| vec![i * 3 + 1, i * 3 + 3, i * 3 + 3, i * 3 + 3])
+      .collect();
+    expected.push(301);
+    for thread in threads {
+      assert_eq!(thread.join().unwrap(), expected);
+    }
+  }
 }
@@ -0,0 +1,474 @@
+use crate::configuration::{Configuration, OUTPUT_FILE};
+use crate::error::AsmError;
+use crate::object::{ObjectFile, ObjectSegment, RelocKind, RelocTarget, Relocation};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Puts every file's segments where the configuration says, fills in the
+// addresses that depend on where things went and lays the memory areas out
+// in the files they're written to
+pub fn link(
+  objects: &[ObjectFile],
+  config: &Configuration,
+  output: &Path,
+) -> Result<Vec<OutputFile>, Vec<AsmError>> {
+  let segments = place_segments(objects, config)?;
+  let symbols = collect_exports(objects, &segments)?;
+  check_imports(objects, &symbols)?;
+  let mut areas: HashMap<&String, Vec<u8>> = HashMap::new();
+  let mut errors = vec![];
+  for segment in &segments {
+    let mut values = vec![0; segment.size];
+    for part in &segment.parts {
+      let object = &objects[part.object];
+      let end = part.offset + part.segment.get_values().len();
+      let bytes = &mut values[part.offset..end];
+      bytes.copy_from_slice(part.segment.get_values());
+      for reloc in part.segment.get_relocations() {
+        let target = match reloc.get_target() {
+          RelocTarget::Segment(name) => find_part(&segments, part.object, name).map(|p| p.address),
+          RelocTarget::Import(name) => symbols.get(name).copied(),
+        };
+        if let Err(message) = relocate(bytes, part.address, reloc, target) {
+          errors.push(AsmError::new(
+            format!("{}: {}", object.get_name(), message),
+            reloc.get_location().copied(),
+          ));
+        }
+      }
+    }
+    // gaps between segments get the area's fill value as well
+    let memory = config.find_memory_by_name(&segment.load).unwrap();
+    let offset = segment.start - memory.get_start() as usize;
+    let area = areas.entry(memory.get_name()).or_default();
+    if area.len() < offset + segment.size {
+      area.resize(offset + segment.size, memory.get_fill_val());
+    }
+    area[offset..offset + segment.size].copy_from_slice(&values);
+  }
+  match errors.is_empty() {
+    true => Ok(write_areas(config, areas, output)),
+    false => Err(errors),
+  }
+}
+
+// The bytes that go in one file
+pub struct OutputFile {
+  path: PathBuf,
+  bytes: Vec<u8>,
+}
+
+impl OutputFile {
+  pub fn get_path(&self) -> &PathBuf {
+    &self.path
+  }
+
+  pub fn get_bytes(&self) -> &Vec<u8> {
+    &self.bytes
+  }
+}
+
+// Areas sharing a file follow each other in the order the configuration
+// lists them. Without fill an area stops after the last byte put in it
+fn write_areas(
+  config: &Configuration,
+  mut areas: HashMap<&String, Vec<u8>>,
+  output: &Path,
+) -> Vec<OutputFile> {
+  let mut files: Vec<OutputFile> = vec![];
+  for memory in config.get_memory().get_entries() {
+    let path = match memory.get_file() {
+      Some(OUTPUT_FILE) => output.to_owned(),
+      Some(file) => PathBuf::from(file),
+      None => continue,
+    };
+    let mut bytes = areas.remove(memory.get_name()).unwrap_or_default();
+    if memory.get_fill() {
+      bytes.resize(memory.get_size() as usize, memory.get_fill_val());
+    }
+    match files.iter_mut().find(|file| file.path == path) {
+      Some(file) => file.bytes.append(&mut bytes),
+      None => files.push(OutputFile { path, bytes }),
+    }
+  }
+  files
+}
+
+// A segment from the configuration and the parts files put in it
+struct PlacedSegment<'o> {
+  load: String,
+  start: usize,
+  size: usize,
+  parts: Vec<Part<'o>>,
+}
+
+// Where one file's part of a segment went
+struct Part<'o> {
+  object: usize,
+  segment: &'o ObjectSegment,
+  offset: usize,
+  address: i32,
+}
+
+// Segments without a start follow the one before them in their memory area.
+// Parts go into their segment in the order the files were given, each padded
+// out to its alignment
+fn place_segments<'o>(
+  objects: &'o [ObjectFile],
+  config: &Configuration,
+) -> Result<Vec<PlacedSegment<'o>>, Vec<AsmError>> {
+  let mut errors = vec![];
+  for object in objects {
+    for segment in object.get_segments() {
+      if config.find_segment_by_name(segment.get_name()).is_none() {
+        let message = format!(
+          "Segment {} used by {} is not in the configuration",
+          segment.get_name(),
+          object.get_name()
+        );
+        errors.push(AsmError::new(message, None));
+      }
+    }
+  }
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+  let mut placed = vec![];
+  let mut used: HashMap<&String, usize> = HashMap::new();
+  for entry in config.get_segments().get_entries() {
+    let memory = config.find_memory_by_name(entry.get_load()).unwrap();
+    let memory_start = memory.get_start() as usize;
+    let memory_end = memory_start + memory.get_size() as usize;
+    let used = used.entry(memory.get_name()).or_insert(memory_start);
+    // an offset counts from the start of the memory area
+    let requested = match (entry.get_start(), entry.get_offset()) {
+      (Some(start), _) => Some(start as usize),
+      (None, Some(offset)) => Some(memory_start + offset as usize),
+      (None, None) => None,
+    };
+    let start = match requested {
+      Some(start) if start < memory_start => {
+        let message = format!(
+          "Segment {} starts at ${:04X}, before memory area {} at ${:04X}",
+          entry.get_name(),
+          start,
+          memory.get_name(),
+          memory_start
+        );
+        errors.push(AsmError::new(message, None));
+        continue;
+      }
+      Some(start) if start < *used => {
+        let message = format!(
+          "Segment {} starts at ${:04X}, but memory area {} is already used up to ${:04X}",
+          entry.get_name(),
+          start,
+          memory.get_name(),
+          used
+        );
+        errors.push(AsmError::new(message, None));
+        continue;
+      }
+      Some(start) => start,
+      None => *used,
+    };
+    let mut size = 0;
+    let mut parts = vec![];
+    for (index, object) in objects.iter().enumerate() {
+      for segment in object.get_segments() {
+        if !segment.get_name().eq_ignore_ascii_case(entry.get_name()) {
+          continue;
+        }
+        let align = segment.get_align() as usize;
+        size += (align - (start + size) % align) % align;
+        parts.push(Part {
+          object: index,
+          segment,
+          offset: size,
+          address: (start + size) as i32,
+        });
+        size += segment.get_values().len();
+      }
+    }
+    if start + size > memory_end {
+      let message = format!(
+        "Segment {} doesn't fit in memory area {}, it runs {} bytes past the end",
+        entry.get_name(),
+        memory.get_name(),
+        start + size - memory_end
+      );
+      errors.push(AsmError::new(message, None));
+      continue;
+    }
+    *used = start + size;
+    placed.push(PlacedSegment {
+      load: entry.get_load().to_owned(),
+      start,
+      size,
+      parts,
+    });
+  }
+  match errors.is_empty() {
+    true => Ok(placed),
+    false => Err(errors),
+  }
+}
+
+fn find_part<'p, 'o>(
+  segments: &'p [PlacedSegment<'o>],
+  object: usize,
+  name: &str,
+) -> Option<&'p Part<'o>> {
+  segments
+    .iter()
+    .flat_map(|segment| segment.parts.iter())
+    .find(|part| part.object == object && part.segment.get_name() == name)
+}
+
+// Labels are exported relative to the exporting file's part of their segment,
+// so they only get their address here
+fn collect_exports(
+  objects: &[ObjectFile],
+  segments: &[PlacedSegment],
+) -> Result<HashMap<String, i32>, Vec<AsmError>> {
+  let mut symbols = HashMap::new();
+  let mut owners: HashMap<&String, &String> = HashMap::new();
+  let mut errors = vec![];
+  for (index, object) in objects.iter().enumerate() {
+    for export in object.get_exports() {
+      if let Some(owner) = owners.get(export.get_name()) {
+        let message = format!(
+          "Symbol '{}' is exported by both {} and {}",
+          export.get_name(),
+          owner,
+          object.get_name()
+        );
+        errors.push(AsmError::new(message, None));
+        continue;
+      }
+      let base = match export.get_segment() {
+        Some(segment) => find_part(segments, index, segment).map_or(0, |part| part.address),
+        None => 0,
+      };
+      owners.insert(export.get_name(), object.get_name());
+      symbols.insert(export.get_name().to_owned(), base + export.get_value());
+    }
+  }
+  match errors.is_empty() {
+    true => Ok(symbols),
+    false => Err(errors),
+  }
+}
+
+fn check_imports(
+  objects: &[ObjectFile],
+  symbols: &HashMap<String, i32>,
+) -> Result<(), Vec<AsmError>> {
+  let errors: Vec<AsmError> = objects
+    .iter()
+    .flat_map(|object| {
+      object
+        .get_imports()
+        .iter()
+        .filter(|name| !symbols.contains_key(*name))
+        .map(move |name| {
+          let message = format!(
+            "Unresolved import '{}' in {}, no file exports it",
+            name,
+            object.get_name()
+          );
+          AsmError::new(message, None)
+        })
+    })
+    .collect();
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(errors),
+  }
+}
+
+// Writes the target's address plus the addend over the placeholder bytes of
+// a part that starts at the given address
+fn relocate(
+  bytes: &mut [u8],
+  address: i32,
+  reloc: &Relocation,
+  target: Option<i32>,
+) -> Result<(), String> {
+  let value = match target {
+    Some(target) => target + reloc.get_addend(),
+    None => return Err(format!("{} is not known", reloc.get_target())),
+  };
+  let offset = reloc.get_offset() as usize;
+  let range_error = |range: &str| {
+    format!(
+      "Range error, {} plus {} comes to {} which is not in {}",
+      reloc.get_target(),
+      reloc.get_addend(),
+      value,
+      range
+    )
+  };
+  match reloc.get_kind() {
+    RelocKind::Byte => match value {
+      -128..=255 => bytes[offset] = value as u8,
+      _ => return Err(range_error("-128..255")),
+    },
+    RelocKind::Word => match value {
+      -32768..=65535 => bytes[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+      _ => return Err(range_error("-32768..65535")),
+    },
+    RelocKind::Far => match value {
+      0..=0xFFFFFF => bytes[offset..offset + 3].copy_from_slice(&value.to_le_bytes()[..3]),
+      _ => return Err(range_error("$000000..$FFFFFF")),
+    },
+    RelocKind::Dbyt => match value {
+      -32768..=65535 => bytes[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes()),
+      _ => return Err(range_error("-32768..65535")),
+    },
+    RelocKind::Dword => bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes()),
+    RelocKind::Lo => bytes[offset] = value as u8,
+    RelocKind::Hi => bytes[offset] = (value >> 8) as u8,
+    RelocKind::Bank => bytes[offset] = (value >> 16) as u8,
+    // counts from the end of the branch, which is the byte after the offset
+    RelocKind::Relative => {
+      let distance = match value - (address + offset as i32 + 1) {
+        o @ -128..=127 => {
+          bytes[offset] = o as u8;
+          return Ok(());
+        }
+        o if o > 127 => o - 127,
+        o => -128 - o,
+      };
+      return Err(format!("Branch out of range by {} bytes", distance));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::object::ObjectSegment;
+  use crate::test_helper::{assemble_source, configure, CONFIG};
+
+  #[test]
+  fn check_vectors_at_top_of_memory() {
+    let config = configure(CONFIG);
+    let mut object = ObjectFile::new("a.s");
+    let mut code = ObjectSegment::new("CODE");
+    code.add_value(0x60);
+    object.add_segment(code);
+    let mut vectors = ObjectSegment::new("VECTORS");
+    for _ in 0..3 {
+      let reloc = Relocation::new(
+        vectors.get_values().len() as u32,
+        RelocKind::Word,
+        RelocTarget::Segment(String::from("CODE")),
+        0,
+        None,
+      );
+      vectors.add_relocation(reloc);
+      vectors.add_value(0);
+      vectors.add_value(0);
+    }
+    object.add_segment(vectors);
+    let files = link(&[object], &config, Path::new("a.out")).unwrap();
+    let bytes = files[0].get_bytes();
+    assert_eq!(bytes.len(), 0x8000);
+    assert_eq!(&bytes[..2], &[0x60, 0xFF]);
+    assert_eq!(&bytes[0x7FFA..], &[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+  }
+
+  #[test]
+  fn check_far_and_big_endian_labels() {
+    let config = configure(CONFIG);
+    let a = assemble_source(
+      "a.s",
+      ".import handler\n.segment \"CODE\"\ntable: .faraddr handler, table\n.dbyt table, handler+1\n",
+      &config,
+    )
+    .unwrap();
+    let b = assemble_source(
+      "b.s",
+      ".export handler\n.segment \"CODE\"\nhandler: nop\n",
+      &config,
+    )
+    .unwrap();
+    let files = link(&[a, b], &config, Path::new("a.out")).unwrap();
+    let bytes = files[0].get_bytes();
+    let expected = [
+      0x0A, 0x80, 0x00, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x0B, 0xEA,
+    ];
+    assert_eq!(&bytes[..11], &expected);
+  }
+
+  #[test]
+  fn check_segment_past_64k() {
+    let config = "MEMORY {\n  BIG: start = $10000, size = $20000, file = %O;\n}\nSEGMENTS {\n  CODE: load = BIG, type = ro;\n}\n";
+    let source = ".segment \"CODE\"\n.res $C000\n.res $C000\nend: .dword end\n";
+    let config = configure(config);
+    let object = assemble_source("a.s", source, &config).unwrap();
+    let files = link(&[object], &config, Path::new("a.out")).unwrap();
+    let bytes = files[0].get_bytes();
+    assert_eq!(bytes.len(), 0x18004);
+    assert_eq!(&bytes[0x18000..], &[0x00, 0x80, 0x02, 0x00]);
+    let small = configure("MEMORY {\n  BIG: start = $10000, size = $10000, file = %O;\n}\nSEGMENTS {\n  CODE: load = BIG, type = ro;\n}\n");
+    let object = assemble_source("a.s", source, &small).unwrap();
+    assert!(link(&[object], &small, Path::new("a.out")).is_err());
+  }
+
+  #[test]
+  fn check_import_in_proc() {
+    let config = configure(CONFIG);
+    let a = assemble_source(
+      "a.s",
+      ".segment \"CODE\"\n.proc main\n.import helper\n  jsr helper\n.endproc\n",
+      &config,
+    )
+    .unwrap();
+    let b = assemble_source(
+      "b.s",
+      ".export helper\n.segment \"CODE\"\nhelper: rts\n",
+      &config,
+    )
+    .unwrap();
+    let files = link(&[a, b], &config, Path::new("a.out")).unwrap();
+    assert_eq!(&files[0].get_bytes()[..4], &[0x20, 0x03, 0x80, 0x60]);
+  }
+
+  #[test]
+  fn check_range_error_location() {
+    let config = configure(CONFIG);
+    let source = ".import big\n.segment \"CODE\"\nnop\n.byte big\n";
+    let a = assemble_source("a.s", source, &config).unwrap();
+    let b = assemble_source("b.s", ".export big\nbig = $1234\n", &config).unwrap();
+    let read = ObjectFile::read("a.o", &a.write()).unwrap();
+    let errors = link(&[a, b], &config, Path::new("a.out")).err().unwrap();
+    let location = errors[0].get_location().unwrap();
+    assert_eq!(location.get_len(), 3);
+    let b = assemble_source("b.s", ".export big\nbig = $1234\n", &config).unwrap();
+    let errors = link(&[read, b], &config, Path::new("a.out")).err().unwrap();
+    assert_eq!(errors[0].get_location().unwrap().get_len(), 3);
+  }
+
+  #[test]
+  fn check_output_files() {
+    let config = configure(
+      "MEMORY {\n  ZP: start = $0000, size = $0100;\n  HDR: start = $0000, size = $0010, file = %O, fill = yes, fillval = $AA;\n  PRG: start = $8000, size = $0100, file = %O;\n  CHR: start = $0000, size = $0008, file = \"chr.bin\", fill = yes;\n}\n\
+       SEGMENTS {\n  HEADER: load = HDR, type = ro;\n  CODE: load = PRG, type = ro;\n  CHARS: load = CHR, type = ro;\n}\n",
+    );
+    let source = ".segment \"HEADER\"\n.byte 1, 2\n.segment \"CODE\"\nnop\nrts\n.segment \"CHARS\"\n.byte $55\n";
+    let object = assemble_source("a.s", source, &config).unwrap();
+    let files = link(&[object], &config, Path::new("a.out")).unwrap();
+    assert_eq!(files.len(), 2);
+    // the header is filled out to its size, the code that follows it isn't
+    assert_eq!(files[0].get_path(), Path::new("a.out"));
+    let mut expected = vec![0x01, 0x02];
+    expected.resize(0x10, 0xAA);
+    expected.extend_from_slice(&[0xEA, 0x60]);
+    assert_eq!(files[0].get_bytes(), &expected);
+    assert_eq!(files[1].get_path(), Path::new("chr.bin"));
+    assert_eq!(files[1].get_bytes(), &vec![0x55, 0, 0, 0, 0, 0, 0, 0]);
+  }
+}
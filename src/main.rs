@@ -1,22 +1,36 @@
 mod char_helper;
 mod common;
 mod configuration;
+mod error;
+mod expression;
 mod generator;
 mod lexer;
+mod linker;
 mod node;
+mod object;
 mod opcode;
+mod options;
 mod parser;
+mod preprocessor;
+#[cfg(test)]
+mod test_helper;
 mod token;
 
-use common::*;
 use configuration::*;
+use error::{AsmError, AsmResult, SourceMap};
 use flexi_logger::{colored_default_format, Duplicate, Logger};
-use generator::generate;
+use generator::assemble;
 use lexer::lex;
+use linker::link;
 use log::*;
 use node::Node;
+use object::ObjectFile;
+use options::{Dump, Options};
 use parser::parse;
+use preprocessor::preprocess;
 use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::time::{Duration, Instant};
 use token::Token;
 
@@ -26,43 +40,256 @@ fn main() {
         .format_for_stdout(colored_default_format)
         .start()
         .unwrap();
-    let input_file = read_to_string("src/data/build.s").expect("File not found");
-    let config_file = read_to_string("src/data/example.cfg").expect("File not found");
-    let tokens = lex_file(&input_file);
-    let tree = parse_file(tokens);
-    generate_file(tree, &config_file);
+    let options = Options::from_args();
+    let mut sources = SourceMap::new();
+    let mut inputs = vec![];
+    let mut errors = vec![];
+    for input in options.get_inputs() {
+        let input_file = read_file(input);
+        if is_object_file(input) {
+            let object = ObjectFile::read(&input.display().to_string(), &input_file)
+                .map_err(|message| vec![AsmError::new(message, None)]);
+            match object {
+                Ok(object) => inputs.push(Input::Object(object)),
+                Err(mut file_errors) => errors.append(&mut file_errors),
+            }
+            continue;
+        }
+        let file_id = sources.add(input, &input_file);
+        let tree = lex_file(&input_file, file_id, input, &options)
+            .map_err(|e| vec![e])
+            .and_then(|tokens| preprocess_file(tokens, &mut sources, &options))
+            .and_then(|tokens| parse_file(tokens, input, &options));
+        match tree {
+            Ok(tree) => inputs.push(Input::Source(input.to_owned(), tree)),
+            Err(mut file_errors) => errors.append(&mut file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        report(errors, &sources, &options);
+    }
+    let config_file = read_file(options.get_config());
+    let file_id = sources.add(options.get_config(), &config_file);
+    let config = configure(&config_file, file_id, &options).map_err(|e| vec![e]);
+    let config = check(config, &sources, &options);
+    let objects = assemble_files(inputs, &config, &options);
+    let objects = check(objects, &sources, &options);
+    if options.is_assemble_only() {
+        for (input, object) in options.get_inputs().iter().zip(objects.iter()) {
+            if !is_object_file(input) {
+                write_file(&options.get_object_path(input), object.write());
+            }
+        }
+        return;
+    }
+    let linked = link_files(&objects, &config, &options);
+    check(linked, &sources, &options);
 }
 
-fn lex_file(file: &String) -> Vec<Token> {
+enum Input {
+    Source(PathBuf, Node<String>),
+    Object(ObjectFile),
+}
+
+fn is_object_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "o")
+}
+
+fn check<T>(result: Result<T, Vec<AsmError>>, sources: &SourceMap, options: &Options) -> T {
+    match result {
+        Ok(value) => value,
+        Err(errors) => report(errors, sources, options),
+    }
+}
+
+// Prints every error in source order against its source and bails out
+fn report(errors: Vec<AsmError>, sources: &SourceMap, options: &Options) -> ! {
+    eprint!(
+        "{}",
+        format_errors(errors, sources, options.get_max_errors())
+    );
+    exit(1);
+}
+
+// Lists at most max errors, then how many there were in all
+fn format_errors(mut errors: Vec<AsmError>, sources: &SourceMap, max: Option<usize>) -> String {
+    errors.sort_by_key(|e| (e.get_location().is_none(), e.get_location().copied()));
+    errors.dedup();
+    let shown = max.unwrap_or(errors.len());
+    let mut out = String::new();
+    for e in errors.iter().take(shown) {
+        out.push_str(&format!("{}\n\n", e.render(sources)));
+    }
+    if errors.len() > shown {
+        let hidden = errors.len() - shown;
+        out.push_str(&format!(
+            "note: {} more {} not shown\n",
+            hidden,
+            plural(hidden, "error")
+        ));
+    }
+    let count = errors.len();
+    out.push_str(&format!(
+        "error: aborting due to {} {}\n",
+        count,
+        plural(count, "error")
+    ));
+    out
+}
+
+fn plural(count: usize, word: &str) -> String {
+    match count {
+        1 => word.to_string(),
+        _ => format!("{}s", word),
+    }
+}
+
+fn read_file(path: &Path) -> String {
+    match read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Unable to read {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) {
+    if let Err(e) = write(path, contents) {
+        error!("Unable to write {}: {}", path.display(), e);
+        exit(1);
+    }
+}
+
+fn dump_tokens(tokens: &[Token], source: &Path, dump: Dump, options: &Options) {
+    if options.should_dump(dump) {
+        let out: Vec<String> = tokens.iter().map(|t| format!("{}", t)).collect();
+        write_file(&options.get_dump_path(source, dump), out.join("\n"));
+    }
+}
+
+fn lex_file(
+    file: &str,
+    file_id: usize,
+    path: &Path,
+    options: &Options,
+) -> AsmResult<Vec<Token>> {
     let lex_start = Instant::now();
-    let tokens = lex(file, true);
+    let tokens = lex(file, file_id, true)?;
     let lex_end = Instant::now();
     log_time("Lexing", lex_end - lex_start);
-    let to_file = tokens.clone();
-    let out: Vec<String> = to_file.iter().map(|t| format!("{}", t)).collect();
-    write("src/out/lexed.out", out.join("\n")).unwrap();
-    tokens
+    dump_tokens(&tokens, path, Dump::Lexed, options);
+    Ok(tokens)
 }
 
-fn parse_file(tokens: Vec<Token>) -> Node<String> {
+fn preprocess_file(
+    tokens: Vec<Token>,
+    sources: &mut SourceMap,
+    options: &Options,
+) -> Result<Vec<Token>, Vec<AsmError>> {
+    let preprocess_start = Instant::now();
+    let tokens = preprocess(tokens, sources, options)?;
+    let preprocess_end = Instant::now();
+    log_time("Preprocessing", preprocess_end - preprocess_start);
+    Ok(tokens)
+}
+
+fn parse_file(
+    tokens: Vec<Token>,
+    path: &Path,
+    options: &Options,
+) -> Result<Node<String>, Vec<AsmError>> {
     let parse_start = Instant::now();
-    let tree = parse(tokens);
+    let tree = parse(tokens)?;
     let parse_end = Instant::now();
     log_time("Parsing", parse_end - parse_start);
-    let to_file = tree.clone();
-    let out = format!("{}", to_file);
-    write("src/out/parsed.out", out).unwrap();
-    tree
+    if options.should_dump(Dump::Parsed) {
+        let out = format!("{}", tree);
+        write_file(&options.get_dump_path(path, Dump::Parsed), out);
+    }
+    Ok(tree)
 }
 
-fn generate_file(tree: Node<String>, config_file: &String) {
-    let generate_start = Instant::now();
-    let generated = generate(tree, &config_file);
-    let generate_end = Instant::now();
-    log_time("Generation", generate_end - generate_start);
-    write("src/out/generated.out", generated).unwrap();
+fn configure(config_file: &str, file_id: usize, options: &Options) -> AsmResult<Configuration> {
+    let tokens = lex(config_file, file_id, false)?;
+    dump_tokens(&tokens, options.get_config(), Dump::ConfigLexed, options);
+    generate_config_data(tokens)
+}
+
+// Each file is assembled on its own, the linker puts them together after
+fn assemble_files(
+    inputs: Vec<Input>,
+    config: &Configuration,
+    options: &Options,
+) -> Result<Vec<ObjectFile>, Vec<AsmError>> {
+    let mut objects = vec![];
+    let mut errors = vec![];
+    for input in inputs {
+        let object = match input {
+            Input::Object(object) => object,
+            Input::Source(path, tree) => {
+                let assemble_start = Instant::now();
+                let name = path.display().to_string();
+                match assemble(tree, &name, config, options) {
+                    Ok(object) => {
+                        log_time("Assembling", Instant::now() - assemble_start);
+                        object
+                    }
+                    Err(mut file_errors) => {
+                        errors.append(&mut file_errors);
+                        continue;
+                    }
+                }
+            }
+        };
+        objects.push(object);
+    }
+    match errors.is_empty() {
+        true => Ok(objects),
+        false => Err(errors),
+    }
+}
+
+fn link_files(
+    objects: &[ObjectFile],
+    config: &Configuration,
+    options: &Options,
+) -> Result<(), Vec<AsmError>> {
+    let link_start = Instant::now();
+    let files = link(objects, config, options.get_output())?;
+    let link_end = Instant::now();
+    log_time("Linking", link_end - link_start);
+    for file in files {
+        write_file(file.get_path(), file.get_bytes());
+    }
+    Ok(())
 }
 
 fn log_time(name: &str, dur: Duration) {
     info!("{} took {} micros", name, dur.as_micros());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helper::{assemble_source, configure, CONFIG};
+
+    #[test]
+    fn check_max_errors() {
+        let source = ".segment \"CODE\"\nlda undefined\nstx $1234,y\n.byte 256\n";
+        let errors = assemble_source("a.s", source, &configure(CONFIG))
+            .err()
+            .unwrap();
+        assert_eq!(errors.len(), 3);
+        let mut sources = SourceMap::new();
+        sources.add(Path::new("a.s"), source);
+        let all = format_errors(errors.clone(), &sources, None);
+        assert_eq!(all.matches("error: ").count(), 4);
+        assert!(all.ends_with("error: aborting due to 3 errors\n"));
+        let limited = format_errors(errors, &sources, Some(1));
+        assert_eq!(limited.matches("-->").count(), 1);
+        assert!(limited.contains("Undefined symbol 'undefined'"));
+        assert!(limited.contains("note: 2 more errors not shown\n"));
+        assert!(limited.ends_with("error: aborting due to 3 errors\n"));
+    }
+}
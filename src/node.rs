@@ -1,4 +1,4 @@
-use crate::token::TokenType;
+use crate::token::{Location, Token, TokenType};
 use std::fmt;
 
 #[derive(Clone, Debug)]
@@ -6,6 +6,7 @@ pub struct Node<T> {
   n_type: NodeType,
   data: Vec<T>,
   children: Vec<Node<T>>,
+  location: Option<Location>,
 }
 
 impl<T> Node<T> {
@@ -14,9 +15,21 @@ impl<T> Node<T> {
       n_type: node_type,
       data: vec![],
       children: vec![],
+      location: None,
     }
   }
 
+  // Creates a node that reports errors at the position of the given token
+  pub fn at(node_type: NodeType, token: &Token) -> Node<T> {
+    let mut node = Node::new(node_type);
+    node.location = Some(*token.get_location());
+    node
+  }
+
+  pub fn get_location(&self) -> Option<&Location> {
+    self.location.as_ref()
+  }
+
   pub fn add_child(&mut self, child: Node<T>) {
     self.children.push(child)
   }
@@ -85,11 +98,13 @@ pub enum NodeType {
   Label,
   LocalLabel,
   UnnamedLabel,
+  ImpliedMode,
   AccumulatorMode,
   ImmediateMode,
   DirectMode,
   DirectRegXMode,
   DirectRegYMode,
+  IndirectMode,
   IndirectXMode,
   IndirectYMode,
   RelativeMode,
@@ -264,8 +279,8 @@ pub enum NodeType {
 }
 
 impl NodeType {
-  pub fn from_token_type(t: &TokenType) -> NodeType {
-    match t {
+  pub fn from_token_type(t: &TokenType) -> Option<NodeType> {
+    let node_type = match t {
       TokenType::DirectiveA16 => NodeType::DirectiveA16,
       TokenType::DirectiveA8 => NodeType::DirectiveA8,
       TokenType::DirectiveAddr => NodeType::DirectiveAddr,
@@ -430,7 +445,8 @@ impl NodeType {
       TokenType::DirectiveXmatch => NodeType::DirectiveXmatch,
       TokenType::DirectiveXor => NodeType::DirectiveXor,
       TokenType::DirectiveZeropage => NodeType::DirectiveZeropage,
-      _ => panic!("Not a directive {:?}", t),
-    }
+      _ => return None,
+    };
+    Some(node_type)
   }
 }
@@ -0,0 +1,466 @@
+use crate::token::Location;
+use std::fmt;
+use std::fmt::Write;
+
+const HEADER: &str = "rusty_axe65 object 3";
+// The sources aren't kept with an object file, so a location read back from
+// one has a line but no file this run knows about
+const UNKNOWN_FILE: usize = usize::MAX;
+const BYTES_PER_LINE: usize = 32;
+
+// Everything assembled from one source file: the bytes it put in each
+// segment, what the linker has to fill in, and the symbols it shares with
+// other files
+pub struct ObjectFile {
+  name: String,
+  segments: Vec<ObjectSegment>,
+  imports: Vec<String>,
+  exports: Vec<Export>,
+}
+
+impl ObjectFile {
+  pub fn new(name: &str) -> ObjectFile {
+    ObjectFile {
+      name: name.to_owned(),
+      segments: vec![],
+      imports: vec![],
+      exports: vec![],
+    }
+  }
+
+  pub fn get_name(&self) -> &String {
+    &self.name
+  }
+
+  pub fn add_segment(&mut self, segment: ObjectSegment) {
+    self.segments.push(segment);
+  }
+
+  pub fn get_segments(&self) -> &Vec<ObjectSegment> {
+    &self.segments
+  }
+
+  pub fn add_import(&mut self, name: &str) {
+    self.imports.push(name.to_owned());
+  }
+
+  pub fn get_imports(&self) -> &Vec<String> {
+    &self.imports
+  }
+
+  pub fn add_export(&mut self, export: Export) {
+    self.exports.push(export);
+  }
+
+  pub fn get_exports(&self) -> &Vec<Export> {
+    &self.exports
+  }
+
+  // <object> ::= <header> { <import> | <export> | <segment> | <data> | <reloc> }
+  // one entry per line, data and relocations belong to the segment above them.
+  // A relocation ends with the line and columns of the source that made it
+  pub fn write(&self) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for import in &self.imports {
+      writeln!(out, "import {}", import).unwrap();
+    }
+    for export in &self.exports {
+      write!(out, "export {} {}", export.name, export.value).unwrap();
+      if let Some(segment) = &export.segment {
+        write!(out, " {}", segment).unwrap();
+      }
+      writeln!(out).unwrap();
+    }
+    for segment in &self.segments {
+      writeln!(
+        out,
+        "segment {} {} {}",
+        segment.name,
+        segment.bytes.len(),
+        segment.align
+      )
+      .unwrap();
+      for chunk in segment.bytes.chunks(BYTES_PER_LINE) {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "data {}", bytes.join(" ")).unwrap();
+      }
+      for reloc in &segment.relocations {
+        write!(
+          out,
+          "reloc {} {} {} {}",
+          reloc.offset,
+          reloc.kind.get_name(),
+          reloc.target.write(),
+          reloc.addend
+        )
+        .unwrap();
+        if let Some(location) = &reloc.location {
+          let end = location.get_start() + location.get_len();
+          write!(
+            out,
+            " {} {} {}",
+            location.get_line(),
+            location.get_start(),
+            end
+          )
+          .unwrap();
+        }
+        writeln!(out).unwrap();
+      }
+    }
+    out
+  }
+
+  pub fn read(name: &str, text: &str) -> Result<ObjectFile, String> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+      Some((_, HEADER)) => (),
+      _ => return Err(format!("{} is not an object file", name)),
+    }
+    let mut object = ObjectFile::new(name);
+    for (index, line) in lines {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      object.read_line(&fields).ok_or_else(|| {
+        format!(
+          "{}:{}: Invalid object file line \"{}\"",
+          name,
+          index + 1,
+          line
+        )
+      })?;
+    }
+    for segment in &object.segments {
+      if segment.bytes.len() != segment.size {
+        return Err(format!(
+          "{}: Segment {} should have {} bytes, found {}",
+          name,
+          segment.name,
+          segment.size,
+          segment.bytes.len()
+        ));
+      }
+      for reloc in &segment.relocations {
+        if reloc.offset as usize + reloc.kind.get_width() > segment.bytes.len() {
+          return Err(format!(
+            "{}: Relocation at offset {} runs past the end of segment {}",
+            name, reloc.offset, segment.name
+          ));
+        }
+      }
+    }
+    Ok(object)
+  }
+
+  fn read_line(&mut self, fields: &[&str]) -> Option<()> {
+    match fields {
+      ["import", name] => self.add_import(name),
+      ["export", name, value] => self.add_export(Export::new(name, value.parse().ok()?, None)),
+      ["export", name, value, segment] => {
+        let export = Export::new(name, value.parse().ok()?, Some(segment.to_string()));
+        self.add_export(export);
+      }
+      ["segment", name, size, align] => {
+        let mut segment = ObjectSegment::new(name);
+        segment.size = size.parse().ok()?;
+        segment.align_to(align.parse().ok()?);
+        self.add_segment(segment);
+      }
+      ["data", bytes @ ..] => {
+        let segment = self.segments.last_mut()?;
+        for byte in bytes {
+          segment.add_value(u8::from_str_radix(byte, 16).ok()?);
+        }
+      }
+      ["reloc", offset, kind, target, name, addend, location @ ..] => {
+        let location = match location {
+          [] => None,
+          [line, start, end] => Some(Location::new(
+            UNKNOWN_FILE,
+            line.parse().ok()?,
+            start.parse().ok()?,
+            end.parse().ok()?,
+          )),
+          _ => return None,
+        };
+        let reloc = Relocation::new(
+          offset.parse().ok()?,
+          RelocKind::from_name(kind)?,
+          RelocTarget::read(target, name)?,
+          addend.parse().ok()?,
+          location,
+        );
+        self.segments.last_mut()?.add_relocation(reloc);
+      }
+      [] => (),
+      _ => return None,
+    }
+    Some(())
+  }
+}
+
+// A file's part of a segment. The linker decides where it goes, starting it
+// on a multiple of the alignment
+pub struct ObjectSegment {
+  name: String,
+  align: u16,
+  bytes: Vec<u8>,
+  relocations: Vec<Relocation>,
+  // only used to check a file that was read back in
+  size: usize,
+}
+
+impl ObjectSegment {
+  pub fn new(name: &str) -> ObjectSegment {
+    ObjectSegment {
+      name: name.to_owned(),
+      align: 1,
+      bytes: vec![],
+      relocations: vec![],
+      size: 0,
+    }
+  }
+
+  pub fn get_name(&self) -> &String {
+    &self.name
+  }
+
+  pub fn align_to(&mut self, align: u16) {
+    self.align = self.align.max(align);
+  }
+
+  pub fn get_align(&self) -> u16 {
+    self.align
+  }
+
+  pub fn add_value(&mut self, byte: u8) {
+    self.bytes.push(byte);
+  }
+
+  pub fn get_values(&self) -> &Vec<u8> {
+    &self.bytes
+  }
+
+  pub fn add_relocation(&mut self, reloc: Relocation) {
+    self.relocations.push(reloc);
+  }
+
+  pub fn get_relocations(&self) -> &Vec<Relocation> {
+    &self.relocations
+  }
+}
+
+// Bytes the linker fills in once it knows where the target is, by adding the
+// addend to it. The location is the operand that asked for it
+pub struct Relocation {
+  offset: u32,
+  kind: RelocKind,
+  target: RelocTarget,
+  addend: i32,
+  location: Option<Location>,
+}
+
+impl Relocation {
+  pub fn new(
+    offset: u32,
+    kind: RelocKind,
+    target: RelocTarget,
+    addend: i32,
+    location: Option<Location>,
+  ) -> Relocation {
+    Relocation {
+      offset,
+      kind,
+      target,
+      addend,
+      location,
+    }
+  }
+
+  pub fn get_offset(&self) -> u32 {
+    self.offset
+  }
+
+  pub fn get_kind(&self) -> RelocKind {
+    self.kind
+  }
+
+  pub fn get_target(&self) -> &RelocTarget {
+    &self.target
+  }
+
+  pub fn get_addend(&self) -> i32 {
+    self.addend
+  }
+
+  pub fn get_location(&self) -> Option<&Location> {
+    self.location.as_ref()
+  }
+}
+
+// What a relocation is relative to: where this file's part of a segment
+// ends up, or a symbol another file exports
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelocTarget {
+  Segment(String),
+  Import(String),
+}
+
+impl RelocTarget {
+  fn read(target: &str, name: &str) -> Option<RelocTarget> {
+    match target {
+      "segment" => Some(RelocTarget::Segment(name.to_owned())),
+      "import" => Some(RelocTarget::Import(name.to_owned())),
+      _ => None,
+    }
+  }
+
+  fn write(&self) -> String {
+    match self {
+      RelocTarget::Segment(name) => format!("segment {}", name),
+      RelocTarget::Import(name) => format!("import {}", name),
+    }
+  }
+}
+
+impl fmt::Display for RelocTarget {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RelocTarget::Segment(name) => write!(f, "The address of segment {}", name),
+      RelocTarget::Import(name) => write!(f, "Imported symbol '{}'", name),
+    }
+  }
+}
+
+// How the value goes in: whole, one byte of it, or as a branch offset from the
+// byte after it. Dbyt is a big endian word
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocKind {
+  Byte,
+  Word,
+  Far,
+  Dbyt,
+  Dword,
+  Lo,
+  Hi,
+  Bank,
+  Relative,
+}
+
+impl RelocKind {
+  fn from_name(name: &str) -> Option<RelocKind> {
+    match name {
+      "byte" => Some(RelocKind::Byte),
+      "word" => Some(RelocKind::Word),
+      "far" => Some(RelocKind::Far),
+      "dbyt" => Some(RelocKind::Dbyt),
+      "dword" => Some(RelocKind::Dword),
+      "lo" => Some(RelocKind::Lo),
+      "hi" => Some(RelocKind::Hi),
+      "bank" => Some(RelocKind::Bank),
+      "relative" => Some(RelocKind::Relative),
+      _ => None,
+    }
+  }
+
+  fn get_name(&self) -> &str {
+    match self {
+      RelocKind::Byte => "byte",
+      RelocKind::Word => "word",
+      RelocKind::Far => "far",
+      RelocKind::Dbyt => "dbyt",
+      RelocKind::Dword => "dword",
+      RelocKind::Lo => "lo",
+      RelocKind::Hi => "hi",
+      RelocKind::Bank => "bank",
+      RelocKind::Relative => "relative",
+    }
+  }
+
+  pub fn get_width(&self) -> usize {
+    match self {
+      RelocKind::Word | RelocKind::Dbyt => 2,
+      RelocKind::Far => 3,
+      RelocKind::Dword => 4,
+      _ => 1,
+    }
+  }
+}
+
+// A label's value is an offset into this file's part of its segment
+pub struct Export {
+  name: String,
+  value: i32,
+  segment: Option<String>,
+}
+
+impl Export {
+  pub fn new(name: &str, value: i32, segment: Option<String>) -> Export {
+    Export {
+      name: name.to_owned(),
+      value,
+      segment,
+    }
+  }
+
+  pub fn get_name(&self) -> &String {
+    &self.name
+  }
+
+  pub fn get_value(&self) -> i32 {
+    self.value
+  }
+
+  pub fn get_segment(&self) -> Option<&String> {
+    self.segment.as_ref()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_round_trip() {
+    let mut object = ObjectFile::new("a.s");
+    object.add_import("far");
+    object.add_export(Export::new("main", 3, Some(String::from("CODE"))));
+    let mut segment = ObjectSegment::new("CODE");
+    segment.align_to(256);
+    for byte in &[0x20, 0x00, 0x00, 0x60] {
+      segment.add_value(*byte);
+    }
+    let far = RelocTarget::Import(String::from("far"));
+    let location = Location::new(0, 4, 6, 9);
+    let reloc = Relocation::new(1, RelocKind::Word, far.clone(), 2, Some(location));
+    segment.add_relocation(reloc);
+    let code = RelocTarget::Segment(String::from("CODE"));
+    segment.add_relocation(Relocation::new(3, RelocKind::Hi, code.clone(), -1, None));
+    object.add_segment(segment);
+    let read = ObjectFile::read("a.o", &object.write()).unwrap();
+    assert_eq!(read.get_imports(), &vec![String::from("far")]);
+    let export = &read.get_exports()[0];
+    assert_eq!(export.get_value(), 3);
+    assert_eq!(export.get_segment(), Some(&String::from("CODE")));
+    let segment = &read.get_segments()[0];
+    assert_eq!(segment.get_align(), 256);
+    assert_eq!(segment.get_values(), &vec![0x20, 0x00, 0x00, 0x60]);
+    let relocs = segment.get_relocations();
+    assert_eq!(relocs[0].get_kind(), RelocKind::Word);
+    assert_eq!(relocs[0].get_target(), &far);
+    assert_eq!(relocs[0].get_addend(), 2);
+    let location = relocs[0].get_location().unwrap();
+    assert_eq!(location.get_line(), 4);
+    assert_eq!(location.get_start(), 6);
+    assert_eq!(location.get_len(), 3);
+    assert_eq!(relocs[1].get_kind(), RelocKind::Hi);
+    assert_eq!(relocs[1].get_target(), &code);
+    assert_eq!(relocs[1].get_addend(), -1);
+    assert!(relocs[1].get_location().is_none());
+    assert!(ObjectFile::read("a.s", "lda #1").is_err());
+    let truncated = format!(
+      "{}\nsegment CODE 2 1\ndata 20 00\nreloc 1 word segment CODE 0\n",
+      HEADER
+    );
+    assert!(ObjectFile::read("a.o", &truncated).is_err());
+  }
+}
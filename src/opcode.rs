@@ -1,250 +1,305 @@
-pub fn is_opcode(t: &str) -> bool {
-  match t.to_ascii_lowercase().as_ref() {
-    "adc" | "and" | "asl" | "bcc" | "bcs" | "beq" | "bit" | "bmi" | "bne" | "bpl" | "brk"
-    | "bvc" | "bvs" | "clc" | "cld" | "cli" | "clv" | "cmp" | "cpx" | "cpy" | "dec" | "dex"
-    | "dey" | "eor" | "inc" | "inx" | "iny" | "jmp" | "jsr" | "lda" | "ldx" | "ldy" | "lsr"
-    | "nop" | "ora" | "pha" | "php" | "pla" | "plp" | "rol" | "ror" | "rti" | "rts" | "sbc"
-    | "sec" | "sed" | "sei" | "sta" | "stx" | "sty" | "tax" | "tay" | "tsx" | "txa" | "txs"
-    | "tya" => true,
-    _ => false,
-  }
+use self::AddressingMode::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressingMode {
+  Implied,
+  Accumulator,
+  Immediate,
+  ZeroPage,
+  ZeroPageX,
+  ZeroPageY,
+  Absolute,
+  AbsoluteX,
+  AbsoluteY,
+  Indirect,
+  IndirectX,
+  IndirectY,
+  Relative,
 }
 
-pub fn is_accumulator(code: &str) -> bool {
-  match code.to_ascii_lowercase().as_str() {
-    "asl" | "brk" | "clc" | "cld" | "cli" | "clv" | "dex" | "dey" | "inx" | "iny" | "lsr"
-    | "pha" | "pla" | "php" | "plp" | "nop" | "rol" | "ror" | "rti" | "rts" | "sec" | "sed"
-    | "sei" | "tax" | "tay" | "tsx" | "txa" | "txs" | "tya" => true,
-    _ => false,
+impl AddressingMode {
+  // Size in bytes of an instruction using this mode, opcode included
+  pub fn get_length(&self) -> u8 {
+    match self {
+      Implied | Accumulator => 1,
+      Absolute | AbsoluteX | AbsoluteY | Indirect => 3,
+      _ => 2,
+    }
   }
 }
 
-pub fn is_branch(code: &str) -> bool {
-  match code.to_ascii_lowercase().as_str() {
-    "bcc" | "bcs" | "beq" | "bmi" | "bne" | "bpl" | "bvc" | "bvs" => true,
-    _ => false,
-  }
+#[derive(Debug, PartialEq, Eq)]
+pub struct Instruction {
+  mnemonic: &'static str,
+  mode: AddressingMode,
+  opcode: u8,
+  cycles: u8,
 }
 
-pub fn get_accumulator(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "asl" => 0x0A,
-    "brk" => 0x00,
-    "clc" => 0x18,
-    "cld" => 0xD8,
-    "cli" => 0x58,
-    "clv" => 0xB8,
-    "dex" => 0xCA,
-    "dey" => 0x88,
-    "inx" => 0xE8,
-    "iny" => 0xC8,
-    "lsr" => 0x4A,
-    "pha" => 0x48,
-    "pla" => 0x68,
-    "php" => 0x08,
-    "plp" => 0x28,
-    "nop" => 0xEA,
-    "rol" => 0x2A,
-    "ror" => 0x6A,
-    "rti" => 0x40,
-    "rts" => 0x60,
-    "sec" => 0x38,
-    "sed" => 0xF8,
-    "sei" => 0x78,
-    "tax" => 0xAA,
-    "tay" => 0xA8,
-    "tsx" => 0xBA,
-    "txa" => 0x8A,
-    "txs" => 0x9A,
-    "tya" => 0x98,
-    _ => panic!("Not an accumulator opcode {}", code),
+impl Instruction {
+  const fn new(mnemonic: &'static str, mode: AddressingMode, opcode: u8, cycles: u8) -> Self {
+    Instruction {
+      mnemonic,
+      mode,
+      opcode,
+      cycles,
+    }
   }
-}
 
-pub fn get_immediate(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x69,
-    "and" => 0x29,
-    "cmp" => 0xC9,
-    "cpx" => 0xE0,
-    "cpy" => 0xC0,
-    "eor" => 0x49,
-    "lda" => 0xA9,
-    "ldx" => 0xA2,
-    "ldy" => 0xA0,
-    "ora" => 0x09,
-    "sbc" => 0xE9,
-    _ => panic!("{} does not have an immediate address mode", code),
+  #[allow(dead_code)]
+  pub fn get_mnemonic(&self) -> &'static str {
+    self.mnemonic
   }
-}
 
-pub fn get_absolute(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x6D,
-    "and" => 0x2D,
-    "asl" => 0x0E,
-    "bit" => 0x2C,
-    "cmp" => 0xCD,
-    "cpx" => 0xEC,
-    "cpy" => 0xCC,
-    "dec" => 0xCE,
-    "eor" => 0x4D,
-    "inc" => 0xEE,
-    "jmp" => 0x4C,
-    "jsr" => 0x20,
-    "lda" => 0xAD,
-    "ldx" => 0xAE,
-    "ldy" => 0xAC,
-    "lsr" => 0x4E,
-    "ora" => 0x0D,
-    "rol" => 0x2E,
-    "ror" => 0x6E,
-    "sbc" => 0xED,
-    "sta" => 0x8D,
-    "stx" => 0x8E,
-    "sty" => 0x8C,
-    _ => panic!("{} does not have an absolute address mode", code),
+  #[allow(dead_code)]
+  pub fn get_mode(&self) -> AddressingMode {
+    self.mode
   }
-}
 
-pub fn get_absolute_x(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x7D,
-    "and" => 0x3D,
-    "asl" => 0x1E,
-    "cmp" => 0xDD,
-    "dec" => 0xDE,
-    "eor" => 0x5D,
-    "inc" => 0xFE,
-    "lda" => 0xBD,
-    "ldx" => 0xBE,
-    "ldy" => 0xBC,
-    "lsr" => 0x5E,
-    "ora" => 0x1D,
-    "rol" => 0x3E,
-    "ror" => 0x7E,
-    "sbc" => 0xFD,
-    "sta" => 0x9D,
-    _ => panic!("{} does not have an absolute x address mode", code),
+  pub fn get_opcode(&self) -> u8 {
+    self.opcode
   }
-}
 
-pub fn get_absolute_y(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x79,
-    "and" => 0x39,
-    "cmp" => 0xD9,
-    "eor" => 0x59,
-    "lda" => 0xB9,
-    "ora" => 0x19,
-    "sbc" => 0xF9,
-    "sta" => 0x99,
-    _ => panic!("{} does not have an absolute y address mode", code),
+  pub fn get_length(&self) -> u8 {
+    self.mode.get_length()
   }
-}
 
-pub fn get_zero_page(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x65,
-    "and" => 0x25,
-    "asl" => 0x06,
-    "bit" => 0x24,
-    "cmp" => 0xC5,
-    "cpx" => 0xE4,
-    "cpy" => 0xC4,
-    "dec" => 0xC6,
-    "eor" => 0x45,
-    "inc" => 0xE6,
-    "lda" => 0xA5,
-    "ldx" => 0xA6,
-    "ldy" => 0xA4,
-    "lsr" => 0x46,
-    "ora" => 0x05,
-    "rol" => 0x26,
-    "ror" => 0x36,
-    "sbc" => 0xE5,
-    "sta" => 0x85,
-    "stx" => 0x86,
-    "sty" => 0x84,
-    _ => panic!("{} does not have a zero page address mode", code),
+  // Base cycle count, before page crossing or branch taken penalties
+  #[allow(dead_code)]
+  pub fn get_cycles(&self) -> u8 {
+    self.cycles
   }
 }
 
-pub fn get_zero_page_x(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x75,
-    "and" => 0x35,
-    "asl" => 0x16,
-    "cmp" => 0xD5,
-    "dec" => 0xD6,
-    "eor" => 0x55,
-    "inc" => 0xF6,
-    "lda" => 0xB5,
-    "ldy" => 0xB4,
-    "lsr" => 0x56,
-    "ora" => 0x15,
-    "rol" => 0x66,
-    "ror" => 0x76,
-    "sbc" => 0xF5,
-    "sta" => 0x95,
-    "sty" => 0x94,
-    _ => panic!("{} does not have a zero page address mode", code),
-  }
+static INSTRUCTIONS: &[Instruction] = &[
+  Instruction::new("adc", Immediate, 0x69, 2),
+  Instruction::new("adc", ZeroPage, 0x65, 3),
+  Instruction::new("adc", ZeroPageX, 0x75, 4),
+  Instruction::new("adc", Absolute, 0x6D, 4),
+  Instruction::new("adc", AbsoluteX, 0x7D, 4),
+  Instruction::new("adc", AbsoluteY, 0x79, 4),
+  Instruction::new("adc", IndirectX, 0x61, 6),
+  Instruction::new("adc", IndirectY, 0x71, 5),
+  Instruction::new("and", Immediate, 0x29, 2),
+  Instruction::new("and", ZeroPage, 0x25, 3),
+  Instruction::new("and", ZeroPageX, 0x35, 4),
+  Instruction::new("and", Absolute, 0x2D, 4),
+  Instruction::new("and", AbsoluteX, 0x3D, 4),
+  Instruction::new("and", AbsoluteY, 0x39, 4),
+  Instruction::new("and", IndirectX, 0x21, 6),
+  Instruction::new("and", IndirectY, 0x31, 5),
+  Instruction::new("asl", Accumulator, 0x0A, 2),
+  Instruction::new("asl", ZeroPage, 0x06, 5),
+  Instruction::new("asl", ZeroPageX, 0x16, 6),
+  Instruction::new("asl", Absolute, 0x0E, 6),
+  Instruction::new("asl", AbsoluteX, 0x1E, 7),
+  Instruction::new("bcc", Relative, 0x90, 2),
+  Instruction::new("bcs", Relative, 0xB0, 2),
+  Instruction::new("beq", Relative, 0xF0, 2),
+  Instruction::new("bit", ZeroPage, 0x24, 3),
+  Instruction::new("bit", Absolute, 0x2C, 4),
+  Instruction::new("bmi", Relative, 0x30, 2),
+  Instruction::new("bne", Relative, 0xD0, 2),
+  Instruction::new("bpl", Relative, 0x10, 2),
+  Instruction::new("brk", Implied, 0x00, 7),
+  Instruction::new("bvc", Relative, 0x50, 2),
+  Instruction::new("bvs", Relative, 0x70, 2),
+  Instruction::new("clc", Implied, 0x18, 2),
+  Instruction::new("cld", Implied, 0xD8, 2),
+  Instruction::new("cli", Implied, 0x58, 2),
+  Instruction::new("clv", Implied, 0xB8, 2),
+  Instruction::new("cmp", Immediate, 0xC9, 2),
+  Instruction::new("cmp", ZeroPage, 0xC5, 3),
+  Instruction::new("cmp", ZeroPageX, 0xD5, 4),
+  Instruction::new("cmp", Absolute, 0xCD, 4),
+  Instruction::new("cmp", AbsoluteX, 0xDD, 4),
+  Instruction::new("cmp", AbsoluteY, 0xD9, 4),
+  Instruction::new("cmp", IndirectX, 0xC1, 6),
+  Instruction::new("cmp", IndirectY, 0xD1, 5),
+  Instruction::new("cpx", Immediate, 0xE0, 2),
+  Instruction::new("cpx", ZeroPage, 0xE4, 3),
+  Instruction::new("cpx", Absolute, 0xEC, 4),
+  Instruction::new("cpy", Immediate, 0xC0, 2),
+  Instruction::new("cpy", ZeroPage, 0xC4, 3),
+  Instruction::new("cpy", Absolute, 0xCC, 4),
+  Instruction::new("dec", ZeroPage, 0xC6, 5),
+  Instruction::new("dec", ZeroPageX, 0xD6, 6),
+  Instruction::new("dec", Absolute, 0xCE, 6),
+  Instruction::new("dec", AbsoluteX, 0xDE, 7),
+  Instruction::new("dex", Implied, 0xCA, 2),
+  Instruction::new("dey", Implied, 0x88, 2),
+  Instruction::new("eor", Immediate, 0x49, 2),
+  Instruction::new("eor", ZeroPage, 0x45, 3),
+  Instruction::new("eor", ZeroPageX, 0x55, 4),
+  Instruction::new("eor", Absolute, 0x4D, 4),
+  Instruction::new("eor", AbsoluteX, 0x5D, 4),
+  Instruction::new("eor", AbsoluteY, 0x59, 4),
+  Instruction::new("eor", IndirectX, 0x41, 6),
+  Instruction::new("eor", IndirectY, 0x51, 5),
+  Instruction::new("inc", ZeroPage, 0xE6, 5),
+  Instruction::new("inc", ZeroPageX, 0xF6, 6),
+  Instruction::new("inc", Absolute, 0xEE, 6),
+  Instruction::new("inc", AbsoluteX, 0xFE, 7),
+  Instruction::new("inx", Implied, 0xE8, 2),
+  Instruction::new("iny", Implied, 0xC8, 2),
+  Instruction::new("jmp", Absolute, 0x4C, 3),
+  Instruction::new("jmp", Indirect, 0x6C, 5),
+  Instruction::new("jsr", Absolute, 0x20, 6),
+  Instruction::new("lda", Immediate, 0xA9, 2),
+  Instruction::new("lda", ZeroPage, 0xA5, 3),
+  Instruction::new("lda", ZeroPageX, 0xB5, 4),
+  Instruction::new("lda", Absolute, 0xAD, 4),
+  Instruction::new("lda", AbsoluteX, 0xBD, 4),
+  Instruction::new("lda", AbsoluteY, 0xB9, 4),
+  Instruction::new("lda", IndirectX, 0xA1, 6),
+  Instruction::new("lda", IndirectY, 0xB1, 5),
+  Instruction::new("ldx", Immediate, 0xA2, 2),
+  Instruction::new("ldx", ZeroPage, 0xA6, 3),
+  Instruction::new("ldx", ZeroPageY, 0xB6, 4),
+  Instruction::new("ldx", Absolute, 0xAE, 4),
+  Instruction::new("ldx", AbsoluteY, 0xBE, 4),
+  Instruction::new("ldy", Immediate, 0xA0, 2),
+  Instruction::new("ldy", ZeroPage, 0xA4, 3),
+  Instruction::new("ldy", ZeroPageX, 0xB4, 4),
+  Instruction::new("ldy", Absolute, 0xAC, 4),
+  Instruction::new("ldy", AbsoluteX, 0xBC, 4),
+  Instruction::new("lsr", Accumulator, 0x4A, 2),
+  Instruction::new("lsr", ZeroPage, 0x46, 5),
+  Instruction::new("lsr", ZeroPageX, 0x56, 6),
+  Instruction::new("lsr", Absolute, 0x4E, 6),
+  Instruction::new("lsr", AbsoluteX, 0x5E, 7),
+  Instruction::new("nop", Implied, 0xEA, 2),
+  Instruction::new("ora", Immediate, 0x09, 2),
+  Instruction::new("ora", ZeroPage, 0x05, 3),
+  Instruction::new("ora", ZeroPageX, 0x15, 4),
+  Instruction::new("ora", Absolute, 0x0D, 4),
+  Instruction::new("ora", AbsoluteX, 0x1D, 4),
+  Instruction::new("ora", AbsoluteY, 0x19, 4),
+  Instruction::new("ora", IndirectX, 0x01, 6),
+  Instruction::new("ora", IndirectY, 0x11, 5),
+  Instruction::new("pha", Implied, 0x48, 3),
+  Instruction::new("php", Implied, 0x08, 3),
+  Instruction::new("pla", Implied, 0x68, 4),
+  Instruction::new("plp", Implied, 0x28, 4),
+  Instruction::new("rol", Accumulator, 0x2A, 2),
+  Instruction::new("rol", ZeroPage, 0x26, 5),
+  Instruction::new("rol", ZeroPageX, 0x36, 6),
+  Instruction::new("rol", Absolute, 0x2E, 6),
+  Instruction::new("rol", AbsoluteX, 0x3E, 7),
+  Instruction::new("ror", Accumulator, 0x6A, 2),
+  Instruction::new("ror", ZeroPage, 0x66, 5),
+  Instruction::new("ror", ZeroPageX, 0x76, 6),
+  Instruction::new("ror", Absolute, 0x6E, 6),
+  Instruction::new("ror", AbsoluteX, 0x7E, 7),
+  Instruction::new("rti", Implied, 0x40, 6),
+  Instruction::new("rts", Implied, 0x60, 6),
+  Instruction::new("sbc", Immediate, 0xE9, 2),
+  Instruction::new("sbc", ZeroPage, 0xE5, 3),
+  Instruction::new("sbc", ZeroPageX, 0xF5, 4),
+  Instruction::new("sbc", Absolute, 0xED, 4),
+  Instruction::new("sbc", AbsoluteX, 0xFD, 4),
+  Instruction::new("sbc", AbsoluteY, 0xF9, 4),
+  Instruction::new("sbc", IndirectX, 0xE1, 6),
+  Instruction::new("sbc", IndirectY, 0xF1, 5),
+  Instruction::new("sec", Implied, 0x38, 2),
+  Instruction::new("sed", Implied, 0xF8, 2),
+  Instruction::new("sei", Implied, 0x78, 2),
+  Instruction::new("sta", ZeroPage, 0x85, 3),
+  Instruction::new("sta", ZeroPageX, 0x95, 4),
+  Instruction::new("sta", Absolute, 0x8D, 4),
+  Instruction::new("sta", AbsoluteX, 0x9D, 5),
+  Instruction::new("sta", AbsoluteY, 0x99, 5),
+  Instruction::new("sta", IndirectX, 0x81, 6),
+  Instruction::new("sta", IndirectY, 0x91, 6),
+  Instruction::new("stx", ZeroPage, 0x86, 3),
+  Instruction::new("stx", ZeroPageY, 0x96, 4),
+  Instruction::new("stx", Absolute, 0x8E, 4),
+  Instruction::new("sty", ZeroPage, 0x84, 3),
+  Instruction::new("sty", ZeroPageX, 0x94, 4),
+  Instruction::new("sty", Absolute, 0x8C, 4),
+  Instruction::new("tax", Implied, 0xAA, 2),
+  Instruction::new("tay", Implied, 0xA8, 2),
+  Instruction::new("tsx", Implied, 0xBA, 2),
+  Instruction::new("txa", Implied, 0x8A, 2),
+  Instruction::new("txs", Implied, 0x9A, 2),
+  Instruction::new("tya", Implied, 0x98, 2),
+];
+
+pub fn find(mnemonic: &str, mode: AddressingMode) -> Option<&'static Instruction> {
+  INSTRUCTIONS
+    .iter()
+    .find(|i| i.mode == mode && i.mnemonic.eq_ignore_ascii_case(mnemonic))
 }
 
-pub fn get_zero_page_y(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "ldx" => 0xB6,
-    "stx" => 0x96,
-    _ => panic!("{} does not have a zero page address mode", code),
-  }
+pub fn encode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+  find(mnemonic, mode).map(Instruction::get_opcode)
 }
 
-pub fn get_relative(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "bcc" => 0x90,
-    "bcs" => 0xB0,
-    "beq" => 0xF0,
-    "bmi" => 0x30,
-    "bne" => 0xD0,
-    "bpl" => 0x10,
-    "bvc" => 0x50,
-    "bvs" => 0x70,
-    _ => panic!("{} does not have a relative address mode", code),
-  }
+// For the disassembler and cycle counter, nothing in the assembler needs it
+#[allow(dead_code)]
+pub fn decode(opcode: u8) -> Option<&'static Instruction> {
+  INSTRUCTIONS.iter().find(|i| i.opcode == opcode)
 }
 
-pub fn _get_indirect(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "jmp" => 0x6C,
-    _ => panic!("{} does not have an indirect address mode", code),
-  }
+pub fn has_mode(mnemonic: &str, mode: AddressingMode) -> bool {
+  find(mnemonic, mode).is_some()
 }
 
-pub fn _get_indirect_x(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x61,
-    "and" => 0x21,
-    "cmp" => 0xC1,
-    "eor" => 0x41,
-    "lda" => 0xA1,
-    "ora" => 0x01,
-    "sbc" => 0xE1,
-    "sta" => 0x81,
-    _ => panic!("{} does not have an indirect x address mode", code),
-  }
+pub fn is_opcode(t: &str) -> bool {
+  INSTRUCTIONS
+    .iter()
+    .any(|i| i.mnemonic.eq_ignore_ascii_case(t))
+}
+
+pub fn is_branch(code: &str) -> bool {
+  has_mode(code, Relative)
+}
+
+// The branch taken on the opposite condition
+pub fn invert_branch(code: &str) -> Option<&'static str> {
+  let inverted = match code.to_ascii_lowercase().as_str() {
+    "bpl" => "bmi",
+    "bmi" => "bpl",
+    "bvc" => "bvs",
+    "bvs" => "bvc",
+    "bcc" => "bcs",
+    "bcs" => "bcc",
+    "bne" => "beq",
+    "beq" => "bne",
+    _ => return None,
+  };
+  Some(inverted)
 }
 
-pub fn _get_indirect_y(code: &str) -> u8 {
-  match code.to_ascii_lowercase().as_str() {
-    "adc" => 0x71,
-    "and" => 0x31,
-    "cmp" => 0xD1,
-    "eor" => 0x51,
-    "lda" => 0xB1,
-    "ora" => 0x11,
-    "sbc" => 0xF1,
-    "sta" => 0x91,
-    _ => panic!("{} does not have an indirect y address mode", code),
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_encode_decode() {
+    assert_eq!(encode("LDA", IndirectY), Some(0xB1));
+    assert_eq!(encode("jmp", Indirect), Some(0x6C));
+    assert_eq!(encode("stx", AbsoluteY), None);
+    assert_eq!(
+      find("asl", Accumulator).map(Instruction::get_length),
+      Some(1)
+    );
+    assert_eq!(decode(0x6C).map(Instruction::get_cycles), Some(5));
+    // every opcode byte belongs to one instruction
+    for instruction in INSTRUCTIONS {
+      let decoded = decode(instruction.get_opcode()).unwrap();
+      assert_eq!(decoded, instruction);
+      assert_eq!(decoded.get_mnemonic(), instruction.mnemonic);
+      assert_eq!(decoded.get_mode(), instruction.mode);
+    }
+  }
+
+  #[test]
+  fn check_invert_branch() {
+    for instruction in INSTRUCTIONS.iter().filter(|i| i.mode == Relative) {
+      let inverted = invert_branch(instruction.mnemonic).unwrap();
+      assert_eq!(invert_branch(inverted), Some(instruction.mnemonic));
+    }
+    assert_eq!(invert_branch("jmp"), None);
   }
 }
@@ -0,0 +1,301 @@
+use clap::{crate_version, App, Arg, ArgMatches};
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct Options {
+  inputs: Vec<PathBuf>,
+  config: PathBuf,
+  output: PathBuf,
+  include_dirs: Vec<PathBuf>,
+  bin_include_dirs: Vec<PathBuf>,
+  defines: Vec<(String, i32)>,
+  dumps: Vec<Dump>,
+  max_errors: Option<usize>,
+  long_branches: bool,
+  assemble_only: bool,
+}
+
+impl Options {
+  pub fn from_args() -> Options {
+    let matches = App::new("rusty_axe65")
+      .version(crate_version!())
+      .about("Assembler for the 6502 processor")
+      .arg(
+        Arg::with_name("input")
+          .help("Source files to assemble, and object files to link with them")
+          .value_name("INPUT")
+          .required(true)
+          .multiple(true),
+      )
+      .arg(
+        Arg::with_name("config")
+          .help("Linker configuration file")
+          .short("C")
+          .long("config")
+          .value_name("FILE")
+          .takes_value(true)
+          .required(true),
+      )
+      .arg(
+        Arg::with_name("output")
+          .help("Output file")
+          .short("o")
+          .long("output")
+          .value_name("FILE")
+          .takes_value(true)
+          .default_value("a.out"),
+      )
+      .arg(
+        Arg::with_name("include_dir")
+          .help("Directory to search for included files")
+          .short("I")
+          .long("include-dir")
+          .value_name("DIR")
+          .takes_value(true)
+          .number_of_values(1)
+          .multiple(true),
+      )
+      .arg(
+        Arg::with_name("bin_include_dir")
+          .help("Directory to search for files included with .incbin")
+          .long("bin-include-dir")
+          .value_name("DIR")
+          .takes_value(true)
+          .number_of_values(1)
+          .multiple(true),
+      )
+      .arg(
+        Arg::with_name("define")
+          .help("Define a symbol, with an optional value (defaults to 1)")
+          .short("D")
+          .long("define")
+          .value_name("SYM[=VALUE]")
+          .takes_value(true)
+          .number_of_values(1)
+          .multiple(true)
+          .validator(|d| parse_define(&d).map(|_| ())),
+      )
+      .arg(
+        Arg::with_name("dump")
+          .help("Write intermediate results next to the output file")
+          .long("dump")
+          .value_name("STAGE")
+          .takes_value(true)
+          .multiple(true)
+          .use_delimiter(true)
+          .possible_values(&["lexed", "parsed", "config_lexed"]),
+      )
+      .arg(
+        Arg::with_name("max_errors")
+          .help("Stop listing errors after this many")
+          .long("max-errors")
+          .value_name("N")
+          .takes_value(true)
+          .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+      )
+      .arg(
+        Arg::with_name("long_branches")
+          .help("Rewrite out of range branches into an inverted branch over a JMP")
+          .long("long-branches"),
+      )
+      .arg(
+        Arg::with_name("assemble_only")
+          .help("Write an object file next to each source file instead of linking")
+          .short("c")
+          .long("assemble-only"),
+      )
+      .get_matches();
+    Options::from_matches(&matches)
+  }
+
+  fn from_matches(matches: &ArgMatches) -> Options {
+    let paths = |name| -> Vec<PathBuf> {
+      match matches.values_of(name) {
+        Some(values) => values.map(PathBuf::from).collect(),
+        None => vec![],
+      }
+    };
+    let defines = match matches.values_of("define") {
+      Some(values) => values.map(|d| parse_define(d).unwrap()).collect(),
+      None => vec![],
+    };
+    let dumps = match matches.values_of("dump") {
+      Some(values) => values.map(Dump::from_str).collect(),
+      None => vec![],
+    };
+    Options {
+      inputs: paths("input"),
+      config: PathBuf::from(matches.value_of("config").unwrap()),
+      output: PathBuf::from(matches.value_of("output").unwrap()),
+      include_dirs: paths("include_dir"),
+      bin_include_dirs: paths("bin_include_dir"),
+      defines,
+      dumps,
+      max_errors: matches.value_of("max_errors").map(|n| n.parse().unwrap()),
+      long_branches: matches.is_present("long_branches"),
+      assemble_only: matches.is_present("assemble_only"),
+    }
+  }
+
+  pub fn get_inputs(&self) -> &Vec<PathBuf> {
+    &self.inputs
+  }
+
+  pub fn get_config(&self) -> &PathBuf {
+    &self.config
+  }
+
+  pub fn get_output(&self) -> &PathBuf {
+    &self.output
+  }
+
+  pub fn get_defines(&self) -> &Vec<(String, i32)> {
+    &self.defines
+  }
+
+  pub fn get_max_errors(&self) -> Option<usize> {
+    self.max_errors
+  }
+
+  pub fn use_long_branches(&self) -> bool {
+    self.long_branches
+  }
+
+  pub fn is_assemble_only(&self) -> bool {
+    self.assemble_only
+  }
+
+  pub fn get_object_path(&self, source: &Path) -> PathBuf {
+    source.with_extension("o")
+  }
+
+  pub fn should_dump(&self, dump: Dump) -> bool {
+    self.dumps.contains(&dump)
+  }
+
+  // Dumps are written alongside the output file, named after the file they came from
+  pub fn get_dump_path(&self, source: &Path, dump: Dump) -> PathBuf {
+    let file_name = source.file_name().unwrap_or_default().to_string_lossy();
+    let dump_name = format!("{}.{}.out", file_name, dump.get_suffix());
+    match self.output.parent() {
+      Some(dir) => dir.join(dump_name),
+      None => PathBuf::from(dump_name),
+    }
+  }
+
+  // Checks next to the including file, then each include directory in the
+  // order given, then falls back to the path as written (relative to the
+  // working directory)
+  pub fn find_include(&self, name: &str, from: &Path) -> Option<PathBuf> {
+    find_file(name, from, self.include_dirs.iter())
+  }
+
+  // Binary includes look in their own directories before the source ones
+  pub fn find_bin_include(&self, name: &str, from: &Path) -> Option<PathBuf> {
+    let dirs = self.bin_include_dirs.iter().chain(self.include_dirs.iter());
+    find_file(name, from, dirs)
+  }
+}
+
+fn find_file<'d>(
+  name: &str,
+  from: &Path,
+  dirs: impl Iterator<Item = &'d PathBuf>,
+) -> Option<PathBuf> {
+  let beside = from.parent().map(|dir| dir.join(name));
+  beside
+    .into_iter()
+    .chain(dirs.map(|dir| dir.join(name)))
+    .chain(std::iter::once(PathBuf::from(name)))
+    .find(|path| path.is_file())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dump {
+  Lexed,
+  Parsed,
+  ConfigLexed,
+}
+
+impl Dump {
+  fn from_str(value: &str) -> Dump {
+    match value {
+      "lexed" => Dump::Lexed,
+      "parsed" => Dump::Parsed,
+      "config_lexed" => Dump::ConfigLexed,
+      _ => panic!("Invalid dump stage {}", value),
+    }
+  }
+
+  fn get_suffix(&self) -> &str {
+    match self {
+      Dump::Lexed => "lexed",
+      Dump::Parsed => "parsed",
+      Dump::ConfigLexed => "lexed",
+    }
+  }
+}
+
+// <define> ::= <id> [ "=" <number> ]
+fn parse_define(define: &str) -> Result<(String, i32), String> {
+  let mut parts = define.splitn(2, '=');
+  let name = parts.next().unwrap_or_default();
+  let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+  if !valid_name {
+    return Err(format!("Invalid symbol name in define '{}'", define));
+  }
+  let value = match parts.next() {
+    Some(v) => parse_number(v).ok_or(format!("Invalid value in define '{}'", define))?,
+    None => 1,
+  };
+  Ok((name.to_owned(), value))
+}
+
+// Numbers are written the same way as in source, with a leading - for
+// negative ones
+fn parse_number(value: &str) -> Option<i32> {
+  let (sign, digits) = match value.strip_prefix('-') {
+    Some(digits) => (-1, digits),
+    None => (1, value),
+  };
+  let result = match digits.chars().next() {
+    Some('$') => u32::from_str_radix(&digits[1..], 16),
+    Some('%') => u32::from_str_radix(&digits[1..], 2),
+    _ => digits.parse(),
+  };
+  result.ok().map(|number| (number as i32).wrapping_mul(sign))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs::{create_dir_all, remove_dir_all, write};
+
+  #[test]
+  fn check_find_include() {
+    let dir = std::env::temp_dir().join("rusty_axe65_check_find_include");
+    for sub in &["src", "lib", "bin"] {
+      create_dir_all(dir.join(sub)).unwrap();
+    }
+    write(dir.join("src/both.s"), "").unwrap();
+    write(dir.join("lib/both.s"), "").unwrap();
+    write(dir.join("lib/lib.s"), "").unwrap();
+    write(dir.join("bin/lib.s"), "").unwrap();
+    let options = Options {
+      include_dirs: vec![dir.join("lib")],
+      bin_include_dirs: vec![dir.join("bin")],
+      ..Options::default()
+    };
+    let from = dir.join("src/main.s");
+    let beside = options.find_include("both.s", &from);
+    let searched = options.find_include("lib.s", &from);
+    let binary = options.find_bin_include("lib.s", &from);
+    let missing = options.find_include("missing.s", &from);
+    remove_dir_all(&dir).unwrap();
+    assert_eq!(beside, Some(dir.join("src/both.s")));
+    assert_eq!(searched, Some(dir.join("lib/lib.s")));
+    assert_eq!(binary, Some(dir.join("bin/lib.s")));
+    assert_eq!(missing, None);
+  }
+}
@@ -1,21 +1,235 @@
 use crate::common::*;
+use crate::error::{AsmError, AsmResult};
 use crate::node::{Node, NodeType};
 use crate::opcode::*;
 use crate::token::{Token, TokenType};
 
-// <program> ::= { <statement> }
-pub fn parse(mut tokens: Vec<Token>) -> Node<String> {
+// <program> ::= { <line> }
+pub fn parse(mut tokens: Vec<Token>) -> Result<Node<String>, Vec<AsmError>> {
   let mut program_tree = Node::new(NodeType::Program);
+  let mut errors = vec![];
   let mut next = peek_next_token(&tokens);
   while next.get_type() != &TokenType::EndOfFile {
-    program_tree.add_child(parse_statement(&mut tokens));
+    // a bad statement only costs us the rest of its line, or the rest of the
+    // definition it is in
+    let result = match next.get_type() {
+      TokenType::DirectiveStruct | TokenType::DirectiveUnion | TokenType::DirectiveEnum => {
+        let mut block = take_block(&mut tokens);
+        parse_definition(&mut block, &mut program_tree)
+      }
+      _ => {
+        let mut line = take_line(&mut tokens);
+        parse_line(&mut line, &mut program_tree)
+      }
+    };
+    if let Err(e) = result {
+      errors.push(e);
+    }
     next = peek_next_token(&tokens);
   }
-  program_tree
+  match errors.is_empty() {
+    true => Ok(program_tree),
+    false => Err(errors),
+  }
+}
+
+// Splits off the tokens up to and including the next newline
+fn take_line(tokens: &mut Vec<Token>) -> Vec<Token> {
+  let len = tokens
+    .iter()
+    .position(|t| t.get_type() == &TokenType::Newline)
+    .map_or(tokens.len(), |i| i + 1);
+  tokens.drain(..len).collect()
+}
+
+// Splits off the lines up to and including the one that closes the definition
+// starting here, or up to the end of the file if nothing does
+fn take_block(tokens: &mut Vec<Token>) -> Vec<Token> {
+  let mut block = vec![];
+  let mut depth = 0;
+  loop {
+    let line = take_line(tokens);
+    for token in line.iter() {
+      match token.get_type() {
+        TokenType::DirectiveStruct | TokenType::DirectiveUnion | TokenType::DirectiveEnum => {
+          depth += 1
+        }
+        TokenType::DirectiveEndstruct
+        | TokenType::DirectiveEndunion
+        | TokenType::DirectiveEndenum => depth -= 1,
+        _ => (),
+      }
+    }
+    block.extend(line);
+    if depth <= 0 || peek_next_token(tokens).get_type() == &TokenType::EndOfFile {
+      return block;
+    }
+  }
+}
+
+// <definition> ::= ( <dir-struct> | <dir-enum> ) <newline>
+fn parse_definition(tokens: &mut Vec<Token>, program_tree: &mut Node<String>) -> AsmResult<()> {
+  let next = peek_next_token(tokens);
+  let mut dir_statement = Node::at(NodeType::DirectiveStatement, &next);
+  match next.get_type() {
+    TokenType::DirectiveEnum => {
+      for assignment in parse_dir_enum(tokens)? {
+        program_tree.add_child(assignment);
+      }
+    }
+    _ => {
+      dir_statement.add_child(parse_dir_struct(tokens, false)?);
+      program_tree.add_child(dir_statement);
+    }
+  }
+  get_next_token_checked(tokens, vec![TokenType::Newline])?;
+  Ok(())
+}
+
+// <dir-struct> ::= ( ".struct" | ".union" ) [ <id> ] <newline> { [ <struct-member> ] <newline> } ( ".endstruct" | ".endunion" )
+fn parse_dir_struct(tokens: &mut Vec<Token>, nested: bool) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let node_type = NodeType::from_token_type(dir_token.get_type()).unwrap();
+  let mut definition = Node::at(node_type, &dir_token);
+  // only nested ones can leave out the name, their members belong to the outer one
+  if !nested || peek_next_token(tokens).get_type() != &TokenType::Newline {
+    let name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+    definition.add_data(name.get_value());
+  }
+  get_next_token_checked(tokens, vec![TokenType::Newline])?;
+  let (end, end_name) = match dir_token.get_type() {
+    TokenType::DirectiveStruct => (TokenType::DirectiveEndstruct, ".endstruct"),
+    _ => (TokenType::DirectiveEndunion, ".endunion"),
+  };
+  loop {
+    let next = peek_next_token(tokens);
+    match next.get_type() {
+      t if t == &end => break,
+      TokenType::EndOfFile => return Err(missing_end(&dir_token, end_name)),
+      TokenType::Newline => (),
+      _ => definition.add_child(parse_struct_member(tokens)?),
+    }
+    get_next_token_checked(tokens, vec![TokenType::Newline])?;
+  }
+  validate_dir_name(&get_next_token(tokens))?;
+  Ok(definition)
+}
+
+// <struct-member> ::= <dir-struct> | [ <id> ] <dir-storage> [ <expression> ] | [ <id> ] ".tag" <symbol>
+fn parse_struct_member(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let next = peek_next_token(tokens);
+  if next.get_type() == &TokenType::DirectiveStruct || next.get_type() == &TokenType::DirectiveUnion
+  {
+    return parse_dir_struct(tokens, true);
+  }
+  let name = match next.get_type() {
+    TokenType::Identifier => Some(get_next_token(tokens)),
+    _ => None,
+  };
+  let storage = vec![
+    TokenType::DirectiveByte,
+    TokenType::DirectiveByt,
+    TokenType::DirectiveRes,
+    TokenType::DirectiveWord,
+    TokenType::DirectiveDbyt,
+    TokenType::DirectiveAddr,
+    TokenType::DirectiveFaraddr,
+    TokenType::DirectiveDword,
+    TokenType::DirectiveTag,
+  ];
+  let dir_token = get_next_token_checked(tokens, storage)?;
+  validate_dir_name(&dir_token)?;
+  let node_type = NodeType::from_token_type(dir_token.get_type()).unwrap();
+  let mut member = Node::at(node_type, name.as_ref().unwrap_or(&dir_token));
+  if let Some(name) = &name {
+    member.add_data(name.get_value());
+  }
+  let next = peek_next_token(tokens);
+  match dir_token.get_type() {
+    TokenType::DirectiveTag => member.add_child(parse_tag_size(tokens)?),
+    // the count is optional for everything but .res
+    TokenType::DirectiveRes => member.add_child(parse_expression(tokens)?),
+    _ if next.get_type() != &TokenType::Newline => member.add_child(parse_expression(tokens)?),
+    _ => (),
+  }
+  Ok(member)
+}
+
+// <dir-enum> ::= ".enum" [ <id> ] <newline> { [ <id> [ "=" <expression> ] ] <newline> } ".endenum"
+// The members become assignments, each one more than the one before unless
+// given a value
+fn parse_dir_enum(tokens: &mut Vec<Token>) -> AsmResult<Vec<Node<String>>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let prefix = match peek_next_token(tokens).get_type() {
+    TokenType::Identifier => format!("{}::", get_next_token(tokens).get_value()),
+    _ => String::new(),
+  };
+  get_next_token_checked(tokens, vec![TokenType::Newline])?;
+  let mut members: Vec<Node<String>> = vec![];
+  loop {
+    let next = peek_next_token(tokens);
+    match next.get_type() {
+      TokenType::DirectiveEndenum => break,
+      TokenType::EndOfFile => return Err(missing_end(&dir_token, ".endenum")),
+      TokenType::Newline => (),
+      _ => {
+        let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+        let value = match peek_next_token(tokens).get_type() {
+          TokenType::Equal => {
+            get_next_token(tokens);
+            parse_expression(tokens)?
+          }
+          _ => next_enum_value(&id, members.last()),
+        };
+        let mut assignment = Node::at(NodeType::AssignmentStatement, &id);
+        assignment.add_data(&format!("{}{}", prefix, id.get_value()));
+        assignment.add_child(value);
+        members.push(assignment);
+      }
+    }
+    get_next_token_checked(tokens, vec![TokenType::Newline])?;
+  }
+  validate_dir_name(&get_next_token(tokens))?;
+  Ok(members)
+}
+
+fn next_enum_value(id: &Token, previous: Option<&Node<String>>) -> Node<String> {
+  let mut number = Node::at(NodeType::Number, id);
+  let previous = match previous {
+    Some(previous) => previous,
+    None => {
+      number.add_data(&String::from("0"));
+      return number;
+    }
+  };
+  number.add_data(&String::from("1"));
+  let mut variable = Node::at(NodeType::Variable, id);
+  variable.add_data(previous.get_first_data_result());
+  let mut sum = Node::at(NodeType::BinaryOp, id);
+  sum.add_data(&String::from("+"));
+  sum.add_child(variable);
+  sum.add_child(number);
+  sum
+}
+
+fn missing_end(token: &Token, end: &str) -> AsmError {
+  AsmError::at_token(token, format!("Missing {} for this", end))
+}
+
+// <line> ::= { <statement> } <newline>
+fn parse_line(tokens: &mut Vec<Token>, program_tree: &mut Node<String>) -> AsmResult<()> {
+  let mut next = peek_next_token(tokens);
+  while next.get_type() != &TokenType::Newline {
+    program_tree.add_child(parse_statement(tokens)?);
+    next = peek_next_token(tokens);
+  }
+  Ok(())
 }
 
 // <statement> ::= <assignment> | <directive> | <label> | <opcode>
-fn parse_statement(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_statement(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let next = peek_next_token(tokens);
   if is_opcode(next.get_value()) {
     return parse_opcode(tokens);
@@ -31,74 +245,200 @@ fn parse_statement(tokens: &mut Vec<Token>) -> Node<String> {
 }
 
 // <assignment> ::= <id> "=" <expression>
-fn parse_assignment(tokens: &mut Vec<Token>) -> Node<String> {
-  let id = get_next_token_checked(tokens, vec![TokenType::Identifier]);
-  let _op = get_next_token_checked(tokens, vec![TokenType::Equal]);
-  let val = parse_expression(tokens);
-  let mut assignment = Node::new(NodeType::AssignmentStatement);
+fn parse_assignment(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+  let _op = get_next_token_checked(tokens, vec![TokenType::Equal])?;
+  let val = parse_expression(tokens)?;
+  let mut assignment = Node::at(NodeType::AssignmentStatement, &id);
   assignment.add_data(id.get_value());
   assignment.add_child(val);
-  assignment
+  Ok(assignment)
 }
 
-// <directive> ::= <dir-segment> | <dir-other>
-fn parse_directive(tokens: &mut Vec<Token>) -> Node<String> {
+// <directive> ::= <dir-segment> | <dir-scope> | <dir-no-args> | <dir-tag> | <dir-symbols>
+//   | <dir-other>
+fn parse_directive(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let directive = peek_next_token(tokens);
-  let mut dir_statement = Node::new(NodeType::DirectiveStatement);
+  let mut dir_statement = Node::at(NodeType::DirectiveStatement, &directive);
   let child = match directive.get_type() {
-    TokenType::DirectiveSegment => parse_dir_segment(tokens),
-    _ => parse_dir_other(tokens),
+    TokenType::DirectiveSegment
+    | TokenType::DirectiveCode
+    | TokenType::DirectiveRodata
+    | TokenType::DirectiveData
+    | TokenType::DirectiveBss
+    | TokenType::DirectiveZeropage => parse_dir_segment(tokens)?,
+    TokenType::DirectiveScope | TokenType::DirectiveProc => parse_dir_scope(tokens)?,
+    TokenType::DirectiveEndscope
+    | TokenType::DirectiveEndproc
+    | TokenType::DirectiveReloc
+    | TokenType::DirectivePushseg
+    | TokenType::DirectivePopseg => parse_dir_no_args(tokens)?,
+    TokenType::DirectiveTag => parse_dir_tag(tokens)?,
+    TokenType::DirectiveExport
+    | TokenType::DirectiveExportzp
+    | TokenType::DirectiveImport
+    | TokenType::DirectiveImportzp
+    | TokenType::DirectiveGlobal
+    | TokenType::DirectiveGlobalzp => parse_dir_symbols(tokens)?,
+    _ => parse_dir_other(tokens)?,
   };
   dir_statement.add_child(child);
-  dir_statement
+  Ok(dir_statement)
 }
 
-// <dir-segment> ::= ".segment" <dir-seg-name>
-fn parse_dir_segment(tokens: &mut Vec<Token>) -> Node<String> {
+// <dir-segment> ::= ".segment" <dir-seg-name> [ ":" <address-size> ] | ".code" | ".rodata" | ".data" | ".bss" | ".zeropage"
+fn parse_dir_segment(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let directive = get_next_token(tokens);
-  if directive.get_value() != "segment" {
-    error(&directive);
+  validate_dir_name(&directive)?;
+  let mut segment = Node::at(NodeType::DirectiveSegment, &directive);
+  let (name, address_size) = match directive.get_type() {
+    TokenType::DirectiveCode => ("CODE", None),
+    TokenType::DirectiveRodata => ("RODATA", None),
+    TokenType::DirectiveData => ("DATA", None),
+    TokenType::DirectiveBss => ("BSS", None),
+    TokenType::DirectiveZeropage => ("ZEROPAGE", Some("zeropage")),
+    _ => {
+      let name = get_next_token_checked(tokens, vec![TokenType::StringConst])?;
+      validate_dir_seg_name(&name)?;
+      segment.add_data(name.get_value());
+      if peek_next_token(tokens).get_type() == &TokenType::Colon {
+        get_next_token(tokens);
+        segment.add_data(&parse_address_size(tokens)?);
+      }
+      return Ok(segment);
+    }
+  };
+  segment.add_data(&String::from(name));
+  if let Some(address_size) = address_size {
+    segment.add_data(&String::from(address_size));
   }
-  let mut segment = Node::new(NodeType::DirectiveSegment);
-  let name = get_next_token_checked(tokens, vec![TokenType::StringConst]);
-  validate_dir_seg_name(&name);
-  segment.add_data(name.get_value());
-  segment
+  Ok(segment)
+}
+
+// <dir-seg-name> ::= <double-quote> ( <up-case-letter> | "_" ) { <up-case-letter> | <digit> | "_" } <double-quote>
+fn validate_dir_seg_name(token: &Token) -> AsmResult<()> {
+  let val = token.get_value();
+  let valid = match val.chars().next() {
+    Some(first) => {
+      (first.is_ascii_uppercase() || first == '_')
+        && val
+          .chars()
+          .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    }
+    None => false,
+  };
+  if !valid {
+    let message = format!("Invalid segment name \"{}\"", val);
+    return Err(AsmError::at_token(token, message));
+  }
+  Ok(())
 }
 
-// <dir-seg-name> ::= <double-quote> <up-case-letter> { <up-case-letter> } <double-quote>
-fn validate_dir_seg_name(token: &Token) {
-  let val = token.get_value().to_ascii_uppercase();
-  if !(val.len() > 1 && &val == token.get_value()) {
-    error(token);
-  }
+// <address-size> ::= "zeropage" | "zp" | "direct" | "absolute" | "abs" | "near"
+fn parse_address_size(tokens: &mut Vec<Token>) -> AsmResult<String> {
+  let token = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+  let address_size = match token.get_value().to_ascii_lowercase().as_str() {
+    "zeropage" | "zp" | "direct" => "zeropage",
+    "absolute" | "abs" | "near" => "absolute",
+    _ => {
+      let message = format!("Unsupported address size '{}'", token.get_value());
+      return Err(AsmError::at_token(&token, message));
+    }
+  };
+  Ok(String::from(address_size))
 }
 
 // <dir-other> ::= <dir-name> { <dir-arg> }
-fn parse_dir_other(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_dir_other(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let dir_token = get_next_token(tokens);
-  validate_dir_name(&dir_token);
-  let mut directive = Node::new(NodeType::from_token_type(dir_token.get_type()));
-  let dir_args = parse_dir_args(tokens);
+  validate_dir_name(&dir_token)?;
+  let node_type = match NodeType::from_token_type(dir_token.get_type()) {
+    Some(node_type) => node_type,
+    None => return Err(error(&dir_token)),
+  };
+  let mut directive = Node::at(node_type, &dir_token);
+  let dir_args = parse_dir_args(tokens)?;
+  directive.add_child(dir_args);
+  Ok(directive)
+}
+
+// <dir-scope> ::= ".scope" [ <id> ] | ".proc" <id>
+fn parse_dir_scope(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let node_type = NodeType::from_token_type(dir_token.get_type()).unwrap();
+  let mut directive = Node::at(node_type, &dir_token);
+  let next = peek_next_token(tokens);
+  // only .scope can leave out the name
+  if dir_token.get_type() == &TokenType::DirectiveProc || next.get_type() != &TokenType::Newline {
+    let name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+    directive.add_data(name.get_value());
+  }
+  Ok(directive)
+}
+
+// <dir-no-args> ::= ".endscope" | ".endproc" | ".reloc" | ".pushseg" | ".popseg"
+fn parse_dir_no_args(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let node_type = NodeType::from_token_type(dir_token.get_type()).unwrap();
+  Ok(Node::at(node_type, &dir_token))
+}
+
+// <dir-symbols> ::= ( ".export" | ".exportzp" | ".import" | ".importzp" | ".global"
+//   | ".globalzp" ) <id> { "," <id> }
+fn parse_dir_symbols(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let node_type = NodeType::from_token_type(dir_token.get_type()).unwrap();
+  let mut directive = Node::at(node_type, &dir_token);
+  loop {
+    let name = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+    directive.add_data(name.get_value());
+    if peek_next_token(tokens).get_type() != &TokenType::Comma {
+      return Ok(directive);
+    }
+    get_next_token(tokens);
+  }
+}
+
+// <dir-tag> ::= ".tag" <symbol>
+fn parse_dir_tag(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  let mut directive = Node::at(NodeType::DirectiveTag, &dir_token);
+  let mut dir_args = Node::at(NodeType::DirArgs, &peek_next_token(tokens));
+  dir_args.add_child(parse_tag_size(tokens)?);
   directive.add_child(dir_args);
-  directive
+  Ok(directive)
+}
+
+// A .tag takes up as much space as the struct it names
+fn parse_tag_size(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let token = peek_next_token(tokens);
+  let symbol = parse_variable(tokens)?;
+  let mut size = Node::at(NodeType::DirectiveSizeof, &token);
+  size.add_data(symbol.get_first_data_result());
+  Ok(size)
 }
 
 // <dir-name> ::= "." <low-case-letter> { <low-case-letter> }
-fn validate_dir_name(token: &Token) {
+fn validate_dir_name(token: &Token) -> AsmResult<()> {
   let val = token.get_value().to_ascii_lowercase();
   if !(val.len() > 1 && &val == token.get_value()) {
-    error(token);
+    let message = format!("Directive \".{}\" must be lower case", token.get_value());
+    return Err(AsmError::at_token(token, message));
   }
+  Ok(())
 }
 
 // <dir-arg> ::= (<string-const>|<expression>) { "," <dir-arg> }
-fn parse_dir_args(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut dir_args = Node::new(NodeType::DirArgs);
+fn parse_dir_args(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let mut dir_args = Node::at(NodeType::DirArgs, &peek_next_token(tokens));
   let next = peek_next_token(tokens);
   let dir_arg = match next.get_type() {
     TokenType::StringConst => parse_string_const(tokens),
-    _ => parse_expression(tokens),
+    _ => parse_expression(tokens)?,
   };
   dir_args.add_child(dir_arg);
   let mut next = peek_next_token(tokens);
@@ -107,160 +447,185 @@ fn parse_dir_args(tokens: &mut Vec<Token>) -> Node<String> {
     let token = peek_next_token(tokens);
     let dir_arg = match token.get_type() {
       TokenType::StringConst => parse_string_const(tokens),
-      _ => parse_expression(tokens),
+      _ => parse_expression(tokens)?,
     };
     dir_args.add_child(dir_arg);
     next = peek_next_token(tokens);
   }
-  dir_args
+  Ok(dir_args)
 }
 
 // <string-const> ::= <dir-string-arg> | <dir-value>
 fn parse_string_const(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut string = Node::new(NodeType::String);
   let token = get_next_token(tokens);
+  let mut string = Node::at(NodeType::String, &token);
   string.add_data(token.get_value());
   string
 }
 
 // <label> ::= <normal-label> | <local-label> | <unnamed-label>
-fn parse_label(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut label_statement = Node::new(NodeType::LabelStatement);
+fn parse_label(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let next = peek_next_token(tokens);
+  let mut label_statement = Node::at(NodeType::LabelStatement, &next);
   let child = match next.get_type() {
-    TokenType::Identifier => parse_normal_label(tokens),
-    TokenType::LocalLabel => parse_local_label(tokens),
-    _ => parse_unnamed_label(tokens),
+    TokenType::Identifier => parse_normal_label(tokens)?,
+    TokenType::LocalLabel => parse_local_label(tokens)?,
+    _ => parse_unnamed_label(tokens)?,
   };
   label_statement.add_child(child);
-  label_statement
+  Ok(label_statement)
 }
 
 // <normal-label> ::= <id> ":"
-fn parse_normal_label(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut normal_label = Node::new(NodeType::Label);
-  let id = get_next_token_checked(tokens, vec![TokenType::Identifier]);
-  get_next_token_checked(tokens, vec![TokenType::Colon]);
+fn parse_normal_label(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+  let mut normal_label = Node::at(NodeType::Label, &id);
+  get_next_token_checked(tokens, vec![TokenType::Colon])?;
   normal_label.add_data(id.get_value());
-  normal_label
+  Ok(normal_label)
 }
 
 // <local-label> ::= "@" <id> ":"
-fn parse_local_label(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut local_label = Node::new(NodeType::LocalLabel);
-  let id = get_next_token_checked(tokens, vec![TokenType::LocalLabel]);
-  get_next_token_checked(tokens, vec![TokenType::Colon]);
+fn parse_local_label(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let id = get_next_token_checked(tokens, vec![TokenType::LocalLabel])?;
+  let mut local_label = Node::at(NodeType::LocalLabel, &id);
+  get_next_token_checked(tokens, vec![TokenType::Colon])?;
   local_label.add_data(id.get_value());
-  local_label
+  Ok(local_label)
 }
 
 // <unnamed-label> ::= ":"
-fn parse_unnamed_label(tokens: &mut Vec<Token>) -> Node<String> {
-  let unnamed = Node::new(NodeType::UnnamedLabel);
-  get_next_token_checked(tokens, vec![TokenType::Colon]);
-  unnamed
+fn parse_unnamed_label(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let colon = get_next_token_checked(tokens, vec![TokenType::Colon])?;
+  Ok(Node::at(NodeType::UnnamedLabel, &colon))
 }
 
-// <opcode> ::= <accumulator-mode> | <immediate-mode> | <direct-memory-mode> | <indirect-memory-mode> | <relative-mode>
-fn parse_opcode(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut op_node = Node::new(NodeType::OpcodeStatement);
+// <opcode> ::= <implied-mode> | <accumulator-mode> | <immediate-mode> | <direct-memory-mode> | <indirect-memory-mode> | <relative-mode>
+fn parse_opcode(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let next = peek_next_token(tokens);
-  match is_accumulator(next.get_value()) {
-    true => {
-      op_node.add_child(parse_accumulator(tokens));
+  let mut op_node = Node::at(NodeType::OpcodeStatement, &next);
+  let code = next.get_value();
+  let after_next = peek_two_ahead(tokens);
+  let child_op_node = match after_next.get_type() {
+    _ if has_mode(code, AddressingMode::Implied) => parse_implied(tokens)?,
+    _ if has_mode(code, AddressingMode::Accumulator) && is_accumulator_operand(&after_next) => {
+      parse_accumulator(tokens)?
     }
-    false => {
-      let after_next = peek_two_ahead(tokens);
-      let child_op_node = match after_next.get_type() {
-        TokenType::Hash => parse_immediate(tokens),
-        TokenType::OParen => parse_indirect(tokens),
-        TokenType::ULabel => parse_ulabel(tokens),
-        _ => match is_branch(next.get_value()) {
-          true => parse_branch(tokens),
-          false => parse_direct(tokens),
-        },
-      };
-      op_node.add_child(child_op_node);
+    TokenType::Newline => {
+      let message = format!("Instruction {} requires an operand", code);
+      return Err(AsmError::at_token(&next, message));
     }
-  }
-  op_node
+    TokenType::Hash => parse_immediate(tokens)?,
+    TokenType::OParen => parse_indirect(tokens)?,
+    TokenType::ULabel => parse_ulabel(tokens)?,
+    _ => match is_branch(code) {
+      true => parse_branch(tokens)?,
+      false => parse_direct(tokens)?,
+    },
+  };
+  op_node.add_child(child_op_node);
+  Ok(op_node)
+}
+
+// <implied-mode> ::= <op-id>
+fn parse_implied(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  let mut imp_node = Node::at(NodeType::ImpliedMode, &code);
+  imp_node.add_data(code.get_value());
+  Ok(imp_node)
 }
 
-// <accumulator-mode> ::= <op-id>
-fn parse_accumulator(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut acc_node = Node::new(NodeType::AccumulatorMode);
-  let code = get_next_token_checked(tokens, vec![TokenType::Opcode]);
+// <accumulator-mode> ::= <op-id> [ "A" ]
+fn parse_accumulator(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  let mut acc_node = Node::at(NodeType::AccumulatorMode, &code);
   acc_node.add_data(code.get_value());
-  acc_node
+  if peek_next_token(tokens).get_type() == &TokenType::Identifier {
+    get_next_token(tokens);
+  }
+  Ok(acc_node)
+}
+
+// The shift and rotate instructions work on the accumulator when given no operand or an explicit "A"
+fn is_accumulator_operand(token: &Token) -> bool {
+  match token.get_type() {
+    TokenType::Newline => true,
+    TokenType::Identifier => token.get_value().eq_ignore_ascii_case("a"),
+    _ => false,
+  }
 }
 
 // <immediate-mode> ::= <op-id> "#" <expression>
-fn parse_immediate(tokens: &mut Vec<Token>) -> Node<String> {
-  let mut imm_node = Node::new(NodeType::ImmediateMode);
-  let code = get_next_token_checked(tokens, vec![TokenType::Opcode]);
+fn parse_immediate(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  let mut imm_node = Node::at(NodeType::ImmediateMode, &code);
   imm_node.add_data(code.get_value());
-  get_next_token_checked(tokens, vec![TokenType::Hash]);
-  let expression = parse_expression(tokens);
+  get_next_token_checked(tokens, vec![TokenType::Hash])?;
+  let expression = parse_expression(tokens)?;
   imm_node.add_child(expression);
-  imm_node
+  Ok(imm_node)
 }
 
 // <direct-memory-mode> ::= <op-id> <expression> { "," <register> }
-fn parse_direct(tokens: &mut Vec<Token>) -> Node<String> {
-  let code = get_next_token_checked(tokens, vec![TokenType::Opcode]);
-  let expression = parse_expression(tokens);
+fn parse_direct(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  let expression = parse_expression(tokens)?;
   let next = peek_next_token(tokens);
-  let mut dir_node = Node::new(match next.get_type() {
+  let node_type = match next.get_type() {
     TokenType::Comma => {
       get_next_token(tokens);
       let reg = get_next_token(tokens);
       match reg.get_type() {
         TokenType::XRegister => NodeType::DirectRegXMode,
         TokenType::YRegister => NodeType::DirectRegYMode,
-        _ => error(&reg),
+        _ => return Err(error(&reg)),
       }
     }
     _ => NodeType::DirectMode,
-  });
+  };
+  let mut dir_node = Node::at(node_type, &code);
   dir_node.add_data(code.get_value());
   dir_node.add_child(expression);
-  dir_node
+  Ok(dir_node)
 }
 
-fn parse_branch(tokens: &mut Vec<Token>) -> Node<String> {
-  let code = get_next_token_checked(tokens, vec![TokenType::Opcode]);
-  let mut node = Node::new(NodeType::RelativeMode);
+fn parse_branch(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  let mut node = Node::at(NodeType::RelativeMode, &code);
   node.add_data(code.get_value());
-  let expression = parse_expression(tokens);
+  let expression = parse_expression(tokens)?;
   node.add_child(expression);
-  node
+  Ok(node)
 }
 
-// <indirect-memory-mode> ::= <indirect-x> | <indirect-y>
-fn parse_indirect(tokens: &mut Vec<Token>) -> Node<String> {
-  let code = get_next_token_checked(tokens, vec![TokenType::Opcode]);
-  get_next_token_checked(tokens, vec![TokenType::OParen]);
-  let expression = parse_expression(tokens);
-  let disambiguator = get_next_token_checked(tokens, vec![TokenType::Comma, TokenType::CParen]);
+// <indirect-memory-mode> ::= <indirect> | <indirect-x> | <indirect-y>
+fn parse_indirect(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let code = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
+  get_next_token_checked(tokens, vec![TokenType::OParen])?;
+  let expression = parse_expression(tokens)?;
+  let disambiguator = get_next_token_checked(tokens, vec![TokenType::Comma, TokenType::CParen])?;
   let mut ind_node = match disambiguator.get_type() {
     TokenType::Comma => {
-      get_next_token_checked(tokens, vec![TokenType::XRegister]);
-      get_next_token_checked(tokens, vec![TokenType::CParen]);
-      Node::new(NodeType::IndirectXMode)
-    }
-    _ => {
-      get_next_token_checked(tokens, vec![TokenType::Comma]);
-      get_next_token_checked(tokens, vec![TokenType::YRegister]);
-      Node::new(NodeType::IndirectYMode)
+      get_next_token_checked(tokens, vec![TokenType::XRegister])?;
+      get_next_token_checked(tokens, vec![TokenType::CParen])?;
+      Node::at(NodeType::IndirectXMode, &code)
     }
+    _ => match peek_next_token(tokens).get_type() {
+      TokenType::Comma => {
+        get_next_token(tokens);
+        get_next_token_checked(tokens, vec![TokenType::YRegister])?;
+        Node::at(NodeType::IndirectYMode, &code)
+      }
+      _ => Node::at(NodeType::IndirectMode, &code),
+    },
   };
   ind_node.add_data(code.get_value());
   ind_node.add_child(expression);
-  ind_node
+  Ok(ind_node)
 }
 
 // <expression> ::= "!" <expression> | <bool-not-exp>
-fn parse_expression(tokens: &mut Vec<Token>) -> Node<String> {
+pub fn parse_expression(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_generic_un_exp(
     tokens,
     parse_expression,
@@ -270,32 +635,32 @@ fn parse_expression(tokens: &mut Vec<Token>) -> Node<String> {
 }
 
 // <bool-not-exp> ::= <bool-or-exp> { ("||"|"OR") <bool-or-exp> }
-fn parse_bool_not_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_bool_not_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_bin_exp(tokens, parse_bool_or_exp, Token::is_prec_level_six)
 }
 
 // <bool-or-exp> ::= <bool-xor-and-exp> { ("&&"|"XOR"|"AND") <bool-xor-and-exp> }
-fn parse_bool_or_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_bool_or_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_bin_exp(tokens, parse_bool_xor_and_exp, Token::is_prec_level_five)
 }
 
 // <bool-xor-and-exp> ::= <relational-exp> { ("="|"<>"|"<"|">"|"<="|">=") <relational-exp> }
-fn parse_bool_xor_and_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_bool_xor_and_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_bin_exp(tokens, parse_relational_exp, Token::is_prec_level_four)
 }
 
 // <relational-exp> ::= <binary-add-sub-exp> { ("+"|"-"|"|"|"BITOR") <binary-add-sub-exp> }
-fn parse_relational_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_relational_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_bin_exp(tokens, parse_binary_add_sub_exp, Token::is_prec_level_three)
 }
 
 // <binary-add-sub-exp> ::= <bitwise-mul-div-exp> { ("_"|"/"|"<<"|">>"|"^"|"&"|"MOD"|"BITAND"|"BITXOR"|"SHL"|"SHR") <bitwise-mul-div-exp> }
-fn parse_binary_add_sub_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_binary_add_sub_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_bin_exp(tokens, parse_bitwise_mul_div_exp, Token::is_prec_level_two)
 }
 
 // <bitwise-mul-div-exp> ::= <unary-op> <bitwise-mul-div-exp> | <unary-exp>
-fn parse_bitwise_mul_div_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_bitwise_mul_div_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_generic_un_exp(
     tokens,
     parse_bitwise_mul_div_exp,
@@ -305,7 +670,7 @@ fn parse_bitwise_mul_div_exp(tokens: &mut Vec<Token>) -> Node<String> {
 }
 
 // <unary-exp> ::= <built-in-string-function> <unary-exp> | <factor>
-fn parse_unary_exp(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_unary_exp(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   parse_generic_un_exp(
     tokens,
     parse_unary_exp,
@@ -314,94 +679,220 @@ fn parse_unary_exp(tokens: &mut Vec<Token>) -> Node<String> {
   )
 }
 
-// <factor> ::= "(" <expression> ")" | <id> | <number>
-fn parse_factor(tokens: &mut Vec<Token>) -> Node<String> {
+// <factor> ::= "(" <expression> ")" | <symbol> | <number> | <sizeof>
+fn parse_factor(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let token = peek_next_token(tokens);
   match token.get_type() {
     TokenType::OParen => {
-      get_next_token_checked(tokens, vec![TokenType::OParen]);
-      let exp = parse_expression(tokens);
-      get_next_token_checked(tokens, vec![TokenType::CParen]);
-      exp
+      get_next_token_checked(tokens, vec![TokenType::OParen])?;
+      let exp = parse_expression(tokens)?;
+      get_next_token_checked(tokens, vec![TokenType::CParen])?;
+      Ok(exp)
     }
-    TokenType::Identifier | TokenType::LocalLabel => parse_variable(tokens),
+    TokenType::Identifier | TokenType::LocalLabel | TokenType::Namespace => parse_variable(tokens),
     TokenType::BinNumber | TokenType::HexNumber | TokenType::DecNumber => parse_number(tokens),
-    _ => error(&token),
+    TokenType::DirectiveSizeof => parse_sizeof(tokens),
+    _ => Err(error(&token)),
   }
 }
 
-fn parse_variable(tokens: &mut Vec<Token>) -> Node<String> {
-  let token = get_next_token(tokens);
-  let mut node = Node::new(NodeType::Variable);
-  node.add_data(token.get_value());
-  node
+// <sizeof> ::= ".sizeof" "(" <symbol> ")"
+fn parse_sizeof(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let dir_token = get_next_token(tokens);
+  validate_dir_name(&dir_token)?;
+  get_next_token_checked(tokens, vec![TokenType::OParen])?;
+  let symbol = parse_variable(tokens)?;
+  get_next_token_checked(tokens, vec![TokenType::CParen])?;
+  let mut node = Node::at(NodeType::DirectiveSizeof, &dir_token);
+  node.add_data(symbol.get_first_data_result());
+  Ok(node)
+}
+
+// <symbol> ::= [ "::" ] <id> { "::" <id> }
+fn parse_variable(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let token = peek_next_token(tokens);
+  let mut name = String::new();
+  if token.get_type() == &TokenType::Namespace {
+    name.push_str(get_next_token(tokens).get_value());
+  }
+  let id = get_next_token_checked(tokens, vec![TokenType::Identifier, TokenType::LocalLabel])?;
+  name.push_str(id.get_value());
+  while peek_next_token(tokens).get_type() == &TokenType::Namespace {
+    name.push_str(get_next_token(tokens).get_value());
+    let id = get_next_token_checked(tokens, vec![TokenType::Identifier])?;
+    name.push_str(id.get_value());
+  }
+  let mut node = Node::at(NodeType::Variable, &token);
+  node.add_data(&name);
+  Ok(node)
 }
 
-fn parse_ulabel(tokens: &mut Vec<Token>) -> Node<String> {
-  let opcode = get_next_token_checked(tokens, vec![TokenType::Opcode]);
+fn parse_ulabel(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
+  let opcode = get_next_token_checked(tokens, vec![TokenType::Opcode])?;
   let token = get_next_token(tokens);
-  let mut mode_node = Node::new(NodeType::RelativeMode);
+  let mut mode_node = Node::at(NodeType::RelativeMode, &opcode);
   mode_node.add_data(opcode.get_value());
-  let mut node = Node::new(NodeType::LabelJump);
+  let mut node = Node::at(NodeType::LabelJump, &token);
   node.add_data(token.get_value());
   mode_node.add_child(node);
-  mode_node
+  Ok(mode_node)
 }
 
 // Take hex/bin/dec number and return it without control chars as decimal number
-fn parse_number(tokens: &mut Vec<Token>) -> Node<String> {
+fn parse_number(tokens: &mut Vec<Token>) -> AsmResult<Node<String>> {
   let token = get_next_token(tokens);
-  let num = convert_number(&token);
-  match num {
-    Ok(val) => {
-      let mut node = Node::new(NodeType::Number);
-      node.add_data(&val.to_string());
-      node
-    }
-    Err(_) => error(&token),
-  }
+  let val = convert_number(&token)?;
+  let mut node = Node::at(NodeType::Number, &token);
+  node.add_data(&val.to_string());
+  Ok(node)
 }
 
-fn parse_bin_exp<F: Fn(&mut Vec<Token>) -> Node<String>, N: Fn(&Token) -> bool>(
+fn parse_bin_exp<F: Fn(&mut Vec<Token>) -> AsmResult<Node<String>>, N: Fn(&Token) -> bool>(
   tokens: &mut Vec<Token>,
   next_exp: F,
   valid_token: N,
-) -> Node<String> {
-  let mut expression = next_exp(tokens);
+) -> AsmResult<Node<String>> {
+  let mut expression = next_exp(tokens)?;
   let mut next = peek_next_token(tokens);
   while valid_token(&next) {
     let op = get_next_token(tokens);
-    let mut node = Node::new(NodeType::BinaryOp);
+    let mut node = Node::at(NodeType::BinaryOp, &op);
     node.add_data(op.get_value());
-    let next_expression = next_exp(tokens);
+    let next_expression = next_exp(tokens)?;
     node.add_child(expression);
     node.add_child(next_expression);
     expression = node;
     next = peek_next_token(tokens);
   }
-  expression
+  Ok(expression)
 }
 
 fn parse_generic_un_exp<
-  F: Fn(&mut Vec<Token>) -> Node<String>,
-  G: Fn(&mut Vec<Token>) -> Node<String>,
+  F: Fn(&mut Vec<Token>) -> AsmResult<Node<String>>,
+  G: Fn(&mut Vec<Token>) -> AsmResult<Node<String>>,
   N: Fn(&Token) -> bool,
 >(
   tokens: &mut Vec<Token>,
   next_exp: F,
   final_exp: G,
   valid_token: N,
-) -> Node<String> {
+) -> AsmResult<Node<String>> {
   let next = peek_next_token(tokens);
   match valid_token(&next) {
     true => {
       let token = get_next_token(tokens);
-      let mut node = Node::new(NodeType::UnaryOp);
+      let mut node = Node::at(NodeType::UnaryOp, &token);
       node.add_data(token.get_value());
-      let expression = next_exp(tokens);
+      let expression = next_exp(tokens)?;
       node.add_child(expression);
-      node
+      Ok(node)
     }
     false => final_exp(tokens),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::SourceMap;
+  use crate::expression::evaluate;
+  use crate::lexer::lex;
+  use crate::options::Options;
+  use crate::preprocessor::preprocess;
+  use std::collections::HashMap;
+
+  fn parse_source(source: &str) -> Node<String> {
+    let tokens = lex(source, 0, true).unwrap();
+    parse(preprocess(tokens, &mut SourceMap::new(), &Options::default()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn check_enum() {
+    let tree = parse_source(".enum Color\n  black\n  red = 5\n  cyan\n.endenum\n");
+    let mut values = HashMap::new();
+    for assignment in tree.get_children() {
+      let value = evaluate(assignment.get_first_child(), &values).unwrap();
+      values.insert(assignment.get_first_data_result().to_owned(), value);
+    }
+    assert_eq!(values["Color::black"], 0);
+    assert_eq!(values["Color::red"], 5);
+    assert_eq!(values["Color::cyan"], 6);
+  }
+
+  #[test]
+  fn check_struct() {
+    let tree = parse_source(
+      ".struct Point\n  xcoord .word\n  .union\n    a .byte 2\n  .endunion\n.endstruct\n",
+    );
+    let definition = tree.get_first_child().get_first_child();
+    assert_eq!(definition.get_type(), &NodeType::DirectiveStruct);
+    assert_eq!(definition.get_children().len(), 2);
+    assert_eq!(
+      definition.get_children()[1].get_type(),
+      &NodeType::DirectiveUnion
+    );
+    assert!(parse(lex(".struct Open\n  a .byte\n", 0, true).unwrap()).is_err());
+  }
+
+  #[test]
+  fn check_errors() {
+    let source = "lda #1 2\nnop\nlda #\n";
+    let tokens = lex(source, 0, true).unwrap();
+    let tokens = preprocess(tokens, &mut SourceMap::new(), &Options::default()).unwrap();
+    let errors = parse(tokens).unwrap_err();
+    let starts: Vec<usize> = errors
+      .iter()
+      .map(|e| e.get_location().unwrap().get_start())
+      .collect();
+    assert_eq!(starts, vec![7, 18]);
+  }
+
+  #[test]
+  fn check_accumulator_mode() {
+    let tree = parse_source("asl a\nasl\nrol A\nasl $10\nasl $0200,x\nclc\nlda a\n");
+    let modes: Vec<&NodeType> = tree
+      .get_children()
+      .iter()
+      .map(|statement| statement.get_first_child().get_type())
+      .collect();
+    let expected = vec![
+      &NodeType::AccumulatorMode,
+      &NodeType::AccumulatorMode,
+      &NodeType::AccumulatorMode,
+      &NodeType::DirectMode,
+      &NodeType::DirectRegXMode,
+      &NodeType::ImpliedMode,
+      &NodeType::DirectMode,
+    ];
+    assert_eq!(modes, expected);
+    let tokens = lex("lda\n", 0, true).unwrap();
+    let tokens = preprocess(tokens, &mut SourceMap::new(), &Options::default()).unwrap();
+    assert!(parse(tokens).is_err());
+  }
+
+  #[test]
+  fn check_segment_shorthands() {
+    let tree = parse_source(".code\n.zeropage\n.segment \"BSS\": abs\n.pushseg\n.popseg\n");
+    let segments: Vec<(&NodeType, &Vec<String>)> = tree
+      .get_children()
+      .iter()
+      .map(|statement| statement.get_first_child())
+      .map(|directive| (directive.get_type(), directive.get_data()))
+      .collect();
+    let data = |values: &[&str]| -> Vec<String> { values.iter().map(|v| v.to_string()).collect() };
+    assert_eq!(segments[0], (&NodeType::DirectiveSegment, &data(&["CODE"])));
+    assert_eq!(
+      segments[1],
+      (
+        &NodeType::DirectiveSegment,
+        &data(&["ZEROPAGE", "zeropage"])
+      )
+    );
+    assert_eq!(
+      segments[2],
+      (&NodeType::DirectiveSegment, &data(&["BSS", "absolute"]))
+    );
+    assert_eq!(segments[3].0, &NodeType::DirectivePushseg);
+    assert_eq!(segments[4].0, &NodeType::DirectivePopseg);
+  }
+}
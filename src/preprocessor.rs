@@ -0,0 +1,889 @@
+use crate::common::describe_token;
+use crate::error::{AsmError, AsmResult, SourceMap};
+use crate::expression::{evaluate, try_evaluate};
+use crate::lexer::lex;
+use crate::node::Node;
+use crate::options::Options;
+use crate::parser::parse_expression;
+use crate::token::{Token, TokenType};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::vec::IntoIter;
+
+const MAX_MACRO_DEPTH: usize = 64;
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MAX_REFERENCE_PASSES: usize = 8;
+
+// Works on whole lines before parsing, splicing in included files, expanding
+// macros and dropping the lines of conditional blocks that aren't assembled.
+// Every line of the result ends in a newline token and the stream still ends
+// in end of file
+pub fn preprocess(
+  mut tokens: Vec<Token>,
+  sources: &mut SourceMap,
+  options: &Options,
+) -> Result<Vec<Token>, Vec<AsmError>> {
+  let end_of_file = tokens.pop().unwrap();
+  let lines = split_lines(tokens);
+  // .ifref goes by references anywhere in the file, some of which only turn
+  // up once includes and macros are expanded. So the lines are expanded again
+  // with the references found until those are the ones that come out
+  let mut referenced = find_references(&lines.concat());
+  let mut reference_check = None;
+  for _ in 0..MAX_REFERENCE_PASSES {
+    let mut preprocessor = Preprocessor::new(sources, options, referenced);
+    preprocessor.process_lines(lines.clone(), 0);
+    preprocessor.close_conditionals(0);
+    let Preprocessor {
+      mut out,
+      errors,
+      referenced: given,
+      last_reference_check,
+      ..
+    } = preprocessor;
+    let found = find_references(&out);
+    reference_check = last_reference_check;
+    if reference_check.is_some() && found != given {
+      referenced = found;
+      continue;
+    }
+    return match errors.is_empty() {
+      true => {
+        out.push(end_of_file);
+        Ok(out)
+      }
+      false => Err(errors),
+    };
+  }
+  let message = format!(
+    "References did not settle after {} passes, an .ifref or .ifnref changes what it checks",
+    MAX_REFERENCE_PASSES
+  );
+  Err(vec![AsmError::at_token(&reference_check.unwrap(), message)])
+}
+
+// Groups the tokens sharing a line, ending each group with a newline
+fn split_lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+  let mut lines: Vec<Vec<Token>> = vec![];
+  for token in tokens {
+    let location = *token.get_location();
+    let same_line = match lines.last().and_then(|line| line.last()) {
+      Some(last) => {
+        let last = last.get_location();
+        last.get_file() == location.get_file() && last.get_line() == location.get_line()
+      }
+      None => false,
+    };
+    match same_line {
+      true => lines.last_mut().unwrap().push(token),
+      false => lines.push(vec![token]),
+    }
+  }
+  for line in lines.iter_mut() {
+    let last = line.last().unwrap();
+    let end = *last._get_end();
+    let newline = Token::new(
+      String::from(""),
+      TokenType::Newline,
+      end,
+      end + 1,
+      *last._get_line(),
+    );
+    line.push(newline.in_file(last.get_location().get_file()));
+  }
+  lines
+}
+
+#[derive(Clone)]
+struct Macro {
+  params: Vec<String>,
+  body: Vec<Vec<Token>>,
+}
+
+// Whether to carry on with the lines of a macro or leave it early
+enum Flow {
+  Continue,
+  Exit,
+}
+
+// One open .if block
+struct Conditional {
+  directive: Token,
+  // the block this one sits in is being assembled
+  enclosing: bool,
+  // one of the branches so far was taken
+  taken: bool,
+  // the current branch is being assembled
+  active: bool,
+  seen_else: bool,
+}
+
+struct Preprocessor<'p> {
+  sources: &'p mut SourceMap,
+  options: &'p Options,
+  include_depth: usize,
+  macros: HashMap<String, Macro>,
+  expansion_count: usize,
+  conditionals: Vec<Conditional>,
+  // constant symbols known so far, for evaluating conditions
+  symbols: HashMap<String, i32>,
+  defined: HashSet<String>,
+  referenced: HashSet<String>,
+  // the last .ifref or .ifnref looked at, if any
+  last_reference_check: Option<Token>,
+  out: Vec<Token>,
+  errors: Vec<AsmError>,
+}
+
+impl<'p> Preprocessor<'p> {
+  fn new(
+    sources: &'p mut SourceMap,
+    options: &'p Options,
+    referenced: HashSet<String>,
+  ) -> Preprocessor<'p> {
+    let symbols: HashMap<String, i32> = options
+      .get_defines()
+      .iter()
+      .map(|(name, value)| (name.to_owned(), *value))
+      .collect();
+    Preprocessor {
+      sources,
+      options,
+      include_depth: 0,
+      macros: HashMap::new(),
+      expansion_count: 0,
+      conditionals: vec![],
+      defined: symbols.keys().cloned().collect(),
+      symbols,
+      referenced,
+      last_reference_check: None,
+      out: vec![],
+      errors: vec![],
+    }
+  }
+
+  fn is_active(&self) -> bool {
+    match self.conditionals.last() {
+      Some(conditional) => conditional.active,
+      None => true,
+    }
+  }
+
+  fn process_lines(&mut self, lines: Vec<Vec<Token>>, depth: usize) -> Flow {
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+      match self.process_line(line, &mut lines, depth) {
+        Ok(Flow::Exit) => return Flow::Exit,
+        Ok(Flow::Continue) => (),
+        Err(e) => self.errors.push(e),
+      }
+    }
+    Flow::Continue
+  }
+
+  fn process_line(
+    &mut self,
+    line: Vec<Token>,
+    rest: &mut IntoIter<Vec<Token>>,
+    depth: usize,
+  ) -> AsmResult<Flow> {
+    let first = &line[0];
+    if is_conditional(first.get_type()) {
+      self.process_conditional(&line)?;
+      return Ok(Flow::Continue);
+    }
+    if !self.is_active() {
+      return Ok(Flow::Continue);
+    }
+    // a label in front of a macro goes out on a line of its own
+    let label_len = get_label_len(&line);
+    if label_len > 0 && self.is_macro(&line[label_len]) {
+      let mut label = line[..label_len].to_vec();
+      label.push(line[line.len() - 1].clone());
+      self.add_symbols(&label);
+      self.out.extend(label);
+      self.expand_macro(&line[label_len..], depth)?;
+      return Ok(Flow::Continue);
+    }
+    match first.get_type() {
+      TokenType::DirectiveMacro | TokenType::DirectiveMac => self.define_macro(line, rest)?,
+      TokenType::DirectiveEndmacro | TokenType::DirectiveEndmac => {
+        return Err(misplaced(first, ".endmacro without .macro"))
+      }
+      TokenType::DirectiveExitmacro | TokenType::DirectiveExitmac => match depth {
+        0 => return Err(misplaced(first, ".exitmacro outside of a macro")),
+        _ => return Ok(Flow::Exit),
+      },
+      TokenType::DirectiveLocal => return Err(misplaced(first, ".local outside of a macro")),
+      TokenType::DirectiveRepeat => return self.repeat_block(line, rest, depth),
+      TokenType::DirectiveEndrep | TokenType::DirectiveEndrepeat => {
+        return Err(misplaced(first, ".endrepeat without .repeat"))
+      }
+      TokenType::DirectiveDelmacro | TokenType::DirectiveDelmac => self.delete_macro(&line)?,
+      TokenType::DirectiveInclude => return self.include_file(&line, depth),
+      TokenType::Identifier if self.is_macro(first) => self.expand_macro(&line, depth)?,
+      _ => {
+        let line = self.resolve_incbin(line)?;
+        self.add_symbols(&line);
+        self.out.extend(line)
+      }
+    }
+    Ok(Flow::Continue)
+  }
+
+  fn is_macro(&self, token: &Token) -> bool {
+    token.get_type() == &TokenType::Identifier && self.macros.contains_key(token.get_value())
+  }
+
+  // <include> ::= ".include" <string-const>
+  fn include_file(&mut self, line: &[Token], depth: usize) -> AsmResult<Flow> {
+    let name = expect_string(&line[1])?;
+    expect_newline(&line[2])?;
+    if self.include_depth >= MAX_INCLUDE_DEPTH {
+      let message = format!(
+        "Includes are nested more than {} levels deep",
+        MAX_INCLUDE_DEPTH
+      );
+      return Err(AsmError::at_token(&line[0], message));
+    }
+    let path = self.find_file(&line[1], |options, from| options.find_include(name, from))?;
+    let text = read_to_string(&path).map_err(|e| file_error(&line[1], &path, e))?;
+    let file_id = self.sources.add(&path, &text);
+    let mut tokens = lex(&text, file_id, true)?;
+    tokens.pop();
+    self.include_depth += 1;
+    let flow = self.process_lines(split_lines(tokens), depth);
+    self.include_depth -= 1;
+    Ok(flow)
+  }
+
+  // Swaps the file name of an .incbin for where it was found, the generator
+  // has no idea which file the directive came from
+  fn resolve_incbin(&mut self, mut line: Vec<Token>) -> AsmResult<Vec<Token>> {
+    let position = line
+      .iter()
+      .position(|t| t.get_type() == &TokenType::DirectiveIncbin);
+    let name_token = match position {
+      Some(i) => &line[i + 1],
+      None => return Ok(line),
+    };
+    let name = expect_string(name_token)?;
+    let path = self.find_file(name_token, |options, from| {
+      options.find_bin_include(name, from)
+    })?;
+    let resolved = name_token.replace(path.display().to_string(), TokenType::StringConst);
+    line[position.unwrap() + 1] = resolved;
+    Ok(line)
+  }
+
+  fn find_file<F: Fn(&Options, &Path) -> Option<PathBuf>>(
+    &self,
+    name_token: &Token,
+    find: F,
+  ) -> AsmResult<PathBuf> {
+    let file = name_token.get_location().get_file();
+    let from = self.sources.get_path(file).cloned().unwrap_or_default();
+    match find(self.options, &from) {
+      Some(path) => Ok(path),
+      None => {
+        let message = format!(
+          "File not found in include paths: {}",
+          name_token.get_value()
+        );
+        Err(AsmError::at_token(name_token, message))
+      }
+    }
+  }
+
+  // Keeps track of labels and constant assignments as they go by
+  fn add_symbols(&mut self, line: &[Token]) {
+    if line[0].get_type() != &TokenType::Identifier {
+      return;
+    }
+    let name = line[0].get_value();
+    match line[1].get_type() {
+      TokenType::Colon => {
+        self.defined.insert(name.to_owned());
+      }
+      TokenType::Equal => {
+        self.defined.insert(name.to_owned());
+        let mut tokens = line[2..].to_vec();
+        let value = parse_expression(&mut tokens)
+          .ok()
+          .and_then(|node| try_evaluate(&node, &self.symbols).ok().flatten());
+        if let Some(value) = value {
+          self.symbols.insert(name.to_owned(), value);
+        }
+      }
+      _ => (),
+    }
+  }
+
+  // <conditional> ::= <if-directive> <condition> | ".elseif" <condition> | ".else" | ".endif"
+  fn process_conditional(&mut self, line: &[Token]) -> AsmResult<()> {
+    let directive = &line[0];
+    match directive.get_type() {
+      TokenType::DirectiveElseif | TokenType::DirectiveElse => {
+        let active = match self.conditionals.last() {
+          Some(c) if c.seen_else => return Err(misplaced(directive, "Branch after .else")),
+          Some(c) if !c.enclosing || c.taken => false,
+          Some(_) => match directive.get_type() {
+            TokenType::DirectiveElse => expect_newline(&line[1]).map(|_| true)?,
+            _ => self.evaluate_condition(directive, &line[1..])?,
+          },
+          None => return Err(misplaced(directive, "Missing .if before this")),
+        };
+        let conditional = self.conditionals.last_mut().unwrap();
+        conditional.seen_else = directive.get_type() == &TokenType::DirectiveElse;
+        conditional.taken |= active;
+        conditional.active = active;
+      }
+      TokenType::DirectiveEndif => {
+        expect_newline(&line[1])?;
+        if self.conditionals.pop().is_none() {
+          return Err(misplaced(directive, "Missing .if before this"));
+        }
+      }
+      _ => {
+        let enclosing = self.is_active();
+        // conditions inside a skipped block are never looked at
+        let active = enclosing && self.evaluate_condition(directive, &line[1..])?;
+        self.conditionals.push(Conditional {
+          directive: directive.clone(),
+          enclosing,
+          taken: active,
+          active,
+          seen_else: false,
+        });
+      }
+    }
+    Ok(())
+  }
+
+  fn evaluate_condition(&mut self, directive: &Token, args: &[Token]) -> AsmResult<bool> {
+    let result = match directive.get_type() {
+      TokenType::DirectiveIf | TokenType::DirectiveElseif => {
+        let expression = self.parse_condition(args)?;
+        evaluate(&expression, &self.symbols)? != 0
+      }
+      TokenType::DirectiveIfconst | TokenType::DirectiveIfnconst => {
+        let expression = self.parse_condition(args)?;
+        let constant = try_evaluate(&expression, &self.symbols)?.is_some();
+        constant == (directive.get_type() == &TokenType::DirectiveIfconst)
+      }
+      TokenType::DirectiveIfdef | TokenType::DirectiveIfndef => {
+        let name = expect_identifier(&args[0])?;
+        expect_newline(&args[1])?;
+        self.defined.contains(name) == (directive.get_type() == &TokenType::DirectiveIfdef)
+      }
+      TokenType::DirectiveIfref | TokenType::DirectiveIfnref => {
+        let name = expect_identifier(&args[0])?;
+        expect_newline(&args[1])?;
+        self.last_reference_check = Some(directive.clone());
+        self.referenced.contains(name) == (directive.get_type() == &TokenType::DirectiveIfref)
+      }
+      TokenType::DirectiveIfblank => args.len() == 1,
+      TokenType::DirectiveIfnblank => args.len() > 1,
+      // only the plain 6502 instruction set is supported
+      TokenType::DirectiveIfp02 => expect_newline(&args[0]).map(|_| true)?,
+      _ => expect_newline(&args[0]).map(|_| false)?,
+    };
+    Ok(result)
+  }
+
+  fn parse_condition(&self, args: &[Token]) -> AsmResult<Node<String>> {
+    let mut tokens = args.to_vec();
+    let expression = parse_expression(&mut tokens)?;
+    expect_newline(&tokens[0])?;
+    Ok(expression)
+  }
+
+  // Reports every .if opened past this depth that never got its .endif
+  fn close_conditionals(&mut self, depth: usize) {
+    for conditional in self.conditionals.drain(depth..) {
+      let message = String::from("Missing .endif for this");
+      self
+        .errors
+        .push(misplaced(&conditional.directive, &message));
+    }
+  }
+
+  // <macro> ::= ".macro" <id> [ <id> { "," <id> } ] <newline> { <line> } ".endmacro"
+  fn define_macro(&mut self, line: Vec<Token>, rest: &mut IntoIter<Vec<Token>>) -> AsmResult<()> {
+    let directive = &line[0];
+    let name = expect_identifier(&line[1])?;
+    let params = parse_names(&line[2..])?;
+    let body = match take_body(rest, is_macro_start, is_macro_end) {
+      Some(body) => body,
+      None => {
+        let message = format!("Macro '{}' is missing .endmacro", name);
+        return Err(AsmError::at_token(directive, message));
+      }
+    };
+    if self.macros.contains_key(name) {
+      let message = format!("Macro '{}' is already defined", name);
+      return Err(AsmError::at_token(&line[1], message));
+    }
+    self.macros.insert(name.to_owned(), Macro { params, body });
+    Ok(())
+  }
+
+  // <repeat> ::= ".repeat" <expression> [ "," <id> ] <newline> { <line> } ".endrepeat"
+  fn repeat_block(
+    &mut self,
+    line: Vec<Token>,
+    rest: &mut IntoIter<Vec<Token>>,
+    depth: usize,
+  ) -> AsmResult<Flow> {
+    let directive = &line[0];
+    let mut tokens = line[1..].to_vec();
+    let count_node = parse_expression(&mut tokens)?;
+    let count = evaluate(&count_node, &self.symbols)?;
+    let counter = match tokens[0].get_type() {
+      TokenType::Comma => {
+        let name = expect_identifier(&tokens[1])?;
+        expect_newline(&tokens[2])?;
+        Some(name.to_owned())
+      }
+      _ => expect_newline(&tokens[0]).map(|_| None)?,
+    };
+    if count < 0 {
+      let message = format!("Repeat count can't be negative, found {}", count);
+      return Err(AsmError::at_node(&count_node, message));
+    }
+    let body = match take_body(rest, is_repeat_start, is_repeat_end) {
+      Some(body) => body,
+      None => return Err(misplaced(directive, ".repeat is missing .endrepeat")),
+    };
+    for i in 0..count {
+      let body = match &counter {
+        Some(name) => body
+          .iter()
+          .map(|line| substitute_counter(line, name, i))
+          .collect(),
+        None => body.clone(),
+      };
+      let open = self.conditionals.len();
+      // an .exitmacro leaves the macro around the loop too
+      if let Flow::Exit = self.process_lines(body, depth) {
+        return Ok(Flow::Exit);
+      }
+      self.close_conditionals(open);
+    }
+    Ok(Flow::Continue)
+  }
+
+  // <delmacro> ::= ".delmacro" <id>
+  fn delete_macro(&mut self, line: &[Token]) -> AsmResult<()> {
+    let name = expect_identifier(&line[1])?;
+    expect_newline(&line[2])?;
+    match self.macros.remove(name) {
+      Some(_) => Ok(()),
+      None => {
+        let message = format!("Macro '{}' is not defined", name);
+        Err(AsmError::at_token(&line[1], message))
+      }
+    }
+  }
+
+  // <invocation> ::= <id> [ <argument> { "," <argument> } ]
+  fn expand_macro(&mut self, line: &[Token], depth: usize) -> AsmResult<()> {
+    let name_token = &line[0];
+    let name = name_token.get_value();
+    if depth >= MAX_MACRO_DEPTH {
+      let message = format!(
+        "Macro '{}' is nested more than {} levels deep",
+        name, MAX_MACRO_DEPTH
+      );
+      return Err(AsmError::at_token(name_token, message));
+    }
+    let definition = self.macros[name].clone();
+    let args = split_arguments(&line[1..line.len() - 1]);
+    if args.len() > definition.params.len() {
+      let message = format!(
+        "Macro '{}' takes at most {} arguments, found {}",
+        name,
+        definition.params.len(),
+        args.len()
+      );
+      return Err(AsmError::at_token(name_token, message));
+    }
+    self.expansion_count += 1;
+    let (locals, body) = take_locals(definition.body)?;
+    let expansion = Expansion {
+      params: &definition.params,
+      args: &args,
+      locals: &locals,
+      id: self.expansion_count,
+    };
+    let body = body.iter().map(|line| expansion.substitute(line)).collect();
+    let open = self.conditionals.len();
+    match self.process_lines(body, depth + 1) {
+      // leaving early skips the .endif of any blocks left open
+      Flow::Exit => self.conditionals.truncate(open),
+      Flow::Continue => self.close_conditionals(open),
+    }
+    Ok(())
+  }
+}
+
+// What gets swapped into the body of one macro invocation
+struct Expansion<'e> {
+  params: &'e Vec<String>,
+  args: &'e Vec<Vec<Token>>,
+  locals: &'e Vec<String>,
+  id: usize,
+}
+
+impl<'e> Expansion<'e> {
+  fn substitute(&self, line: &[Token]) -> Vec<Token> {
+    let mut out = Vec::with_capacity(line.len());
+    for token in line {
+      match token.get_type() {
+        TokenType::Identifier => {
+          let name = token.get_value();
+          match self.params.iter().position(|p| p == name) {
+            // parameters without an argument are blank
+            Some(i) => out.extend(self.args.get(i).cloned().unwrap_or_default()),
+            None if self.locals.contains(name) => {
+              // '#' can't appear in a source identifier, so this can't clash
+              let unique = format!("{}#{}", name, self.id);
+              out.push(token.replace(unique, TokenType::Identifier));
+            }
+            None => out.push(token.clone()),
+          }
+        }
+        TokenType::DirectiveParamcount => {
+          let count = self.args.len().to_string();
+          out.push(token.replace(count, TokenType::DecNumber));
+        }
+        _ => out.push(token.clone()),
+      }
+    }
+    out
+  }
+}
+
+// Takes the lines up to the one ending the block, leaving that one out. Blocks
+// of the same kind can be nested inside
+fn take_body(
+  rest: &mut IntoIter<Vec<Token>>,
+  is_start: fn(&TokenType) -> bool,
+  is_end: fn(&TokenType) -> bool,
+) -> Option<Vec<Vec<Token>>> {
+  let mut body = vec![];
+  let mut nesting = 0;
+  loop {
+    let line = rest.next()?;
+    let first = line[0].get_type();
+    if is_start(first) {
+      nesting += 1;
+    } else if is_end(first) {
+      if nesting == 0 {
+        return Some(body);
+      }
+      nesting -= 1;
+    }
+    body.push(line);
+  }
+}
+
+fn is_macro_start(t: &TokenType) -> bool {
+  t == &TokenType::DirectiveMacro || t == &TokenType::DirectiveMac
+}
+
+fn is_macro_end(t: &TokenType) -> bool {
+  t == &TokenType::DirectiveEndmacro || t == &TokenType::DirectiveEndmac
+}
+
+fn is_repeat_start(t: &TokenType) -> bool {
+  t == &TokenType::DirectiveRepeat
+}
+
+fn is_repeat_end(t: &TokenType) -> bool {
+  t == &TokenType::DirectiveEndrep || t == &TokenType::DirectiveEndrepeat
+}
+
+// The loop counter is swapped for its value, so it works anywhere a number does
+fn substitute_counter(line: &[Token], name: &str, value: i32) -> Vec<Token> {
+  line
+    .iter()
+    .map(|token| match token.get_type() {
+      TokenType::Identifier if token.get_value() == name => {
+        token.replace(value.to_string(), TokenType::DecNumber)
+      }
+      _ => token.clone(),
+    })
+    .collect()
+}
+
+// How many tokens the label starting a line takes up, if it starts with one
+fn get_label_len(line: &[Token]) -> usize {
+  match (line[0].get_type(), line[1].get_type()) {
+    (TokenType::Identifier, TokenType::Colon) | (TokenType::LocalLabel, TokenType::Colon) => 2,
+    (TokenType::Colon, _) => 1,
+    _ => 0,
+  }
+}
+
+fn is_conditional(t: &TokenType) -> bool {
+  matches!(
+    t,
+    TokenType::DirectiveIf
+      | TokenType::DirectiveIfblank
+      | TokenType::DirectiveIfconst
+      | TokenType::DirectiveIfdef
+      | TokenType::DirectiveIfnblank
+      | TokenType::DirectiveIfnconst
+      | TokenType::DirectiveIfndef
+      | TokenType::DirectiveIfnref
+      | TokenType::DirectiveIfp02
+      | TokenType::DirectiveIfp4510
+      | TokenType::DirectiveIfp816
+      | TokenType::DirectiveIfpc02
+      | TokenType::DirectiveIfpsc02
+      | TokenType::DirectiveIfref
+      | TokenType::DirectiveElseif
+      | TokenType::DirectiveElse
+      | TokenType::DirectiveEndif
+  )
+}
+
+// Names used anywhere other than where they're defined
+fn find_references(tokens: &[Token]) -> HashSet<String> {
+  let mut referenced = HashSet::new();
+  for line in tokens.split(|t| t.get_type() == &TokenType::Newline) {
+    let defines = match line.get(1).map(Token::get_type) {
+      Some(TokenType::Colon) | Some(TokenType::Equal) => {
+        line[0].get_type() == &TokenType::Identifier
+      }
+      _ => false,
+    };
+    let skip = match defines {
+      true => 2,
+      false => 0,
+    };
+    for token in line.iter().skip(skip) {
+      if token.get_type() == &TokenType::Identifier {
+        referenced.insert(token.get_value().to_owned());
+      }
+    }
+  }
+  referenced
+}
+
+// Pulls the .local lines belonging to this macro out of its body
+fn take_locals(body: Vec<Vec<Token>>) -> AsmResult<(Vec<String>, Vec<Vec<Token>>)> {
+  let mut locals = vec![];
+  let mut lines = vec![];
+  let mut nesting = 0;
+  for line in body {
+    match line[0].get_type() {
+      TokenType::DirectiveMacro | TokenType::DirectiveMac => nesting += 1,
+      TokenType::DirectiveEndmacro | TokenType::DirectiveEndmac => nesting -= 1,
+      TokenType::DirectiveLocal if nesting == 0 => {
+        locals.append(&mut parse_names(&line[1..])?);
+        continue;
+      }
+      _ => (),
+    }
+    lines.push(line);
+  }
+  Ok((locals, lines))
+}
+
+// Arguments are split on commas, curly braces keep commas inside one argument
+fn split_arguments(tokens: &[Token]) -> Vec<Vec<Token>> {
+  if tokens.is_empty() {
+    return vec![];
+  }
+  let mut args = vec![vec![]];
+  let mut braces = 0;
+  for token in tokens {
+    match token.get_type() {
+      TokenType::Comma if braces == 0 => {
+        args.push(vec![]);
+        continue;
+      }
+      TokenType::OCurly => braces += 1,
+      TokenType::CCurly => braces -= 1,
+      _ => (),
+    }
+    args.last_mut().unwrap().push(token.clone());
+  }
+  for arg in args.iter_mut() {
+    let braced = arg.first().map(Token::get_type) == Some(&TokenType::OCurly)
+      && arg.last().map(Token::get_type) == Some(&TokenType::CCurly);
+    if braced {
+      arg.pop();
+      arg.remove(0);
+    }
+  }
+  args
+}
+
+// <names> ::= [ <id> { "," <id> } ] <newline>
+fn parse_names(tokens: &[Token]) -> AsmResult<Vec<String>> {
+  let mut names = vec![];
+  let mut tokens = tokens.iter();
+  let mut next = tokens.next().unwrap();
+  while next.get_type() != &TokenType::Newline {
+    names.push(expect_identifier(next)?.to_owned());
+    next = tokens.next().unwrap();
+    match next.get_type() {
+      TokenType::Comma => next = tokens.next().unwrap(),
+      _ => expect_newline(next)?,
+    }
+  }
+  Ok(names)
+}
+
+fn expect_identifier(token: &Token) -> AsmResult<&String> {
+  match token.get_type() {
+    TokenType::Identifier => Ok(token.get_value()),
+    _ => Err(unexpected(token, "an identifier")),
+  }
+}
+
+fn expect_string(token: &Token) -> AsmResult<&String> {
+  match token.get_type() {
+    TokenType::StringConst => Ok(token.get_value()),
+    _ => Err(unexpected(token, "a file name")),
+  }
+}
+
+fn expect_newline(token: &Token) -> AsmResult<()> {
+  match token.get_type() {
+    TokenType::Newline => Ok(()),
+    _ => Err(unexpected(token, "end of line")),
+  }
+}
+
+fn unexpected(token: &Token, expected: &str) -> AsmError {
+  let message = format!("Expected {}, found {}", expected, describe_token(token));
+  AsmError::at_token(token, message)
+}
+
+fn file_error(token: &Token, path: &Path, e: std::io::Error) -> AsmError {
+  let message = format!("Unable to read {}: {}", path.display(), e);
+  AsmError::at_token(token, message)
+}
+
+fn misplaced(token: &Token, message: &str) -> AsmError {
+  AsmError::at_token(token, String::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::lex;
+
+  fn expand(source: &str) -> Result<Vec<String>, Vec<AsmError>> {
+    let options = Options::default();
+    let tokens = preprocess(
+      lex(source, 0, true).unwrap(),
+      &mut SourceMap::new(),
+      &options,
+    )?;
+    Ok(tokens.iter().map(|t| t.get_value().to_owned()).collect())
+  }
+
+  #[test]
+  fn check_macros() {
+    let source = ".macro add a, b\n.local skip\nlda a\nskip: .byte .paramcount\n.endmacro\nadd 1, {2, 3}\nadd\n";
+    let expanded = expand(source).unwrap().join(" ");
+    assert_eq!(expanded, "lda 1  skip#1 : byte 2  lda  skip#2 : byte 0  ");
+    let recursive = ".macro loop\nloop\n.endmacro\nloop\n";
+    assert!(expand(recursive).is_err());
+    let exit = ".macro early\n.exitmacro\nnop\n.endmacro\nearly\n";
+    assert_eq!(expand(exit).unwrap(), vec![String::from("")]);
+  }
+
+  #[test]
+  fn check_conditionals() {
+    let source = "DEBUG = 1\nSIZE = 2\n.if SIZE > 2\nbig\n.elseif DEBUG\n.ifndef DEBUG\nno\n.else\ndebug\n.endif\n.else\nsmall\n.endif\n";
+    let expanded = expand(source).unwrap();
+    assert_eq!(expanded.join(" "), "DEBUG = 1  SIZE = 2  debug  ");
+    assert!(expand(".if 1\n").is_err());
+    assert!(expand(".else\n").is_err());
+    assert!(expand(".if undefined\n.endif\n").is_err());
+  }
+
+  #[test]
+  fn check_repeat() {
+    let source = ".macro twice v
+.byte v, v
+.endmacro
+.repeat 3, i
+.if i <> 1
+twice i
+.endif
+.endrepeat
+";
+    let expanded = expand(source).unwrap().join(" ");
+    assert_eq!(expanded, "byte 0 , 0  byte 2 , 2  ");
+    let nested = ".repeat 2, row
+.repeat 2, col
+.byte row * 2 + col
+.endrep
+.endrep
+";
+    let expanded = expand(nested).unwrap().join(" ");
+    assert_eq!(
+      expanded,
+      "byte 0 * 2 + 0  byte 0 * 2 + 1  byte 1 * 2 + 0  byte 1 * 2 + 1  "
+    );
+    assert!(expand(
+      ".repeat 2
+nop
+"
+    )
+    .is_err());
+    assert!(expand(
+      ".endrepeat
+"
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn check_labelled_macros() {
+    let source = ".macro inc16 addr\ninc addr\n.endmacro\nstart: inc16 ptr\n: inc16 ptr\n";
+    let expanded = expand(source).unwrap().join(" ");
+    assert_eq!(expanded, "start :  inc ptr  :  inc ptr  ");
+    let defined = ".macro m\n.endmacro\nstart: m\n.ifdef start\nyes\n.endif\n";
+    assert_eq!(expand(defined).unwrap().join(" "), "start :  yes  ");
+  }
+
+  #[test]
+  fn check_references() {
+    let unused = ".macro unused\njsr helper\n.endmacro\n.ifnref helper\nnone\n.endif\n";
+    assert_eq!(expand(unused).unwrap().join(" "), "none  ");
+    let path = std::env::temp_dir().join("rusty_axe65_check_references.s");
+    std::fs::write(&path, "jsr helper\n").unwrap();
+    let included = format!(
+      ".include \"{}\"\n.ifref helper\nhelper: rts\n.endif\n",
+      path.display()
+    );
+    let expanded = expand(&included).unwrap().join(" ");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(expanded, "jsr helper  helper : rts  ");
+    let paradox = ".ifnref helper\njsr helper\n.endif\n";
+    assert!(expand(paradox).is_err());
+  }
+
+  #[test]
+  fn check_includes() {
+    let dir = std::env::temp_dir().join("rusty_axe65_check_includes");
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("inc/outer.s"), ".include \"inner.s\"\nouter\n").unwrap();
+    std::fs::write(dir.join("inc/inner.s"), "inner\n").unwrap();
+    let source = format!(".include \"{}\"\nmain\n", dir.join("inc/outer.s").display());
+    let expanded = expand(&source).map(|tokens| tokens.join(" "));
+    let missing = expand(".include \"rusty_axe65_missing.s\"\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(expanded.unwrap(), "inner  outer  main  ");
+    assert!(missing.is_err());
+  }
+}
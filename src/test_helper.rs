@@ -0,0 +1,28 @@
+use crate::configuration::{generate_config_data, Configuration};
+use crate::error::{AsmError, SourceMap};
+use crate::generator::assemble;
+use crate::lexer::lex;
+use crate::object::ObjectFile;
+use crate::options::Options;
+use crate::parser::parse;
+use crate::preprocessor::preprocess;
+
+// Zero page, a filled ROM for code and the vectors at the top of it
+pub const CONFIG: &str = "MEMORY {\n  ZP: start = $0000, size = $0100;\n  PRG: start = $8000, size = $8000, file = %O, fill = yes, fillval = $FF;\n}\n\
+   SEGMENTS {\n  ZEROPAGE: load = ZP, type = zp;\n  CODE: load = PRG, type = ro;\n  VECTORS: load = PRG, type = ro, start = $FFFA;\n}\n";
+
+pub fn configure(text: &str) -> Configuration {
+  generate_config_data(lex(text, 0, false).unwrap()).unwrap()
+}
+
+// Runs a source through everything up to the object file
+pub fn assemble_source(
+  name: &str,
+  source: &str,
+  config: &Configuration,
+) -> Result<ObjectFile, Vec<AsmError>> {
+  let options = Options::default();
+  let tokens = lex(source, 0, true).map_err(|e| vec![e])?;
+  let tokens = preprocess(tokens, &mut SourceMap::new(), &options)?;
+  assemble(parse(tokens)?, name, config, &options)
+}
@@ -1,13 +1,46 @@
 use std::cmp::Ordering;
 use std::fmt;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+  file: usize,
+  line: usize,
+  start: usize,
+  end: usize,
+}
+
+impl Location {
+  pub fn new(file: usize, line: usize, start: usize, end: usize) -> Location {
+    Location {
+      file,
+      line,
+      start,
+      end,
+    }
+  }
+
+  pub fn get_file(&self) -> usize {
+    self.file
+  }
+
+  pub fn get_line(&self) -> usize {
+    self.line
+  }
+
+  pub fn get_start(&self) -> usize {
+    self.start
+  }
+
+  pub fn get_len(&self) -> usize {
+    self.end.saturating_sub(self.start)
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct Token {
   val: String,
   t_type: TokenType,
-  start: usize,
-  end: usize,
-  line: usize,
+  location: Location,
 }
 
 impl Token {
@@ -15,9 +48,21 @@ impl Token {
     Token {
       val,
       t_type: t,
-      start,
-      end,
-      line,
+      location: Location::new(0, line, start, end),
+    }
+  }
+
+  pub fn in_file(mut self, file: usize) -> Token {
+    self.location.file = file;
+    self
+  }
+
+  // A different token in the same place, for tokens rewritten before parsing
+  pub fn replace(&self, val: String, t: TokenType) -> Token {
+    Token {
+      val,
+      t_type: t,
+      location: self.location,
     }
   }
 
@@ -29,16 +74,20 @@ impl Token {
     &self.val
   }
 
+  pub fn get_location(&self) -> &Location {
+    &self.location
+  }
+
   pub fn _get_start(&self) -> &usize {
-    &self.start
+    &self.location.start
   }
 
   pub fn _get_end(&self) -> &usize {
-    &self.end
+    &self.location.end
   }
 
-  pub fn get_line(&self) -> &usize {
-    &self.line
+  pub fn _get_line(&self) -> &usize {
+    &self.location.line
   }
 
   fn get_type_padding(&self) -> String {
@@ -55,13 +104,13 @@ impl Token {
 
   fn get_start_padding(&self) -> String {
     let max_len = 6;
-    let my_len = format!("{:?}", self.start).len();
+    let my_len = format!("{:?}", self.location.start).len();
     self.get_padding(max_len - my_len)
   }
 
   fn get_end_padding(&self) -> String {
     let max_len = 6;
-    let my_len = format!("{:?}", self.end).len();
+    let my_len = format!("{:?}", self.location.end).len();
     self.get_padding(max_len - my_len)
   }
 
@@ -181,11 +230,11 @@ impl fmt::Display for Token {
       type_padding,
       self.val,
       text_padding,
-      self.start,
+      self.location.start,
       start_padding,
-      self.end,
+      self.location.end,
       end_padding,
-      self.line
+      self.location.line
     )
   }
 }
@@ -198,7 +247,7 @@ impl PartialOrd for Token {
 
 impl Ord for Token {
   fn cmp(&self, other: &Self) -> Ordering {
-    self.start.cmp(&other.start)
+    self.location.cmp(&other.location)
   }
 }
 
@@ -206,7 +255,7 @@ impl Eq for Token {}
 
 impl PartialEq for Token {
   fn eq(&self, other: &Self) -> bool {
-    self.start == other.start && self.val == other.val
+    self.location == other.location && self.val == other.val
   }
 }
 
@@ -458,8 +507,32 @@ pub enum TokenType {
 }
 
 impl TokenType {
-  pub fn get_directive_type(identifier: &str) -> TokenType {
-    match identifier {
+  // Control commands that are really operators inside expressions
+  pub fn get_operator_type(identifier: &str) -> Option<TokenType> {
+    let operator = match identifier {
+      "and" => TokenType::BoolAnd,
+      "or" => TokenType::BoolOr,
+      "xor" => TokenType::BoolXor,
+      "not" => TokenType::BoolNot,
+      "mod" => TokenType::Modulo,
+      "bitand" => TokenType::And,
+      "bitor" => TokenType::Or,
+      "bitxor" => TokenType::Xor,
+      "bitnot" => TokenType::Not,
+      "shl" => TokenType::Shl,
+      "shr" => TokenType::Shr,
+      "lobyte" => TokenType::Lobyte,
+      "hibyte" => TokenType::Hibyte,
+      "bankbyte" => TokenType::Bankbyte,
+      "loword" => TokenType::Loword,
+      "hiword" => TokenType::Hiword,
+      _ => return None,
+    };
+    Some(operator)
+  }
+
+  pub fn get_directive_type(identifier: &str) -> Option<TokenType> {
+    let directive = match identifier {
       "a16" => TokenType::DirectiveA16,
       "a8" => TokenType::DirectiveA8,
       "addr" => TokenType::DirectiveAddr,
@@ -588,7 +661,7 @@ impl TokenType {
       "pc02" => TokenType::DirectivePc02,
       "popcpu" => TokenType::DirectivePopcpu,
       "popseg" => TokenType::DirectivePopseg,
-      "prog" => TokenType::DirectiveProc,
+      "proc" => TokenType::DirectiveProc,
       "psc02" => TokenType::DirectivePsc02,
       "pushcpu" => TokenType::DirectivePushcpu,
       "pushseg" => TokenType::DirectivePushseg,
@@ -624,8 +697,9 @@ impl TokenType {
       "xmatch" => TokenType::DirectiveXmatch,
       "xor" => TokenType::DirectiveXor,
       "zeropage" => TokenType::DirectiveZeropage,
-      _ => panic!("{} is not a directive", identifier),
-    }
+      _ => return None,
+    };
+    Some(directive)
   }
 
   pub fn is_directive(&self) -> bool {